@@ -0,0 +1,192 @@
+use std::fmt;
+
+/// An arithmetic expression, as found inside of `$(( ))`
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Expression {
+    Number(i64),
+    Variable(String),
+    Negate(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl Operator {
+    /// Operators with a higher precedence are applied first
+    fn precedence(&self) -> u8 {
+        match *self {
+            Operator::Add | Operator::Subtract => 1,
+            Operator::Multiply | Operator::Divide | Operator::Remainder => 2,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match *self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Remainder => "%",
+        }
+    }
+
+    fn apply(&self, left: i64, right: i64) -> Result<i64, String> {
+        let result = match *self {
+            Operator::Add => left.checked_add(right),
+            Operator::Subtract => left.checked_sub(right),
+            Operator::Multiply => left.checked_mul(right),
+            Operator::Divide | Operator::Remainder if right == 0 => {
+                return Err("division by zero".to_string());
+            },
+            Operator::Divide => left.checked_div(right),
+            Operator::Remainder => left.checked_rem(right),
+        };
+        result.ok_or(format!("overflow in {} {} {}", left, self.symbol(), right))
+    }
+}
+
+impl Expression {
+    /// Builds the expression applying each of the operators from left to right
+    pub fn fold(first: Expression, rest: Vec<(Operator, Expression)>) -> Expression {
+        rest.into_iter().fold(first, |left, (operator, right)| {
+            Expression::Binary(operator, Box::new(left), Box::new(right))
+        })
+    }
+
+    /// Evaluates the expression, looking up the values of variables with the given function.
+    /// Variables that are not set are treated as zero.
+    pub fn evaluate<F: Fn(&str) -> Option<String>>(&self, variables: &F) -> Result<i64, String> {
+        match *self {
+            Expression::Number(number) => Ok(number),
+            Expression::Variable(ref name) => {
+                match variables(name) {
+                    Some(ref value) if !value.trim().is_empty() => {
+                        value.trim()
+                             .parse::<i64>()
+                             .map_err(|_| format!("{} is not a number: {}", name, value))
+                    },
+                    _ => Ok(0),
+                }
+            },
+            Expression::Negate(ref operand) => {
+                let value = try!(operand.evaluate(variables));
+                value.checked_neg().ok_or(format!("overflow in -{}", value))
+            },
+            Expression::Binary(operator, ref left, ref right) => {
+                let left = try!(left.evaluate(variables));
+                let right = try!(right.evaluate(variables));
+                operator.apply(left, right)
+            },
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match *self {
+            Expression::Binary(operator, _, _) => operator.precedence(),
+            _ => 3,
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    /// Displays the expression with only the parentheses needed to keep its meaning
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expression::Number(number) => write!(f, "{}", number),
+            Expression::Variable(ref name) => write!(f, "{}", name),
+            Expression::Negate(ref operand) => {
+                if operand.precedence() < 3 {
+                    write!(f, "-({})", operand)
+                } else {
+                    write!(f, "-{}", operand)
+                }
+            },
+            Expression::Binary(operator, ref left, ref right) => {
+                if left.precedence() < operator.precedence() {
+                    try!(write!(f, "({})", left));
+                } else {
+                    try!(write!(f, "{}", left));
+                }
+                try!(write!(f, " {} ", operator.symbol()));
+                if right.precedence() <= operator.precedence() {
+                    write!(f, "({})", right)
+                } else {
+                    write!(f, "{}", right)
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peg::{parse, WordSegment};
+
+    fn expression(code: &str) -> Expression {
+        let word = parse(&format!("echo $(({}))", code)).unwrap().remove(0).jobs.remove(0).words.remove(1);
+        match word.segments[0] {
+            WordSegment::Arithmetic(ref expression) => expression.clone(),
+            ref segment => panic!("expected an arithmetic expansion, found {:?}", segment),
+        }
+    }
+
+    fn evaluate(code: &str) -> Result<i64, String> {
+        expression(code).evaluate(&|name: &str| {
+            match name {
+                "x" => Some("4".to_string()),
+                "empty" => Some("".to_string()),
+                "word" => Some("four".to_string()),
+                _ => None,
+            }
+        })
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(Ok(7), evaluate("1 + 2 * 3"));
+        assert_eq!(Ok(9), evaluate("(1 + 2) * 3"));
+        assert_eq!(Ok(2), evaluate("10 - 5 - 3"));
+        assert_eq!(Ok(2), evaluate("12 / 3 / 2"));
+        assert_eq!(Ok(1), evaluate("10 % 3"));
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(Ok(-3), evaluate("-3"));
+        assert_eq!(Ok(-9), evaluate("-(1 + 2) * 3"));
+        assert_eq!(Ok(3), evaluate("--3"));
+        assert_eq!(Ok(5), evaluate("2 - -3"));
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(Ok(8), evaluate("x * 2"));
+        assert_eq!(Ok(5), evaluate("$x + 1"));
+        assert_eq!(Ok(0), evaluate("unset + empty"));
+        assert!(evaluate("word").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Err("division by zero".to_string()), evaluate("1 / (x - 4)"));
+        assert!(evaluate("9223372036854775807 + 1").is_err());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!("1 + 2 * 3", expression("1+2*3").to_string());
+        assert_eq!("(1 + 2) * 3", expression("(1 + 2) * 3").to_string());
+        assert_eq!("1 - (2 - 3)", expression("1 - (2 - 3)").to_string());
+        assert_eq!("-(x + 1)", expression("-(x + 1)").to_string());
+    }
+}
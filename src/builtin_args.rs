@@ -0,0 +1,127 @@
+//! Parses the arguments of the builtins that run another command, such as `watch -n 5 df -h`.
+//! The errors are the messages that the builtins print after their names.
+
+/// The longest that `watch` waits between runs, which is a day
+const MAX_WATCH_SECONDS: f64 = 86400.0;
+
+/// The seconds to wait between runs and the command of `watch [-n seconds] command...`, which
+/// waits two seconds unless `-n` is given
+pub fn watch(args: &[String]) -> Result<(f64, &[String]), String> {
+    let (seconds, command) = if args.get(1).map_or(false, |arg| arg == "-n") {
+        // Comparisons with NaN are false, so it is rejected along with infinity
+        match args.get(2).and_then(|seconds| seconds.parse::<f64>().ok()) {
+            Some(seconds) if seconds > 0.0 && seconds <= MAX_WATCH_SECONDS => (seconds, &args[3..]),
+            _ => return Err("-n: expected a number of seconds".to_string()),
+        }
+    } else {
+        (2.0, &args[1..])
+    };
+    if command.is_empty() {
+        return Err("expected a command".to_string());
+    }
+    Ok((seconds, command))
+}
+
+/// Whether to stop at the first run that fails, the number of runs and the command of
+/// `repeat [-e] count command...`
+pub fn repeat(args: &[String]) -> Result<(bool, usize, &[String]), String> {
+    let stop_on_failure = args.get(1).map_or(false, |arg| arg == "-e");
+    let args = if stop_on_failure { &args[2..] } else { &args[1..] };
+    let count = match args.first().and_then(|count| count.parse::<usize>().ok()) {
+        Some(count) => count,
+        None => return Err("expected the number of times to run the command".to_string()),
+    };
+    if args.len() < 2 {
+        return Err("expected a command".to_string());
+    }
+    Ok((stop_on_failure, count, &args[1..]))
+}
+
+/// How many commands to run at once, the command and the inputs of
+/// `parallel [-j count] command... -- inputs...`, which runs as many at once as there are
+/// processors unless `-j` is given
+pub fn parallel(args: &[String], processors: usize) -> Result<(usize, &[String], &[String]), String> {
+    let mut args = &args[1..];
+    let mut limit = processors;
+    if let Some(arg) = args.first().cloned() {
+        let count = if arg == "-j" {
+            args = &args[1..];
+            Some(args.first().cloned().unwrap_or(String::new()))
+        } else if arg.starts_with("-j") {
+            Some(arg[2..].to_string())
+        } else {
+            None
+        };
+        if let Some(count) = count {
+            match count.parse::<usize>() {
+                Ok(count) if count > 0 => limit = count,
+                _ => return Err("-j: expected the number of commands to run at once".to_string()),
+            }
+            args = &args[1..];
+        }
+    }
+    match args.iter().position(|arg| arg == "--") {
+        Some(separator) if separator > 0 => Ok((limit, &args[..separator], &args[separator + 1..])),
+        _ => Err("expected a command followed by -- and the inputs".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_watch() {
+        let watch_args = args(&["watch", "-n", "0.5", "ls", "-l"]);
+        assert_eq!(Ok((0.5, &watch_args[3..])), watch(&watch_args));
+        let watch_args = args(&["watch", "ls"]);
+        assert_eq!(Ok((2.0, &watch_args[1..])), watch(&watch_args));
+        assert_eq!(Err("-n: expected a number of seconds".to_string()), watch(&args(&["watch", "-n", "soon", "ls"])));
+        assert_eq!(Err("-n: expected a number of seconds".to_string()), watch(&args(&["watch", "-n", "0", "ls"])));
+        for &seconds in &["inf", "NaN", "1e30", "86401"] {
+            assert_eq!(Err("-n: expected a number of seconds".to_string()),
+                       watch(&args(&["watch", "-n", seconds, "ls"])));
+        }
+        let watch_args = args(&["watch", "-n", "86400", "ls"]);
+        assert_eq!(Ok((86400.0, &watch_args[3..])), watch(&watch_args));
+        assert_eq!(Err("-n: expected a number of seconds".to_string()), watch(&args(&["watch", "-n"])));
+        assert_eq!(Err("expected a command".to_string()), watch(&args(&["watch", "-n", "1"])));
+        assert_eq!(Err("expected a command".to_string()), watch(&args(&["watch"])));
+    }
+
+    #[test]
+    fn parse_repeat() {
+        let repeat_args = args(&["repeat", "-e", "3", "make", "test"]);
+        assert_eq!(Ok((true, 3, &repeat_args[3..])), repeat(&repeat_args));
+        let repeat_args = args(&["repeat", "5", "ls"]);
+        assert_eq!(Ok((false, 5, &repeat_args[2..])), repeat(&repeat_args));
+        let count = Err("expected the number of times to run the command".to_string());
+        assert_eq!(count, repeat(&args(&["repeat", "many", "ls"])));
+        assert_eq!(count, repeat(&args(&["repeat", "-1", "ls"])));
+        assert_eq!(count, repeat(&args(&["repeat", "-e"])));
+        assert_eq!(count, repeat(&args(&["repeat"])));
+        assert_eq!(Err("expected a command".to_string()), repeat(&args(&["repeat", "-e", "2"])));
+    }
+
+    #[test]
+    fn parse_parallel() {
+        let parallel_args = args(&["parallel", "-j", "4", "gzip", "--", "a", "b"]);
+        assert_eq!(Ok((4, &parallel_args[3..4], &parallel_args[5..])), parallel(&parallel_args, 8));
+        let parallel_args = args(&["parallel", "-j2", "convert", "{}", "--", "a.jpg"]);
+        assert_eq!(Ok((2, &parallel_args[2..4], &parallel_args[5..])), parallel(&parallel_args, 8));
+        let parallel_args = args(&["parallel", "gzip", "--"]);
+        assert_eq!(Ok((8, &parallel_args[1..2], &parallel_args[3..])), parallel(&parallel_args, 8));
+        let count = Err("-j: expected the number of commands to run at once".to_string());
+        assert_eq!(count, parallel(&args(&["parallel", "-j", "0", "gzip", "--", "a"]), 8));
+        assert_eq!(count, parallel(&args(&["parallel", "-jmany", "gzip", "--", "a"]), 8));
+        assert_eq!(count, parallel(&args(&["parallel", "-j"]), 8));
+        let command = Err("expected a command followed by -- and the inputs".to_string());
+        assert_eq!(command, parallel(&args(&["parallel", "gzip", "a"]), 8));
+        assert_eq!(command, parallel(&args(&["parallel", "--", "a"]), 8));
+        assert_eq!(command, parallel(&args(&["parallel"]), 8));
+    }
+}
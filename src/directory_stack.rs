@@ -1,9 +1,11 @@
 use std::collections::VecDeque;
 use std::env::{set_current_dir, current_dir, home_dir};
-use std::path::PathBuf;
+use std::io;
+use std::path::{Component, Path, PathBuf};
 use variables::Variables;
 use super::status::{SUCCESS, FAILURE};
 
+#[derive(Clone)]
 pub struct DirectoryStack {
     dirs: VecDeque<PathBuf>, // The top is always the current directory
 }
@@ -31,11 +33,11 @@ impl DirectoryStack {
         }
     }
 
-    pub fn popd<I: IntoIterator>(&mut self, _: I) -> i32
+    pub fn popd<I: IntoIterator>(&mut self, _: I, variables: &mut Variables) -> i32
         where I::Item: AsRef<str>
     {
         if let Some(dir) = self.get_previous_dir() {
-            if let Err(err) = set_current_dir(dir) {
+            if let Err(err) = change_dir(dir, variables) {
                 println!("{}: Failed to switch to directory {}", err, dir.display());
                 return FAILURE;
             }
@@ -48,7 +50,7 @@ impl DirectoryStack {
         SUCCESS
     }
 
-    pub fn pushd<I: IntoIterator>(&mut self, args: I, variables: &Variables) -> i32
+    pub fn pushd<I: IntoIterator>(&mut self, args: I, variables: &mut Variables) -> i32
         where I::Item: AsRef<str>
     {
         if let Some(dir) = args.into_iter().nth(1) {
@@ -61,13 +63,21 @@ impl DirectoryStack {
         }
     }
 
-    pub fn cd<I: IntoIterator>(&mut self, args: I, variables: &Variables) -> i32
+    /// Changes to the directory, which relative directories are first looked for in the
+    /// directories of `$CDPATH`. `cd -` returns to the previous directory, and `cd` alone goes to
+    /// `$HOME`.
+    pub fn cd<I: IntoIterator>(&mut self, args: I, variables: &mut Variables) -> i32
         where I::Item: AsRef<str>
     {
         if let Some(dir) = args.into_iter().nth(1) {
             let dir = dir.as_ref();
             if dir == "-" {
                 self.switch_to_previous_directory(variables)
+            } else if let Some(path) = find_in_cdpath(dir, &variables.expand_string("$CDPATH")) {
+                // The directory is shown because it is not the one that was given
+                let path = path.to_string_lossy().to_string();
+                println!("{}", path);
+                self.change_and_push_dir(&path, variables)
             } else {
                 self.change_and_push_dir(dir, variables)
             }
@@ -76,26 +86,29 @@ impl DirectoryStack {
         }
     }
 
-    fn switch_to_home_directory(&mut self, variables: &Variables) -> i32 {
-        if let Some(home) = home_dir() {
-            if let Some(home) = home.to_str() {
-                self.change_and_push_dir(home, variables)
-            } else {
-                println!("Failed to convert home directory to str");
-                FAILURE
-            }
+    fn switch_to_home_directory(&mut self, variables: &mut Variables) -> i32 {
+        let home = match variables.get_var("HOME").cloned() {
+            Some(home) => Some(home),
+            None => home_dir().and_then(|home| home.to_str().map(|home| home.to_string())),
+        };
+        if let Some(home) = home {
+            self.change_and_push_dir(&home, variables)
         } else {
             println!("Failed to get home directory");
             FAILURE
         }
     }
 
-    fn switch_to_previous_directory(&mut self, variables: &Variables) -> i32 {
+    fn switch_to_previous_directory(&mut self, variables: &mut Variables) -> i32 {
         if let Some(prev) = self.get_previous_dir()
                                 .map(|path| path.to_string_lossy().to_string()) {
-            self.dirs.remove(1);
             println!("{}", prev);
-            self.change_and_push_dir(&prev, variables)
+            let result = self.change_and_push_dir(&prev, variables);
+            // The directory has been pushed again, so it is now after the one that was left
+            if result == SUCCESS {
+                self.dirs.remove(2);
+            }
+            result
         } else {
             println!("No previous directory to switch to");
             FAILURE
@@ -110,17 +123,20 @@ impl DirectoryStack {
         }
     }
 
-    pub fn change_and_push_dir(&mut self, dir: &str, variables: &Variables) -> i32 {
-        match (set_current_dir(dir), current_dir()) {
-            (Ok(()), Ok(cur_dir)) => {
-                self.push_dir(cur_dir, variables);
+    /// Changes to the directory and pushes it to the stack, setting `$OLDPWD` to the directory
+    /// that was left and `$PWD` to the new one. Relative directories are followed from `$PWD`, so
+    /// `..` leaves a symbolic link that was changed into the way it came.
+    pub fn change_and_push_dir(&mut self, dir: &str, variables: &mut Variables) -> i32 {
+        let logical = logical_path(dir, variables.get_var("PWD"));
+        match change_dir(&logical, variables) {
+            Ok(()) => {
+                self.push_dir(logical, variables);
                 SUCCESS
             }
-            (Err(err), _) => {
+            Err(err) => {
                 println!("Failed to set current dir to {}: {}", dir, err);
                 FAILURE
             }
-            (_, _) => FAILURE, // This should not happen
         }
     }
 
@@ -144,3 +160,164 @@ impl DirectoryStack {
         println!("{}", dir.trim_left());
     }
 }
+
+/// Changes to the directory, setting `$OLDPWD` to the directory that was left and `$PWD` to the
+/// new one
+fn change_dir(dir: &Path, variables: &mut Variables) -> io::Result<()> {
+    try!(set_current_dir(dir));
+    if let Some(previous) = variables.get_var("PWD").cloned() {
+        variables.set_var("OLDPWD", &previous);
+    }
+    variables.set_var("PWD", &dir.to_string_lossy());
+    Ok(())
+}
+
+/// Runs the `pwd` builtin, which prints the current directory as it was reached through symbolic
+/// links, or with `-P` with them resolved
+pub fn pwd<I: IntoIterator>(args: I, variables: &Variables) -> i32
+    where I::Item: AsRef<str>
+{
+    let mut physical = false;
+    for arg in args.into_iter().skip(1) {
+        match arg.as_ref() {
+            "-P" => physical = true,
+            "-L" => physical = false,
+            arg => {
+                println!("ion: pwd: {}: invalid option", arg);
+                return FAILURE;
+            }
+        }
+    }
+    let dir = if physical { current_dir() } else { logical_dir(variables) };
+    match dir {
+        Ok(dir) => {
+            println!("{}", dir.display());
+            SUCCESS
+        }
+        Err(err) => {
+            println!("ion: pwd: {}", err);
+            FAILURE
+        }
+    }
+}
+
+/// `$PWD` if it is an absolute path to the current directory, which it can be without its
+/// symbolic links resolved, or else the current directory with them resolved
+pub fn logical_dir(variables: &Variables) -> io::Result<PathBuf> {
+    let physical = try!(current_dir());
+    if let Some(pwd) = variables.get_var("PWD") {
+        let pwd = Path::new(pwd);
+        if pwd.is_absolute() && pwd.canonicalize().ok().as_ref() == Some(&physical) {
+            return Ok(pwd.to_path_buf());
+        }
+    }
+    Ok(physical)
+}
+
+/// The directory that a relative directory leads to from `$PWD`, with `.` and `..` removed
+/// without following symbolic links. The directory is kept as it is if `$PWD` is not absolute.
+fn logical_path(dir: &str, pwd: Option<&String>) -> PathBuf {
+    let path = match pwd {
+        Some(pwd) if Path::new(pwd).is_absolute() => Path::new(pwd).join(dir),
+        _ => return PathBuf::from(dir),
+    };
+    let mut logical = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                logical.pop();
+            }
+            component => logical.push(component.as_os_str()),
+        }
+    }
+    logical
+}
+
+/// Finds the first directory of the colon separated `$CDPATH` that contains the relative
+/// directory. An empty entry stands for the current directory, which is not searched for here
+/// because the directory is changed to as it was given then. Directories that start with `/`,
+/// `.` or `..` are never searched for.
+fn find_in_cdpath(dir: &str, cdpath: &str) -> Option<PathBuf> {
+    let explicit = match Path::new(dir).components().next() {
+        Some(Component::Normal(_)) => false,
+        _ => true,
+    };
+    if explicit || cdpath.is_empty() {
+        return None;
+    }
+    for entry in cdpath.split(':') {
+        if entry.is_empty() {
+            if Path::new(dir).is_dir() {
+                return None;
+            }
+            continue;
+        }
+        let path = Path::new(entry).join(dir);
+        if path.is_dir() {
+            return Some(path);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirectoryStack, find_in_cdpath, logical_path};
+    use std::path::PathBuf;
+    use std::env::{current_dir, set_current_dir, temp_dir};
+    use std::fs;
+    use status::{SUCCESS, FAILURE};
+    use variables::Variables;
+
+    #[test]
+    fn search_cdpath() {
+        let base = temp_dir().join("ion-cdpath-test");
+        let _ = fs::create_dir_all(base.join("first/project"));
+        let _ = fs::create_dir_all(base.join("second/project"));
+        let _ = fs::create_dir_all(base.join("second/other"));
+        let cdpath = format!("{}:{}", base.join("first").display(), base.join("second").display());
+        assert_eq!(Some(base.join("first/project")), find_in_cdpath("project", &cdpath));
+        assert_eq!(Some(base.join("second/other")), find_in_cdpath("other", &cdpath));
+        assert_eq!(None, find_in_cdpath("missing", &cdpath));
+        assert_eq!(None, find_in_cdpath("./project", &cdpath));
+        assert_eq!(None, find_in_cdpath("../project", &cdpath));
+        assert_eq!(None, find_in_cdpath("project", ""));
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn follow_relative_directories_logically() {
+        let pwd = "/home/user/link".to_string();
+        assert_eq!(PathBuf::from("/home/user"), logical_path("..", Some(&pwd)));
+        assert_eq!(PathBuf::from("/home/user/link/src"), logical_path("./src/", Some(&pwd)));
+        assert_eq!(PathBuf::from("/tmp"), logical_path("/var/../tmp", Some(&pwd)));
+        assert_eq!(PathBuf::from("/"), logical_path("../../../..", Some(&pwd)));
+        assert_eq!(PathBuf::from("../src"), logical_path("../src", Some(&"?".to_string())));
+        assert_eq!(PathBuf::from("src"), logical_path("src", None));
+    }
+
+    #[test]
+    fn pop_back_to_the_directory_that_was_reached() {
+        let base = temp_dir().join("ion-popd-test");
+        let _ = fs::create_dir_all(base.join("start"));
+        let _ = fs::create_dir_all(base.join("other"));
+        let original = current_dir().unwrap();
+        let path = |dir: &str| base.join(dir).to_string_lossy().to_string();
+        let mut variables = Variables::new();
+        let mut stack = DirectoryStack::new().unwrap();
+        assert_eq!(SUCCESS, stack.cd(vec!["cd", &path("start")[..]], &mut variables));
+        assert_eq!(SUCCESS, stack.pushd(vec!["pushd", &path("other")[..]], &mut variables));
+        assert_eq!(SUCCESS, stack.popd(vec!["popd"], &mut variables));
+        assert_eq!(Some(&path("start")), variables.get_var("PWD"));
+        assert_eq!(Some(&path("other")), variables.get_var("OLDPWD"));
+        assert_eq!(SUCCESS, stack.cd(vec!["cd", ".."], &mut variables));
+        assert_eq!(Some(&base.to_string_lossy().to_string()), variables.get_var("PWD"));
+        // A directory that can not be returned to stays on the stack
+        let _ = fs::remove_dir(base.join("start"));
+        assert_eq!(FAILURE, stack.cd(vec!["cd", "-"], &mut variables));
+        assert_eq!(Some(&base.join("start")), stack.get_previous_dir());
+        set_current_dir(original).unwrap();
+        let _ = fs::remove_dir_all(&base);
+    }
+}
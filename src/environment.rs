@@ -0,0 +1,73 @@
+//! The environment variables of the shell, which are exported to the programs it runs. They are
+//! kept apart from the environment of the shell process, so that they can be copied and restored
+//! along with the other variables, such as for subshells.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map::Iter;
+use std::env;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Environment {
+    variables: BTreeMap<String, String>,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Environment { variables: BTreeMap::new() }
+    }
+
+    /// The environment that the shell was started with. Variables whose names or values are not
+    /// valid unicode are left out.
+    pub fn inherit() -> Self {
+        let mut environment = Environment::new();
+        for (name, value) in env::vars_os() {
+            if let (Ok(name), Ok(value)) = (name.into_string(), value.into_string()) {
+                environment.set(&name, &value);
+            }
+        }
+        environment
+    }
+
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.variables.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.variables.contains_key(name)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.variables.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn unset(&mut self, name: &str) -> Option<String> {
+        self.variables.remove(name)
+    }
+
+    /// The variables, in order of their names
+    pub fn iter(&self) -> Iter<String, String> {
+        self.variables.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn set_and_unset_variables() {
+        let mut environment = Environment::new();
+        environment.set("EDITOR", "vi");
+        assert!(environment.contains("EDITOR"));
+        assert_eq!(Some(&"vi".to_string()), environment.get("EDITOR"));
+        assert_eq!(Some("vi".to_string()), environment.unset("EDITOR"));
+        assert_eq!(None, environment.get("EDITOR"));
+    }
+
+    #[test]
+    fn inherit_the_environment_of_the_shell() {
+        let environment = Environment::inherit();
+        assert_eq!(env::var("PATH").ok().as_ref(), environment.get("PATH"));
+    }
+}
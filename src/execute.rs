@@ -0,0 +1,751 @@
+//! Runs the jobs of parsed pipelines as child processes.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::env;
+use std::process::{Command, Stdio, Child};
+use std::os::unix::fs::PermissionsExt;
+use std::io::{self, stdout, stderr, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use std::thread;
+
+use libc::{self, pid_t};
+
+use super::status::{SUCCESS, FAILURE, STOPPED, TERMINATED, NO_SUCH_COMMAND};
+use super::peg::{Pipeline, Job, Redirection, Target};
+use super::environment::Environment;
+use super::jobs::JobTable;
+use super::signals;
+
+/// Runs the pipeline in the foreground, and waits for it to finish or stop. Returns the exit
+/// status of the last job, or with `pipefail` the first one that failed, along with the status of
+/// every job. The command is the code of the pipeline, which is shown if it stops.
+///
+/// This function will panic if called with a pipeline without jobs
+pub fn execute_pipeline(pipeline: &Pipeline,
+                        command: String,
+                        environment: &Environment,
+                        cache: &mut CommandCache,
+                        jobs: &mut JobTable,
+                        pipefail: bool)
+                        -> (i32, Vec<i32>) {
+    let children = spawn(&pipeline.jobs, environment, cache, jobs.job_control(), true, 0);
+    let failures: Vec<Option<i32>> = children.iter().map(|child| child.as_ref().err().cloned()).collect();
+    let mut processes = match jobs.wait_for_pipeline(command, pids(children)) {
+        Some(statuses) => statuses.into_iter(),
+        None => return (STOPPED, vec![STOPPED; pipeline.jobs.len()]),
+    };
+    // The jobs that could not be started have the status they failed with
+    let statuses: Vec<i32> = failures.iter()
+                                     .map(|failure| failure.unwrap_or_else(|| processes.next().unwrap_or(TERMINATED)))
+                                     .collect();
+    if statuses.contains(&(128 + libc::SIGINT)) {
+        signals::interrupt();
+    }
+    (pipeline_status(&statuses, pipefail), statuses)
+}
+
+/// The exit status of a pipeline from the status of each of its jobs, which is that of the last
+/// job, or with `pipefail` the first one that failed
+pub fn pipeline_status(statuses: &[i32], pipefail: bool) -> i32 {
+    if pipefail {
+        statuses.iter().cloned().find(|&status| status != SUCCESS).unwrap_or(SUCCESS)
+    } else {
+        statuses[statuses.len() - 1]
+    }
+}
+
+/// Spawns every job of the pipeline without waiting for them, returning the processes of the
+/// jobs that could be started.
+pub fn execute_background(pipeline: &Pipeline,
+                          environment: &Environment,
+                          cache: &mut CommandCache,
+                          job_control: bool)
+                          -> Vec<u32> {
+    pids(spawn(&pipeline.jobs, environment, cache, job_control, false, 0))
+}
+
+/// Spawns every job of the pipeline in the foreground without waiting for them, like
+/// `execute_background`. With job control, the jobs join the process group, or start a new one if
+/// it is zero, so that the programs that `parallel` runs at the same time share the terminal.
+pub fn execute_in_group(pipeline: &Pipeline,
+                        environment: &Environment,
+                        cache: &mut CommandCache,
+                        job_control: bool,
+                        pgid: pid_t)
+                        -> Vec<u32> {
+    pids(spawn(&pipeline.jobs, environment, cache, job_control, true, pgid))
+}
+
+fn pids(children: Vec<Result<Child, i32>>) -> Vec<u32> {
+    children.iter().filter_map(|child| child.as_ref().ok().map(|child| child.id())).collect()
+}
+
+/// Spawns every job, connecting the output of each one to the input of the next. Jobs that could
+/// not be started have the exit status they fail with instead of a child. With job control, the
+/// jobs are put in the process group, or in a new one led by the first job if it is zero, which
+/// is given the terminal if it runs in the foreground.
+fn spawn(jobs: &[Job],
+         environment: &Environment,
+         cache: &mut CommandCache,
+         job_control: bool,
+         foreground: bool,
+         group: pid_t)
+         -> Vec<Result<Child, i32>> {
+    let end = jobs.len() - 1;
+    let mut children: Vec<Result<Child, i32>> = vec![];
+    let mut pgid = group;
+    for (index, job) in jobs.iter().enumerate() {
+        // The read end of the pipe is taken from the previous child, so that the shell does not
+        // keep it open once it has been given to this job
+        let previous_stdout = match children.last_mut() {
+            Some(&mut Ok(ref mut child)) => child.stdout.take(),
+            _ => None,
+        };
+        let path = environment.get("PATH").map(|path| path.as_str());
+        let program = match cache.find(&job.command, path) {
+            Some(program) => program,
+            None => {
+                let suggestions = cache.suggest(&job.command, path);
+                if suggestions.is_empty() {
+                    println!("ion: command not found: {}", job.command);
+                } else {
+                    println!("ion: command not found: {}, did you mean {}?", job.command, suggestions.join(", "));
+                }
+                children.push(Err(NO_SUCH_COMMAND));
+                continue;
+            },
+        };
+        let mut command = job.build_command(&program, environment.iter());
+        if index < end {
+            command.stdout(Stdio::piped());
+        }
+        if job_control {
+            // Zero puts the first job in a new group with its own process ID
+            let group = pgid;
+            command.before_exec(move || {
+                unsafe {
+                    libc::setpgid(0, group);
+                    if foreground {
+                        libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+                    }
+                    for &signal in &[libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+                        libc::signal(signal, libc::SIG_DFL);
+                    }
+                }
+                Ok(())
+            });
+        }
+        if index > 0 {
+            match previous_stdout {
+                Some(stdout) => unsafe { command.stdin(Stdio::from_raw_fd(stdout.into_raw_fd())); },
+                // The previous command failed to spawn
+                None => { command.stdin(Stdio::null()); },
+            }
+        }
+        let child = if let Some(redirections) = open_redirections(&job.redirections) {
+            // The redirections are applied after the pipes, so that they take the place of them
+            let steps = redirections.steps.clone();
+            command.before_exec(move || {
+                for &(fd, source) in steps.iter() {
+                    try!(apply(fd, source));
+                }
+                Ok(())
+            });
+            let child = command.spawn();
+            // The child has its own copies of the files now
+            drop(redirections);
+            match child {
+                Ok(child) => {
+                    if job_control {
+                        // The group is also set here, so that it exists before the shell
+                        // gives it the terminal or starts the next job in it
+                        if pgid == 0 {
+                            pgid = child.id() as pid_t;
+                        }
+                        unsafe { libc::setpgid(child.id() as pid_t, pgid) };
+                    }
+                    Ok(child)
+                },
+                Err(err) => {
+                    println!("ion: failed to run {}: {}", job.command, err);
+                    Err(FAILURE)
+                },
+            }
+        } else {
+            Err(FAILURE)
+        };
+        // The command holds the shell's copies of the child's ends of its pipes, which are
+        // closed here so that the jobs see the end of their input when the job before exits
+        drop(command);
+        children.push(child);
+    }
+    children
+}
+
+/// Replaces the shell with the program that runs the command, which is the first of the
+/// arguments. This only returns if the program could not be run, with the exit status of the
+/// failure.
+pub fn exec(args: &[String], environment: &Environment, cache: &mut CommandCache) -> i32 {
+    let program = match cache.find(&args[0], environment.get("PATH").map(|path| path.as_str())) {
+        Some(program) => program,
+        None => {
+            println!("ion: exec: {}: command not found", args[0]);
+            return NO_SUCH_COMMAND;
+        },
+    };
+    let mut command = Command::new(&program);
+    command.args(&args[1..]).env_clear();
+    for (name, value) in environment.iter() {
+        command.env(name, value);
+    }
+    // The program should not ignore the signals that the shell ignores for job control
+    command.before_exec(|| {
+        for &signal in &[libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+            unsafe { libc::signal(signal, libc::SIG_DFL) };
+        }
+        Ok(())
+    });
+    flush();
+    let err = command.exec();
+    println!("ion: exec: {}: {}", args[0], err);
+    FAILURE
+}
+
+/// Finds the program that runs the command. Commands that contain a `/` are paths to the
+/// program, and others are looked up in the directories of the path, in order.
+pub fn find_command(command: &str, path: Option<&str>) -> Option<PathBuf> {
+    if command.contains('/') {
+        return Some(PathBuf::from(command));
+    }
+    if command.is_empty() {
+        return None;
+    }
+    path.and_then(|paths| {
+        env::split_paths(paths).map(|directory| directory.join(command)).find(|path| is_executable(path))
+    })
+}
+
+/// Remembers where the commands that have been run were found on the `$PATH`, so that the
+/// directories are only searched the first time, like the hash table of other shells. The cache
+/// is emptied when `$PATH` changes.
+pub struct CommandCache {
+    path: Option<String>,
+    commands: BTreeMap<String, PathBuf>,
+    /// The names of every program on the `$PATH`, once they have been listed for suggestions
+    names: Option<Vec<String>>,
+}
+
+impl CommandCache {
+    pub fn new() -> Self {
+        CommandCache {
+            path: None,
+            commands: BTreeMap::new(),
+            names: None,
+        }
+    }
+
+    fn update_path(&mut self, path: Option<&str>) {
+        if path != self.path.as_ref().map(|path| path.as_str()) {
+            self.commands.clear();
+            self.names = None;
+            self.path = path.map(|path| path.to_string());
+        }
+    }
+
+    /// Finds the program that runs the command, like `find_command`. A program that was found
+    /// before is only searched for again if it is no longer executable.
+    pub fn find(&mut self, command: &str, path: Option<&str>) -> Option<PathBuf> {
+        if command.contains('/') || command.is_empty() {
+            return find_command(command, path);
+        }
+        self.update_path(path);
+        if let Some(program) = self.commands.get(command) {
+            if is_executable(program) {
+                return Some(program.clone());
+            }
+        }
+        match find_command(command, path) {
+            Some(program) => {
+                self.commands.insert(command.to_string(), program.clone());
+                Some(program)
+            },
+            None => {
+                self.commands.remove(command);
+                None
+            },
+        }
+    }
+
+    /// The commands that have been found, with their programs
+    pub fn commands(&self) -> &BTreeMap<String, PathBuf> {
+        &self.commands
+    }
+
+    pub fn clear(&mut self) {
+        self.commands.clear();
+        self.names = None;
+    }
+
+    /// The programs on the path with names that are close to the command, such as `git` for
+    /// `gti`, closest first. The directories are only listed the first time.
+    pub fn suggest(&mut self, command: &str, path: Option<&str>) -> Vec<String> {
+        self.update_path(path);
+        if self.names.is_none() {
+            let mut names = vec![];
+            for directory in path.map_or(vec![], |paths| env::split_paths(paths).collect()) {
+                if let Ok(entries) = fs::read_dir(directory) {
+                    for entry in entries.filter_map(|entry| entry.ok()) {
+                        if let Ok(name) = entry.file_name().into_string() {
+                            if is_executable(&entry.path()) {
+                                names.push(name);
+                            }
+                        }
+                    }
+                }
+            }
+            names.sort();
+            names.dedup();
+            self.names = Some(names);
+        }
+        // Longer names can be further off and still be what was meant
+        let limit = if command.chars().count() < 5 { 1 } else { 2 };
+        let mut suggestions: Vec<(usize, &String)> = self.names
+                                                         .iter()
+                                                         .flat_map(|names| names.iter())
+                                                         .map(|name| (edit_distance(command, name), name))
+                                                         .filter(|&(distance, _)| distance <= limit)
+                                                         .collect();
+        suggestions.sort();
+        suggestions.into_iter().take(3).map(|(_, name)| name.clone()).collect()
+    }
+}
+
+/// The number of characters that have to be inserted, removed, replaced or swapped with the one
+/// next to them to turn one text into the other
+fn edit_distance(from: &str, to: &str) -> usize {
+    let from: Vec<char> = from.chars().collect();
+    let to: Vec<char> = to.chars().collect();
+    // The distances between the beginnings of the texts, by their lengths
+    let mut distances = vec![vec![0; to.len() + 1]; from.len() + 1];
+    for i in 0..from.len() + 1 {
+        distances[i][0] = i;
+    }
+    for j in 0..to.len() + 1 {
+        distances[0][j] = j;
+    }
+    for i in 1..from.len() + 1 {
+        for j in 1..to.len() + 1 {
+            let cost = if from[i - 1] == to[j - 1] { 0 } else { 1 };
+            let mut distance = *[distances[i - 1][j] + 1, distances[i][j - 1] + 1, distances[i - 1][j - 1] + cost]
+                                    .iter()
+                                    .min()
+                                    .unwrap();
+            if i > 1 && j > 1 && from[i - 1] == to[j - 2] && from[i - 2] == to[j - 1] {
+                distance = cmp::min(distance, distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[from.len()][to.len()]
+}
+
+fn is_executable(path: &PathBuf) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+/// The files that the redirections of a job opened, and what is done to its descriptors, in the
+/// order the redirections were written. Each step points a descriptor at another one, or closes
+/// it if there is none.
+struct Redirections {
+    files: Vec<File>,
+    steps: Vec<(RawFd, Option<RawFd>)>,
+}
+
+/// Whether `>` refuses to truncate files that already exist, which `>|` still does
+static NOCLOBBER: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Sets whether `>` refuses to truncate files that already exist, from the `noclobber` option
+pub fn set_noclobber(noclobber: bool) {
+    NOCLOBBER.store(noclobber, Ordering::SeqCst);
+}
+
+/// Creates the file for `>`. With `noclobber`, an existing regular file is not truncated, while
+/// other files such as `/dev/null` can still be written to.
+fn create(path: &str) -> io::Result<File> {
+    if NOCLOBBER.load(Ordering::SeqCst) && fs::metadata(path).map(|metadata| metadata.is_file()).unwrap_or(false) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "cannot overwrite existing file"));
+    }
+    File::create(path)
+}
+
+/// Opens the files named by a job's redirections. Returns None if a file could not be opened,
+/// after reporting why.
+fn open_redirections(redirections: &[Redirection]) -> Option<Redirections> {
+    let mut opened = Redirections {
+        files: vec![],
+        steps: vec![],
+    };
+    // Files are moved above every descriptor that is redirected, so that applying one step can
+    // not replace a file that a later step needs, and so that none of them is left open on its
+    // own target with the flag that closes it in programs
+    let lowest = redirections.iter()
+        .map(|redirection| redirection.fd as RawFd + 1)
+        .fold(10, cmp::max);
+    for redirection in redirections {
+        let fd = redirection.fd as RawFd;
+        let (path, file) = match redirection.target {
+            // The paths have been expanded into literal words by now
+            Target::Input(ref path) => (path.to_string(), File::open(path.to_string())),
+            Target::Output(ref path) => (path.to_string(), create(&path.to_string())),
+            Target::Clobber(ref path) => (path.to_string(), File::create(path.to_string())),
+            Target::Append(ref path) => {
+                let path = path.to_string();
+                let file = OpenOptions::new().write(true).append(true).create(true).open(&path);
+                (path, file)
+            },
+            Target::Duplicate(target) => {
+                opened.steps.push((fd, Some(target as RawFd)));
+                continue;
+            },
+            Target::Close => {
+                opened.steps.push((fd, None));
+                continue;
+            },
+            Target::HereDocument(ref body) => ("here document".to_string(), here_document(body.to_string())),
+            Target::HereString(ref word) => ("here string".to_string(), here_document(format!("{}\n", word))),
+        };
+        match file.and_then(|file| move_above(file, lowest)) {
+            Ok(file) => {
+                opened.steps.push((fd, Some(file.as_raw_fd())));
+                opened.files.push(file);
+            },
+            Err(err) => {
+                println!("ion: {}: {}", path, err);
+                return None;
+            },
+        }
+    }
+    Some(opened)
+}
+
+/// Moves the file to the first free descriptor from `lowest` up, which is not passed to programs
+fn move_above(file: File, lowest: RawFd) -> io::Result<File> {
+    let fd = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, lowest) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Opens a pipe, returning the end that is read from and the end that is written to. Neither end
+/// is passed on to programs unless it is redirected.
+pub fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for &fd in fds.iter() {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Opens a pipe that the text is written into by another thread, so that the text can be read
+/// from it however long it is
+fn here_document(text: String) -> io::Result<File> {
+    let (reader, mut writer) = try!(pipe());
+    // The writer stops early if the text is not all read, since it then gets an error
+    thread::spawn(move || {
+        let _ = writer.write_all(text.as_bytes());
+    });
+    Ok(reader)
+}
+
+/// Applies a step of a redirection to the descriptors of this process. This is also run in
+/// children between forking and executing the program, so it only makes system calls.
+fn apply(fd: RawFd, source: Option<RawFd>) -> io::Result<()> {
+    match source {
+        Some(source) => {
+            if unsafe { libc::dup2(source, fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        },
+        // Closing a descriptor that is not open is not an error
+        None => unsafe { libc::close(fd); },
+    }
+    Ok(())
+}
+
+/// The standard streams of the shell while they are redirected for a builtin, function or group,
+/// which are put back when this is dropped
+pub struct RedirectedStreams {
+    saved: Vec<(RawFd, Option<RawFd>)>,
+}
+
+/// Redirects the descriptors of the shell itself, returning None if they could not be
+/// redirected, after reporting why
+pub fn redirect_shell(redirections: &[Redirection]) -> Option<RedirectedStreams> {
+    let redirections = match open_redirections(redirections) {
+        Some(redirections) => redirections,
+        None => return None,
+    };
+    flush();
+    let mut streams = RedirectedStreams { saved: vec![] };
+    for &(fd, source) in redirections.steps.iter() {
+        // The copies are kept above the descriptors that redirections usually use, and are not
+        // passed on to programs
+        let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+        streams.saved.push((fd, if copy < 0 { None } else { Some(copy) }));
+        if let Err(err) = apply(fd, source) {
+            println!("ion: failed to redirect file descriptor {}: {}", fd, err);
+            return None;
+        }
+    }
+    Some(streams)
+}
+
+/// Redirects the input of the shell to the file, such as the pipe that a builtin at the end of a
+/// pipeline reads from
+pub fn redirect_input(file: File) -> RedirectedStreams {
+    flush();
+    let copy = unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_DUPFD_CLOEXEC, 10) };
+    unsafe { libc::dup2(file.as_raw_fd(), libc::STDIN_FILENO) };
+    RedirectedStreams { saved: vec![(libc::STDIN_FILENO, if copy < 0 { None } else { Some(copy) })] }
+}
+
+impl RedirectedStreams {
+    /// Keeps the redirections instead of putting the streams back, such as for `exec > log`
+    pub fn keep(mut self) {
+        for &(_, copy) in self.saved.iter() {
+            if let Some(copy) = copy {
+                unsafe { libc::close(copy) };
+            }
+        }
+        self.saved.clear();
+    }
+}
+
+impl Drop for RedirectedStreams {
+    fn drop(&mut self) {
+        flush();
+        for &(fd, copy) in self.saved.iter().rev() {
+            match copy {
+                Some(copy) => unsafe {
+                    libc::dup2(copy, fd);
+                    libc::close(copy);
+                },
+                None => unsafe { libc::close(fd); },
+            }
+        }
+    }
+}
+
+/// Writes what builtins have printed so far, before the descriptors it goes to change
+fn flush() {
+    let _ = stdout().flush();
+    let _ = stderr().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::io::Read;
+    use environment::Environment;
+    use jobs::JobTable;
+
+    fn path() -> Option<String> {
+        env::var("PATH").ok()
+    }
+
+    fn run(code: &str) -> i32 {
+        let pipeline = ::peg::parse(code).unwrap().remove(0);
+        execute_pipeline(&pipeline,
+                         code.to_string(),
+                         &Environment::inherit(),
+                         &mut CommandCache::new(),
+                         &mut JobTable::new(),
+                         false)
+            .0
+    }
+
+    fn run_with_pipefail(code: &str) -> i32 {
+        let pipeline = ::peg::parse(code).unwrap().remove(0);
+        execute_pipeline(&pipeline,
+                         code.to_string(),
+                         &Environment::inherit(),
+                         &mut CommandCache::new(),
+                         &mut JobTable::new(),
+                         true)
+            .0
+    }
+
+    #[test]
+    fn pipe_output_between_jobs() {
+        assert_eq!(0, run("printf 'a\\nb\\n' | grep -q b"));
+        assert_eq!(1, run("printf 'a\\n' | grep -q b"));
+    }
+
+    #[test]
+    fn last_job_sets_the_status() {
+        assert_eq!(0, run("false | true"));
+        assert_eq!(::status::NO_SUCH_COMMAND, run("true | ion-command-that-does-not-exist"));
+        assert_eq!(128 + libc::SIGTERM, run("sh -c 'kill $$'"));
+    }
+
+    #[test]
+    fn status_of_every_job() {
+        let pipeline = ::peg::parse("sh -c 'exit 2' | ion-command-that-does-not-exist | true").unwrap().remove(0);
+        let (status, statuses) = execute_pipeline(&pipeline,
+                                                  String::new(),
+                                                  &Environment::inherit(),
+                                                  &mut CommandCache::new(),
+                                                  &mut JobTable::new(),
+                                                  false);
+        assert_eq!(0, status);
+        assert_eq!(vec![2, ::status::NO_SUCH_COMMAND, 0], statuses);
+    }
+
+    #[test]
+    fn pipefail_takes_the_first_failure() {
+        assert_eq!(1, run_with_pipefail("false | true"));
+        assert_eq!(3, run_with_pipefail("sh -c 'exit 3' | sh -c 'exit 4' | true"));
+        assert_eq!(::status::NO_SUCH_COMMAND, run_with_pipefail("ion-command-that-does-not-exist | true"));
+        assert_eq!(0, run_with_pipefail("true | true"));
+    }
+
+    #[test]
+    fn find_commands_on_the_path() {
+        let sh = find_command("sh", path().as_ref().map(|path| path.as_str())).expect("sh should be on the path");
+        assert!(sh.is_absolute() && sh.ends_with("sh"));
+        assert_eq!(None, find_command("ion-command-that-does-not-exist", Some("/bin")));
+        assert_eq!(None, find_command("", Some("/bin")));
+        assert_eq!(Some(PathBuf::from("./target/ion")), find_command("./target/ion", None));
+    }
+
+    #[test]
+    fn cache_found_commands() {
+        let path = path();
+        let path = path.as_ref().map(|path| path.as_str());
+        let mut cache = CommandCache::new();
+        assert_eq!(find_command("sh", path), cache.find("sh", path));
+        assert_eq!(find_command("sh", path).as_ref(), cache.commands().get("sh"));
+        assert_eq!(None, cache.find("ion-command-that-does-not-exist", path));
+        assert_eq!(Some(PathBuf::from("./target/ion")), cache.find("./target/ion", path));
+        assert_eq!(1, cache.commands().len());
+        assert_eq!(None, cache.find("sh", Some("/ion/directory/that/does/not/exist")));
+        assert!(cache.commands().is_empty());
+        cache.find("sh", path);
+        cache.clear();
+        assert!(cache.commands().is_empty());
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(0, edit_distance("git", "git"));
+        assert_eq!(1, edit_distance("gti", "git"));
+        assert_eq!(1, edit_distance("carg", "cargo"));
+        assert_eq!(3, edit_distance("kitten", "sitting"));
+        assert_eq!(3, edit_distance("", "abc"));
+    }
+
+    #[test]
+    fn suggest_programs_with_close_names() {
+        let directory = env::temp_dir().join(format!("ion-suggest-{}", unsafe { libc::getpid() }));
+        fs::create_dir_all(&directory).unwrap();
+        for &(name, mode) in &[("git", 0o755), ("gist", 0o755), ("gif", 0o644), ("make", 0o755)] {
+            let path = directory.join(name);
+            File::create(&path).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let path = directory.to_str().unwrap().to_string();
+        let mut cache = CommandCache::new();
+        assert_eq!(vec!["git".to_string(), "gist".to_string()], cache.suggest("gti", Some(&path)));
+        assert!(cache.suggest("cargo", Some(&path)).is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn here_documents_are_read_from_stdin() {
+        let output = temporary_file("here-document");
+        assert_eq!(0, run(&format!("cat <<EOF > {}\nfirst\nsecond\nEOF", output)));
+        assert_eq!("first\nsecond\n", read(&output));
+        assert_eq!(0, run("grep -q word <<< word"));
+    }
+
+    fn temporary_file(name: &str) -> String {
+        let path = env::temp_dir().join(format!("ion-{}-{}", name, unsafe { libc::getpid() }));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn read(path: &str) -> String {
+        let mut text = String::new();
+        File::open(path).unwrap().read_to_string(&mut text).unwrap();
+        fs::remove_file(path).unwrap();
+        text
+    }
+
+    #[test]
+    fn redirections_are_applied_in_order() {
+        let path = temporary_file("both");
+        assert_eq!(0, run(&format!("sh -c 'echo out; echo err >&2' > {} 2>&1", path)));
+        assert_eq!("out\nerr\n", read(&path));
+        let path = temporary_file("out");
+        let code = format!("sh -c 'echo err >&2' 2>&1 > {} | grep -q err", path);
+        assert_eq!(0, run(&code));
+        assert_eq!("", read(&path));
+    }
+
+    #[test]
+    fn redirect_low_descriptors() {
+        let path = temporary_file("three");
+        assert_eq!(0, run(&format!("sh -c 'echo three >&3' 3> {}", path)));
+        assert_eq!("three\n", read(&path));
+        let (first, second) = (temporary_file("four"), temporary_file("other-three"));
+        assert_eq!(0, run(&format!("sh -c 'echo four >&4; echo three >&3' 4> {} 3> {}", first, second)));
+        assert_eq!("four\n", read(&first));
+        assert_eq!("three\n", read(&second));
+    }
+
+    #[test]
+    fn report_files_that_can_not_be_opened() {
+        assert_eq!(::status::FAILURE, run("cat < /ion/file/that/does/not/exist"));
+    }
+
+    #[test]
+    fn redirect_the_shell() {
+        let path = temporary_file("shell");
+        {
+            let redirections = vec![Redirection::new(5, Target::output(&path))];
+            let _streams = redirect_shell(&redirections).unwrap();
+            assert_eq!(0, run("sh -c 'echo builtin >&5'"));
+        }
+        assert_eq!(-1, unsafe { libc::fcntl(5, libc::F_GETFD) });
+        assert_eq!("builtin\n", read(&path));
+    }
+
+    #[test]
+    fn keep_redirections_of_the_shell() {
+        let path = temporary_file("keep");
+        let redirections = vec![Redirection::new(6, Target::output(&path))];
+        redirect_shell(&redirections).unwrap().keep();
+        assert_eq!(0, run("sh -c 'echo kept >&6'"));
+        unsafe { libc::close(6) };
+        assert_eq!("kept\n", read(&path));
+    }
+
+    #[test]
+    fn exec_reports_commands_that_are_not_found() {
+        let args = vec!["ion-command-that-does-not-exist".to_string()];
+        assert_eq!(::status::NO_SUCH_COMMAND,
+                   exec(&args, &Environment::inherit(), &mut CommandCache::new()));
+    }
+}
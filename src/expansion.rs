@@ -0,0 +1,199 @@
+use glob::glob;
+
+use super::peg::{Word, WordSegment};
+
+/// Expands the brace and range expansions in the word, producing a word for each combination of
+/// their alternatives. A word without any is returned as the only word.
+pub fn expand_braces(word: &Word) -> Vec<Word> {
+    let mut expanded: Vec<Vec<WordSegment>> = vec![vec![]];
+    for segment in word.segments.iter() {
+        let alternatives: Vec<Word> = match *segment {
+            WordSegment::Brace(ref alternatives) => {
+                alternatives.iter().flat_map(expand_braces).collect()
+            },
+            WordSegment::Range(start, end, step) => {
+                range(start, end, step).iter()
+                                       .map(|number| Word::literal(&number.to_string()))
+                                       .collect()
+            },
+            _ => {
+                for combination in expanded.iter_mut() {
+                    combination.push(segment.clone());
+                }
+                continue;
+            },
+        };
+        let mut combinations = vec![];
+        for prefix in expanded.iter() {
+            for alternative in alternatives.iter() {
+                let mut combination = prefix.clone();
+                combination.extend(alternative.segments.iter().cloned());
+                combinations.push(combination);
+            }
+        }
+        expanded = combinations;
+    }
+    expanded.into_iter().map(Word::new).collect()
+}
+
+/// Returns the numbers from start to end, inclusive, counting by the size of the step in the
+/// direction of the end.
+fn range(start: i64, end: i64, step: i64) -> Vec<i64> {
+    let step = if step == 0 { 1 } else { step.abs() };
+    let mut numbers = vec![];
+    let mut number = start;
+    while (start <= end && number <= end) || (start > end && number >= end) {
+        numbers.push(number);
+        let next = if start <= end { number.checked_add(step) } else { number.checked_sub(step) };
+        match next {
+            Some(next) => number = next,
+            None => break,
+        }
+    }
+    numbers
+}
+
+/// Escapes the glob characters in the text, so that a pattern containing it only matches the
+/// text literally.
+pub fn escape_pattern(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '*' | '?' | '[' | ']' => {
+                escaped.push('[');
+                escaped.push(character);
+                escaped.push(']');
+            },
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// Returns the paths matching the glob pattern. The result is empty if the pattern is invalid or
+/// does not match anything. A `**` path component matches any number of directories when
+/// `recursive` is set, and behaves like `*` otherwise.
+pub fn expand_glob(pattern: &str, recursive: bool) -> Vec<String> {
+    match glob(&normalize_stars(pattern, recursive)) {
+        Ok(paths) => {
+            paths.filter_map(Result::ok)
+                 .map(|path| path.to_string_lossy().into_owned())
+                 .collect()
+        },
+        Err(_) => vec![],
+    }
+}
+
+/// Collapses runs of `*` into a single one, except for whole `**` path components when recursive
+/// globbing is enabled. The glob crate rejects `**` anywhere else.
+fn normalize_stars(pattern: &str, recursive: bool) -> String {
+    let components: Vec<String> = pattern.split('/').map(|component| {
+        if recursive && component == "**" {
+            component.to_string()
+        } else {
+            let mut collapsed = String::with_capacity(component.len());
+            for character in component.chars() {
+                if character != '*' || !collapsed.ends_with('*') {
+                    collapsed.push(character);
+                }
+            }
+            collapsed
+        }
+    }).collect();
+    components.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peg::{parse, Word};
+
+    fn word(code: &str) -> Word {
+        parse(code).unwrap().remove(0).jobs.remove(0).words.remove(0)
+    }
+
+    fn expand(code: &str) -> Vec<String> {
+        expand_braces(&word(code)).iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn expand_braces_with_prefix_and_suffix() {
+        assert_eq!(vec!["file.old", "file.new"], expand("file.{old,new}"));
+        assert_eq!(vec!["a.c", "b.c"], expand("{a,b}.c"));
+    }
+
+    #[test]
+    fn expand_nested_braces() {
+        assert_eq!(vec!["abf", "acdf", "acef"], expand("a{b,c{d,e}}f"));
+    }
+
+    #[test]
+    fn expand_several_braces() {
+        assert_eq!(vec!["a1", "a2", "b1", "b2"], expand("{a,b}{1,2}"));
+    }
+
+    #[test]
+    fn expand_empty_alternative() {
+        assert_eq!(vec!["x", "xy"], expand("x{,y}"));
+    }
+
+    #[test]
+    fn expand_ranges() {
+        assert_eq!(vec!["1", "2", "3"], expand("{1..3}"));
+        assert_eq!(vec!["5", "3", "1"], expand("{5..1..2}"));
+        assert_eq!(vec!["x-1", "x0", "x1"], expand("x{-1..1}"));
+        assert_eq!(vec!["0", "4", "8"], expand("{0..10..-4}"));
+    }
+
+    #[test]
+    fn expand_range_inside_braces() {
+        assert_eq!(vec!["a", "1", "2"], expand("{a,{1..2}}"));
+    }
+
+    #[test]
+    fn word_without_braces_is_unchanged() {
+        assert_eq!(vec!["{}"], expand("{}"));
+    }
+
+    #[test]
+    fn escape_glob_characters() {
+        assert_eq!("a[*]b[?][[]c[]]", escape_pattern("a*b?[c]"));
+        assert_eq!("plain.rs", escape_pattern("plain.rs"));
+    }
+
+    #[test]
+    fn expand_matching_pattern() {
+        assert_eq!(vec!["src/main.rs".to_string()], expand_glob("src/ma*.rs", false));
+    }
+
+    #[test]
+    fn recursive_pattern_matches_any_depth() {
+        let paths = expand_glob("**/Cargo.toml", true);
+        assert!(paths.contains(&"Cargo.toml".to_string()));
+        let paths = expand_glob("**/main.rs", true);
+        assert!(paths.contains(&"src/main.rs".to_string()));
+    }
+
+    #[test]
+    fn recursive_pattern_is_a_single_star_when_disabled() {
+        let paths = expand_glob("**/Cargo.toml", false);
+        assert!(!paths.contains(&"Cargo.toml".to_string()));
+    }
+
+    #[test]
+    fn stars_are_collapsed_within_components() {
+        assert_eq!("a*b/**/c*", normalize_stars("a**b/**/c***", true));
+        assert_eq!("a*b/*/c*", normalize_stars("a**b/**/c***", false));
+        assert_eq!("[*][*]", normalize_stars("[*][*]", false));
+    }
+
+    #[test]
+    fn escaped_pattern_matches_literally() {
+        assert!(expand_glob(&escape_pattern("src/*.rs"), false).is_empty());
+    }
+
+    #[test]
+    fn invalid_pattern_matches_nothing() {
+        assert!(expand_glob("[", false).is_empty());
+    }
+}
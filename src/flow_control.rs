@@ -1,152 +1,77 @@
+use super::peg::{Condition, Statement};
+use super::status::SUCCESS;
 use super::to_num::ToNum;
-use super::peg::Pipeline;
-use super::status::{SUCCESS, FAILURE};
 
-pub fn is_flow_control_command(command: &str) -> bool {
-    command == "end" || command == "if" || command == "else"
-}
-
-#[derive(Clone)]
-pub enum Statement {
-    For(String, Vec<String>),
-    Function(String, Vec<String>),
-    If,
-    Default
-}
-
-pub struct CodeBlock {
-    pub pipelines: Vec<Pipeline>,
-}
-
-pub struct Mode {
-    pub value: bool,
-}
-
-pub struct FlowControl {
-    pub modes: Vec<Mode>,
-    pub collecting_block: bool,
-    pub current_block: CodeBlock,
-    pub current_statement: Statement, /* pub prompt: &'static str,  // Custom prompt while collecting code block */
+/// Compares the left and right sides of the test of an `if` or `while`. Equality compares the
+/// text, and the orderings compare the numbers the sides contain.
+pub fn compare(left: &str, comparison: &str, right: &str) -> Result<bool, String> {
+    match comparison {
+        "==" => Ok(left == right),
+        "!=" => Ok(left != right),
+        ">" => Ok(left.to_num_signed() > right.to_num_signed()),
+        ">=" => Ok(left.to_num_signed() >= right.to_num_signed()),
+        "<" => Ok(left.to_num_signed() < right.to_num_signed()),
+        "<=" => Ok(left.to_num_signed() <= right.to_num_signed()),
+        _ => Err(format!("unknown comparison: {}", comparison)),
+    }
 }
 
-impl FlowControl {
-    pub fn new() -> FlowControl {
-        FlowControl {
-            modes: vec![],
-            collecting_block: false,
-            current_block: CodeBlock { pipelines: vec![] },
-            current_statement: Statement::Default,
-        }
+/// Whether `set -e` ends the shell after the statement finished with the exit status, given the
+/// statement after it. Failures are allowed in the test of an `if` or `while`, on the left of
+/// `&&` and `||` and in negated pipelines. `if`, `while` and `for` are not checked as a whole,
+/// since the statements inside them are checked as they run.
+pub fn errexit_applies(statement: &Statement,
+                       next: Option<&Statement>,
+                       status: Option<i32>,
+                       in_test: bool)
+                       -> bool {
+    if in_test || status.map_or(true, |status| status == SUCCESS) {
+        return false;
     }
-
-    pub fn skipping(&self) -> bool {
-        self.modes.iter().any(|mode| !mode.value)
+    if next.map_or(false, |next| next.condition() != Condition::Always) {
+        return false;
     }
-
-    pub fn if_<I: IntoIterator>(&mut self, args: I) -> i32
-        where I::Item: AsRef<str>
-    {
-        let mut args = args.into_iter(); // TODO why does the compiler want this to be mutable?
-        let value;
-        if let Some(left) = args.nth(1) {
-            let left = left.as_ref();
-            if let Some(cmp) = args.nth(0) {
-                let cmp = cmp.as_ref();
-                if let Some(right) = args.nth(0) {
-                    let right = right.as_ref();
-                    if cmp == "==" {
-                        value = left == right;
-                    } else if cmp == "!=" {
-                        value = left != right;
-                    } else if cmp == ">" {
-                        value = left.to_num_signed() > right.to_num_signed();
-                    } else if cmp == ">=" {
-                        value = left.to_num_signed() >= right.to_num_signed();
-                    } else if cmp == "<" {
-                        value = left.to_num_signed() < right.to_num_signed();
-                    } else if cmp == "<=" {
-                        value = left.to_num_signed() <= right.to_num_signed();
-                    } else {
-                        println!("Unknown comparison: {}", cmp);
-                        return FAILURE;
-                    }
-                } else {
-                    println!("No right hand side");
-                    return FAILURE;
-                }
-            } else {
-                println!("No comparison operator");
-                return FAILURE;
-            }
-        } else {
-            println!("No left hand side");
-            return FAILURE;
-        }
-        self.modes.insert(0, Mode { value: value });
-        self.current_statement = Statement::If;
-        SUCCESS
+    match *statement {
+        Statement::Pipeline(ref pipeline) => !pipeline.negated,
+        Statement::Subshell { .. } | Statement::Group { .. } | Statement::Assignment { .. } => true,
+        _ => false,
     }
+}
 
-    pub fn else_<I: IntoIterator>(&mut self, _: I) -> i32
-        where I::Item: AsRef<str>
-    {
-        if let Some(mode) = self.modes.get_mut(0) {
-            mode.value = !mode.value;
-            SUCCESS
-        } else {
-            println!("Syntax error: else found with no previous if");
-            FAILURE
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peg::parse_statements;
+    use status::SUCCESS;
 
-    pub fn end<I: IntoIterator>(&mut self, _: I) -> i32
-        where I::Item: AsRef<str>
-    {
-        if !self.modes.is_empty() {
-            self.modes.remove(0);
-            SUCCESS
-        } else {
-            println!("Syntax error: end found outside of a block");
-            FAILURE
-        }
+    fn applies(code: &str, index: usize, in_test: bool) -> bool {
+        let statements = parse_statements(code).unwrap();
+        errexit_applies(&statements[index], statements.get(index + 1), Some(1), in_test)
     }
 
-    pub fn for_<I: IntoIterator>(&mut self, args: I) -> i32
-        where I::Item: AsRef<str>
-    {
-        let mut args = args.into_iter();
-        if let Some(variable) = args.nth(1).map(|var| var.as_ref().to_string()) {
-            if let Some(in_) = args.nth(0) {
-                if in_.as_ref() != "in" {
-                    println!("For loops must have 'in' as the second argument");
-                    return FAILURE;
-                }
-            } else {
-                println!("For loops must have 'in' as the second argument");
-                return FAILURE;
-            }
-            let values: Vec<String> = args.map(|value| value.as_ref().to_string()).collect();
-            self.current_statement = Statement::For(variable, values);
-            self.collecting_block = true;
-        } else {
-            println!("For loops must have a variable name as the first argument");
-            return FAILURE;
-        }
-        SUCCESS
+    #[test]
+    fn errexit_applies_to_failed_commands() {
+        assert!(applies("false", 0, false));
+        assert!(applies("false; echo", 0, false));
+        assert!(applies("(false)", 0, false));
+        assert!(applies("{ false; }", 0, false));
+        let statements = parse_statements("false").unwrap();
+        assert!(!errexit_applies(&statements[0], None, Some(SUCCESS), false));
+        assert!(!errexit_applies(&statements[0], None, None, false));
     }
 
-    pub fn fn_<I: IntoIterator>(&mut self, args: I) -> i32
-        where I::Item: AsRef<str>
-    {
-        let mut args = args.into_iter();
-        if let Some(name) = args.nth(1) {
-            self.collecting_block = true;
-            let values: Vec<String> = args.map(|value| value.as_ref().to_string()).collect();
-            self.current_statement = Statement::Function(name.as_ref().to_string(), values);
-        } else {
-            println!("Functions must have the function name as the first argument");
-            return FAILURE;
-        }
-        SUCCESS
+    #[test]
+    fn errexit_exemptions() {
+        // The test of an `if` or `while`, and anything it calls
+        assert!(!applies("false", 0, true));
+        // The left of `&&` and `||`, but not the right
+        assert!(!applies("false && echo", 0, false));
+        assert!(!applies("false || echo", 0, false));
+        assert!(applies("true && false", 1, false));
+        // Negated pipelines
+        assert!(!applies("! false", 0, false));
+        // Blocks, whose statements are checked on their own
+        assert!(!applies("if false\necho\nend", 0, false));
+        assert!(!applies("for x in a\nfalse\nend", 0, false));
     }
 }
@@ -1,8 +1,8 @@
-use super::peg::Pipeline;
+use super::peg::Statement;
 
 #[derive(Clone)]
 pub struct Function {
     pub name: String,
-    pub pipelines: Vec<Pipeline>,
+    pub statements: Vec<Statement>,
     pub args: Vec<String>
 }
@@ -1,9 +1,27 @@
-use std::io::stdin;
+use std::io;
 
+use libc;
+
+/// Reads a line from standard input. The line is read a byte at a time, so that none of what
+/// comes after it is taken, which matters when the input is a pipe that other commands read too.
 pub fn readln() -> Option<String> {
-    let mut buffer = String::new();
-    match stdin().read_line(&mut buffer) {
-        Ok(_) => Some(buffer),
-        Err(_) => None,
+    let mut bytes = vec![];
+    let mut byte = [0u8; 1];
+    loop {
+        match unsafe { libc::read(libc::STDIN_FILENO, byte.as_mut_ptr() as *mut libc::c_void, 1) } {
+            1 => {
+                bytes.push(byte[0]);
+                if byte[0] == b'\n' {
+                    break;
+                }
+            },
+            0 => break,
+            _ => {
+                if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                    return None;
+                }
+            },
+        }
     }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
 }
@@ -0,0 +1,1075 @@
+//! Keeps track of the pipelines that run in the background, so that they can be referred to by
+//! their job numbers once the prompt is back.
+//!
+//! When the shell is interactive, each pipeline runs in its own process group, and the shell
+//! hands the terminal to the group of the pipeline in the foreground. Pressing Ctrl-Z then stops
+//! only that pipeline, which the shell notices when it waits for it, and the pipeline is added to
+//! the table so that it can be continued with `fg` or `bg`.
+
+use std::fmt;
+use std::io;
+use std::mem;
+
+use libc::{self, c_int, pid_t};
+
+use super::signals;
+use super::status::{SUCCESS, FAILURE, NO_SUCH_COMMAND, STOPPED, TERMINATED};
+use super::usage::Usage;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JobState {
+    Running,
+    Stopped,
+    /// The pipeline finished, with the exit status of its last process
+    Done(i32),
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JobState::Running => write!(f, "Running"),
+            JobState::Stopped => write!(f, "Stopped"),
+            JobState::Done(0) => write!(f, "Done"),
+            JobState::Done(status) => write!(f, "Exit {}", status),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BackgroundJob {
+    /// The job number, which is the lowest one that was not in use when the job started
+    pub id: usize,
+    /// The processes of the pipeline, in the order of its jobs
+    pub pids: Vec<u32>,
+    /// The process group of the pipeline, if it was started with job control
+    pub pgid: Option<u32>,
+    /// The code of the pipeline, as it was written
+    pub command: String,
+    pub state: JobState,
+    /// Whether the job is left running when an interactive shell exits, instead of being sent
+    /// `SIGHUP` like the other jobs
+    pub nohup: bool,
+    /// Whether the user has been told about the state the job is in, once it finished or stopped
+    /// in the background
+    reported: bool,
+    /// The processes that have not finished yet
+    running: Vec<u32>,
+    /// The exit status of each process of the pipeline, once it has finished
+    statuses: Vec<Option<i32>>,
+    /// What the processes that have finished used
+    usage: Usage,
+}
+
+impl BackgroundJob {
+    /// Collects the status of the processes that have finished or stopped. If `block` is true,
+    /// waits until every process has finished, or one of them has stopped. If `interruptible` is
+    /// also true, waiting is given up on when Ctrl-C is pressed or a trapped signal arrives.
+    fn collect(&mut self, block: bool, interruptible: bool) {
+        let options = if block {
+            libc::WUNTRACED
+        } else {
+            libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED
+        };
+        let previous = self.state;
+        for pid in self.running.clone() {
+            let mut status: c_int = 0;
+            let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+            let mut result = unsafe { libc::wait4(pid as pid_t, &mut status, options, &mut rusage) };
+            while result < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+                if interruptible && signals::arrived().is_some() {
+                    return;
+                }
+                result = unsafe { libc::wait4(pid as pid_t, &mut status, options, &mut rusage) };
+            }
+            if result == pid as pid_t {
+                self.record(pid, status, &rusage);
+                if block && libc::WIFSTOPPED(status) {
+                    break;
+                }
+            } else if result < 0 {
+                // The process was already collected, so there is nothing left to wait for
+                self.finish(pid);
+            }
+        }
+        // Jobs in the foreground are reported on as they are waited for
+        if !block && self.state != previous && self.state != JobState::Running {
+            self.reported = false;
+        }
+    }
+
+    /// Records what `wait4` reported for one of the processes of the job
+    fn record(&mut self, pid: u32, status: c_int, rusage: &libc::rusage) {
+        if libc::WIFSTOPPED(status) {
+            self.state = JobState::Stopped;
+        } else if libc::WIFCONTINUED(status) {
+            self.state = JobState::Running;
+        } else {
+            self.usage.add(rusage);
+            if let Some(index) = self.pids.iter().position(|&stage| stage == pid) {
+                self.statuses[index] = Some(exit_status(status));
+            }
+            self.finish(pid);
+        }
+    }
+
+    /// Forgets a process that has finished, and marks the job done once they all have
+    fn finish(&mut self, pid: u32) {
+        self.running.retain(|&running| running != pid);
+        if self.running.is_empty() {
+            self.state = JobState::Done(self.statuses.last().cloned().and_then(|status| status).unwrap_or(TERMINATED));
+        }
+    }
+
+    /// The exit status of each process of the pipeline, in order, once the job is done
+    pub fn statuses(&self) -> Vec<i32> {
+        self.statuses.iter().map(|status| status.unwrap_or(TERMINATED)).collect()
+    }
+
+    /// Collects the status of the processes that have finished, without waiting for the rest,
+    /// and returns the exit status of the job once it is done
+    pub fn poll(&mut self) -> Option<i32> {
+        self.collect(false, false);
+        match self.state {
+            JobState::Done(status) => Some(status),
+            _ => None,
+        }
+    }
+
+    /// Sends a signal to the processes of the job, and continues them if they are stopped, so
+    /// that they get it
+    pub fn signal(&self, signal: c_int) {
+        let mut signals = vec![signal];
+        if self.state == JobState::Stopped && signal != libc::SIGCONT && signal != libc::SIGSTOP && signal != libc::SIGKILL {
+            signals.push(libc::SIGCONT);
+        }
+        for signal in signals {
+            match self.pgid {
+                Some(pgid) => unsafe { libc::killpg(pgid as pid_t, signal); },
+                None => {
+                    for &pid in self.running.iter() {
+                        unsafe { libc::kill(pid as pid_t, signal) };
+                    }
+                },
+            }
+        }
+    }
+}
+
+pub struct JobTable {
+    jobs: Vec<BackgroundJob>,
+    /// The job numbers from the least to the most recently used, so that the last one is the
+    /// current job and the one before it is the previous job
+    recent: Vec<usize>,
+    /// The process group of the shell, if it has job control
+    shell_pgid: Option<pid_t>,
+    /// What the last pipeline that finished in the foreground used
+    last_usage: Usage,
+}
+
+impl JobTable {
+    pub fn new() -> Self {
+        JobTable {
+            jobs: vec![],
+            recent: vec![],
+            shell_pgid: None,
+            last_usage: Usage::new(),
+        }
+    }
+
+    /// Puts the shell in its own process group and takes control of the terminal, so that
+    /// pipelines can be run in process groups of their own. The shell ignores the signals that
+    /// would stop it, which the pipelines get back when they start. Returns whether job control
+    /// is enabled, which it is not if the input of the shell is not a terminal.
+    pub fn enable_job_control(&mut self) -> bool {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return false;
+            }
+            // A shell started in the background waits until it is brought into the foreground
+            loop {
+                let pgid = libc::getpgrp();
+                if libc::tcgetpgrp(libc::STDIN_FILENO) == pgid {
+                    break;
+                }
+                libc::kill(-pgid, libc::SIGTTIN);
+            }
+            for &signal in &[libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+            let pid = libc::getpid();
+            // This fails if the shell already leads its session, which is what it wants anyway
+            libc::setpgid(pid, pid);
+            let pgid = libc::getpgrp();
+            libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+            self.shell_pgid = Some(pgid);
+        }
+        true
+    }
+
+    /// Leaves the jobs of the shell behind in a child process that runs a subshell. With job
+    /// control, the child gets a process group of its own, and the terminal, so that it can be
+    /// stopped and continued like any other pipeline.
+    pub fn start_subshell(&mut self) {
+        self.jobs.clear();
+        self.recent.clear();
+        if self.shell_pgid.is_some() {
+            unsafe {
+                libc::setpgid(0, 0);
+                let pgid = libc::getpgrp();
+                libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+                self.shell_pgid = Some(pgid);
+            }
+        }
+    }
+
+    /// Leaves the jobs of the shell behind in a child process that runs builtins or functions in
+    /// the background. With job control, the child gets a process group of its own like other
+    /// background pipelines, but not the terminal, and the programs it runs stay in its group.
+    pub fn start_background(&mut self) {
+        self.jobs.clear();
+        self.recent.clear();
+        if self.shell_pgid.is_some() {
+            unsafe { libc::setpgid(0, 0) };
+        }
+        self.shell_pgid = None;
+    }
+
+    /// Forgets the jobs in a forked copy of the shell that runs a substitution. It stays in the
+    /// process group of the shell, and so do the pipelines it runs, so that they are not given the
+    /// terminal while the pipeline the substitution is part of has it.
+    pub fn start_substitution(&mut self) {
+        self.jobs.clear();
+        self.recent.clear();
+        self.shell_pgid = None;
+    }
+
+    /// Stops the shell itself with `SIGTSTP`, along with the rest of its process group, such as
+    /// when it was started from another shell, which can continue it with `fg`. The shell takes
+    /// the terminal back once it is continued.
+    pub fn suspend(&mut self) -> i32 {
+        unsafe {
+            let previous = libc::signal(libc::SIGTSTP, libc::SIG_DFL);
+            libc::kill(0, libc::SIGTSTP);
+            libc::signal(libc::SIGTSTP, previous);
+        }
+        self.reclaim_terminal();
+        SUCCESS
+    }
+
+    /// Whether pipelines are run in process groups of their own
+    pub fn job_control(&self) -> bool {
+        self.shell_pgid.is_some()
+    }
+
+    /// Adds the processes of a pipeline that was started in the background, returning its job
+    /// number
+    pub fn add(&mut self, command: String, pids: Vec<u32>) -> usize {
+        let job = self.new_job(command, pids);
+        self.insert(job)
+    }
+
+    /// Adds the processes of a pipeline that was started in a process group that other jobs share,
+    /// such as the programs that `parallel` runs at the same time, returning its job number
+    pub fn add_in_group(&mut self, command: String, pids: Vec<u32>, pgid: u32) -> usize {
+        let mut job = self.new_job(command, pids);
+        if job.pgid.is_some() {
+            job.pgid = Some(pgid);
+        }
+        self.insert(job)
+    }
+
+    /// Makes a job of the processes of a pipeline, without adding it to the table
+    pub fn new_job(&self, command: String, pids: Vec<u32>) -> BackgroundJob {
+        BackgroundJob {
+            id: 0,
+            pids: pids.clone(),
+            // The first process of the pipeline leads its process group
+            pgid: if self.job_control() { pids.first().cloned() } else { None },
+            command: command,
+            state: if pids.is_empty() { JobState::Done(TERMINATED) } else { JobState::Running },
+            nohup: false,
+            reported: true,
+            statuses: vec![None; pids.len()],
+            usage: Usage::new(),
+            running: pids,
+        }
+    }
+
+    /// Gives the job the lowest job number that is not in use, and makes it the current job
+    fn insert(&mut self, mut job: BackgroundJob) -> usize {
+        let mut id = 1;
+        while self.jobs.iter().any(|job| job.id == id) {
+            id += 1;
+        }
+        job.id = id;
+        self.jobs.push(job);
+        self.recent.push(id);
+        id
+    }
+
+    /// Gives the terminal to the process group, if the shell has job control
+    fn give_terminal(&self, pgid: Option<u32>) {
+        if let (Some(_), Some(pgid)) = (self.shell_pgid, pgid) {
+            unsafe { libc::tcsetpgrp(libc::STDIN_FILENO, pgid as pid_t) };
+        }
+    }
+
+    /// Takes the terminal back once the pipeline in the foreground has finished or stopped
+    fn reclaim_terminal(&self) {
+        if let Some(pgid) = self.shell_pgid {
+            unsafe { libc::tcsetpgrp(libc::STDIN_FILENO, pgid) };
+        }
+    }
+
+    /// Waits for a pipeline that was started in the foreground, returning its exit status. If it
+    /// is stopped, it is added to the table instead.
+    pub fn wait_for_foreground(&mut self, command: String, pids: Vec<u32>) -> i32 {
+        match self.wait_for_pipeline(command, pids) {
+            Some(statuses) => statuses.last().cloned().unwrap_or(TERMINATED),
+            None => STOPPED,
+        }
+    }
+
+    /// Waits for a pipeline that was started in the foreground like `wait_for_foreground`, but
+    /// returns the exit status of each of its processes, or None if it was stopped
+    pub fn wait_for_pipeline(&mut self, command: String, pids: Vec<u32>) -> Option<Vec<i32>> {
+        let mut job = self.new_job(command, pids);
+        self.give_terminal(job.pgid);
+        job.collect(true, false);
+        self.reclaim_terminal();
+        match job.state {
+            JobState::Done(_) => {
+                self.last_usage = job.usage;
+                Some(job.statuses())
+            },
+            _ => {
+                let id = self.insert(job);
+                self.report_stopped(id);
+                None
+            },
+        }
+    }
+
+    /// Waits until one of the jobs, which share a process group in the foreground, finishes or
+    /// stops, and returns its number. A job that stops is reported like a pipeline in the
+    /// foreground. What is collected for processes of other jobs is recorded in them, so that
+    /// they are reported once the prompt is back. Returns None if there are no child processes
+    /// left to wait for.
+    pub fn wait_any(&mut self, ids: &[usize]) -> Option<usize> {
+        let pgid = ids.first().and_then(|&id| self.get(id)).and_then(|job| job.pgid);
+        self.give_terminal(pgid);
+        let found = self.collect_any(ids);
+        self.reclaim_terminal();
+        if let Some(id) = found {
+            if self.get(id).map_or(false, |job| job.state == JobState::Stopped) {
+                self.touch(id);
+                self.report_stopped(id);
+            }
+        }
+        found
+    }
+
+    fn collect_any(&mut self, ids: &[usize]) -> Option<usize> {
+        loop {
+            let mut status: c_int = 0;
+            let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+            let pid = unsafe { libc::wait4(-1, &mut status, libc::WUNTRACED, &mut rusage) };
+            if pid < 0 {
+                // The jobs are in the foreground, so they get Ctrl-C too and are waited for until
+                // they finish
+                if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                return None;
+            }
+            // Processes that are not part of any job, such as those of substitutions, are only
+            // collected
+            if let Some(job) = self.jobs.iter_mut().find(|job| job.running.contains(&(pid as u32))) {
+                let previous = job.state;
+                job.record(pid as u32, status, &rusage);
+                if ids.contains(&job.id) {
+                    if job.state != JobState::Running {
+                        return Some(job.id);
+                    }
+                } else if job.state != previous && job.state != JobState::Running {
+                    job.reported = false;
+                }
+            }
+        }
+    }
+
+    /// What the processes of the last pipeline that finished in the foreground used
+    pub fn last_usage(&self) -> Usage {
+        self.last_usage
+    }
+
+    fn report_stopped(&self, id: usize) {
+        if let Some(job) = self.get(id) {
+            println!("\n[{}]+ {}\t{}", id, job.state, job.command);
+        }
+    }
+
+    /// The job that was most recently started, or used
+    pub fn current(&self) -> Option<usize> {
+        self.recent.last().cloned()
+    }
+
+    /// The job that was current before the current job
+    pub fn previous(&self) -> Option<usize> {
+        if self.recent.len() < 2 {
+            None
+        } else {
+            Some(self.recent[self.recent.len() - 2])
+        }
+    }
+
+    /// Makes the job the current job
+    pub fn touch(&mut self, id: usize) {
+        if self.get(id).is_some() {
+            self.recent.retain(|&recent| recent != id);
+            self.recent.push(id);
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<&BackgroundJob> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<BackgroundJob> {
+        self.recent.retain(|&recent| recent != id);
+        match self.jobs.iter().position(|job| job.id == id) {
+            Some(index) => Some(self.jobs.remove(index)),
+            None => None,
+        }
+    }
+
+    /// The jobs, in the order they were started
+    pub fn jobs(&self) -> &[BackgroundJob] {
+        &self.jobs
+    }
+
+    /// Collects the exit status of the processes that have finished, without waiting for the
+    /// rest, so that they do not linger as zombies
+    pub fn update(&mut self) {
+        for job in self.jobs.iter_mut() {
+            if let JobState::Done(_) = job.state {
+                continue;
+            }
+            job.collect(false, false);
+        }
+    }
+
+    /// Finds the job that the argument of a builtin refers to, or the current job if there is no
+    /// argument. A job is given by its number such as `%1` or `1`, as the current job with `%+`
+    /// or `%%`, as the previous job with `%-`, by the start of its code with `%name`, or by any
+    /// part of its code with `%?text`. The code must match only one job.
+    pub fn find(&self, spec: Option<&String>) -> Result<usize, String> {
+        let spec = match spec {
+            Some(spec) => spec,
+            None => return self.current().ok_or("no current job".to_string()),
+        };
+        let text = if spec.starts_with('%') { &spec[1..] } else { spec.as_str() };
+        let found = match text {
+            "" | "+" | "%" => self.current(),
+            "-" => self.previous(),
+            _ => {
+                if let Ok(id) = text.parse::<usize>() {
+                    self.get(id).map(|job| job.id)
+                } else if !spec.starts_with('%') {
+                    None
+                } else {
+                    let matches: Vec<usize> = if text.starts_with('?') {
+                        self.jobs.iter().filter(|job| job.command.contains(&text[1..])).map(|job| job.id).collect()
+                    } else {
+                        self.jobs.iter().filter(|job| job.command.starts_with(text)).map(|job| job.id).collect()
+                    };
+                    if matches.len() > 1 {
+                        return Err(format!("{}: ambiguous job spec", spec));
+                    }
+                    matches.first().cloned()
+                }
+            },
+        };
+        found.ok_or(format!("{}: no such job", spec))
+    }
+
+    /// Continues the processes of the job that have been stopped
+    pub fn resume(&mut self, id: usize) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+            match job.pgid {
+                Some(pgid) => unsafe { libc::killpg(pgid as pid_t, libc::SIGCONT); },
+                None => {
+                    for &pid in job.running.iter() {
+                        unsafe { libc::kill(pid as pid_t, libc::SIGCONT) };
+                    }
+                },
+            }
+            job.state = JobState::Running;
+        }
+    }
+
+    /// Waits for the job to finish, and removes it, returning its exit status. If the job is
+    /// stopped instead, it stays in the table and becomes the current job.
+    pub fn wait_for(&mut self, id: usize) -> i32 {
+        self.wait_job(id, true)
+    }
+
+    /// Waits for the job, giving it the terminal if it is in the foreground
+    fn wait_job(&mut self, id: usize, foreground: bool) -> i32 {
+        let pgid = match self.get(id) {
+            Some(job) => job.pgid,
+            None => return FAILURE,
+        };
+        if foreground {
+            self.give_terminal(pgid);
+        }
+        let state = match self.jobs.iter_mut().find(|job| job.id == id) {
+            Some(job) => {
+                job.collect(true, !foreground);
+                job.state
+            },
+            None => return FAILURE,
+        };
+        self.reclaim_terminal();
+        match state {
+            JobState::Done(status) => {
+                self.remove(id);
+                status
+            },
+            // Waiting was given up on for a signal, and the job goes on in the background
+            JobState::Running => 128 + signals::arrived().unwrap_or(libc::SIGINT),
+            _ => {
+                self.touch(id);
+                self.report_stopped(id);
+                STOPPED
+            },
+        }
+    }
+
+    /// Waits for the jobs or processes given, which are job numbers such as `%1` or process IDs,
+    /// or for every job if none are given. Returns the exit status of the last one, or of a
+    /// job that stopped instead.
+    pub fn wait(&mut self, args: &[String]) -> i32 {
+        let mut ids = vec![];
+        for arg in args.iter().skip(1) {
+            let id = if arg.starts_with('%') {
+                self.find(Some(arg)).ok()
+            } else {
+                match arg.parse::<u32>() {
+                    Ok(pid) => self.jobs.iter().find(|job| job.pids.contains(&pid)).map(|job| job.id),
+                    Err(_) => None,
+                }
+            };
+            match id {
+                Some(id) => ids.push(id),
+                None => {
+                    println!("ion: wait: {}: no such job", arg);
+                    return NO_SUCH_COMMAND;
+                },
+            }
+        }
+        let all = ids.is_empty();
+        if all {
+            ids = self.jobs.iter().map(|job| job.id).collect();
+        }
+        let mut status = SUCCESS;
+        for id in ids {
+            if let Some(signal) = signals::arrived() {
+                return 128 + signal;
+            }
+            let running = self.get(id).map_or(false, |job| job.state == JobState::Running);
+            if all && !running {
+                continue;
+            }
+            status = self.wait_job(id, false);
+            if status == STOPPED {
+                return status;
+            }
+        }
+        if all { SUCCESS } else { status }
+    }
+
+    /// Continues a job in the foreground, and waits for it
+    pub fn fg(&mut self, args: &[String]) -> i32 {
+        let id = match self.find(args.get(1)) {
+            Ok(id) => id,
+            Err(message) => {
+                println!("ion: fg: {}", message);
+                return FAILURE;
+            },
+        };
+        if let Some(job) = self.get(id) {
+            println!("{}", job.command);
+        }
+        self.resume(id);
+        self.wait_for(id)
+    }
+
+    /// Continues a stopped job in the background
+    pub fn bg(&mut self, args: &[String]) -> i32 {
+        let id = match self.find(args.get(1)) {
+            Ok(id) => id,
+            Err(message) => {
+                println!("ion: bg: {}", message);
+                return FAILURE;
+            },
+        };
+        self.resume(id);
+        self.touch(id);
+        if let Some(job) = self.get(id) {
+            if job.command.ends_with('&') {
+                println!("[{}]+ {}", id, job.command);
+            } else {
+                println!("[{}]+ {} &", id, job.command);
+            }
+        }
+        SUCCESS
+    }
+
+    /// Sends a signal to processes, or to every process of jobs given like `%1`. The signal is
+    /// `TERM` unless it is given as `-INT`, `-9`, `-s HUP` or `-n 1`, and stopped jobs are also
+    /// continued so that they get it. `kill -l` lists the names of the signals, and `kill -l 130`
+    /// names the signal that ended a process with that status.
+    pub fn kill(&mut self, args: &[String]) -> i32 {
+        let mut args = &args[1..];
+        let mut signal = libc::SIGTERM;
+        match args.first().map(|arg| arg.as_str()) {
+            Some("-l") | Some("-L") => {
+                if args.len() < 2 {
+                    println!("{}", signals::names().join(" "));
+                    return SUCCESS;
+                }
+                let mut status = SUCCESS;
+                for arg in &args[1..] {
+                    match arg.parse::<c_int>() {
+                        Ok(number) if signals::number(&(number & 127).to_string()).is_some() => {
+                            println!("{}", signals::name(number & 127))
+                        },
+                        _ => match signals::number(arg) {
+                            Some(number) => println!("{}", number),
+                            None => {
+                                println!("ion: kill: {}: invalid signal specification", arg);
+                                status = FAILURE;
+                            },
+                        },
+                    }
+                }
+                return status;
+            },
+            Some("-s") | Some("-n") => {
+                let name = match args.get(1) {
+                    Some(name) => name,
+                    None => {
+                        println!("ion: kill: {}: option requires an argument", args[0]);
+                        return FAILURE;
+                    },
+                };
+                match signals::number(name) {
+                    Some(number) => signal = number,
+                    None => {
+                        println!("ion: kill: {}: invalid signal specification", name);
+                        return FAILURE;
+                    },
+                }
+                args = &args[2..];
+            },
+            Some("--") => args = &args[1..],
+            Some(arg) if arg.starts_with('-') && arg.len() > 1 => {
+                match signals::number(&arg[1..]) {
+                    Some(number) => signal = number,
+                    None => {
+                        println!("ion: kill: {}: invalid signal specification", &arg[1..]);
+                        return FAILURE;
+                    },
+                }
+                args = &args[1..];
+            },
+            _ => (),
+        }
+        if args.is_empty() {
+            println!("ion: kill: usage: kill [-s signal | -signal] pid | %job...");
+            return FAILURE;
+        }
+        let mut status = SUCCESS;
+        for arg in args {
+            if arg.starts_with('%') {
+                match self.find(Some(arg)) {
+                    Ok(id) => self.signal(id, signal),
+                    Err(message) => {
+                        println!("ion: kill: {}", message);
+                        status = FAILURE;
+                    },
+                }
+                continue;
+            }
+            match arg.parse::<pid_t>() {
+                Ok(pid) => {
+                    if unsafe { libc::kill(pid, signal) } < 0 {
+                        println!("ion: kill: ({}) - {}", pid, io::Error::last_os_error());
+                        status = FAILURE;
+                    }
+                },
+                Err(_) => {
+                    println!("ion: kill: {}: arguments must be process or job IDs", arg);
+                    status = FAILURE;
+                },
+            }
+        }
+        status
+    }
+
+    /// Sends a signal to the processes of the job, and continues them if it was stopped
+    fn signal(&self, id: usize, signal: c_int) {
+        if let Some(job) = self.get(id) {
+            job.signal(signal);
+        }
+    }
+
+    /// Removes jobs from the table, so that the shell forgets them and does not hang them up when
+    /// it exits. With `-h` the jobs stay in the table and are only left running at exit. `-a`
+    /// applies to every job, and the current job is used if no job is given.
+    pub fn disown(&mut self, args: &[String]) -> i32 {
+        let mut nohup = false;
+        let mut all = false;
+        let mut specs = vec![];
+        for arg in args.iter().skip(1) {
+            match arg.as_str() {
+                "-h" => nohup = true,
+                "-a" => all = true,
+                _ => specs.push(arg),
+            }
+        }
+        let mut ids = vec![];
+        if all {
+            ids = self.jobs.iter().map(|job| job.id).collect();
+        } else if specs.is_empty() {
+            match self.find(None) {
+                Ok(id) => ids.push(id),
+                Err(message) => {
+                    println!("ion: disown: {}", message);
+                    return FAILURE;
+                },
+            }
+        }
+        let mut status = SUCCESS;
+        for spec in specs {
+            match self.find(Some(spec)) {
+                Ok(id) => ids.push(id),
+                Err(message) => {
+                    println!("ion: disown: {}", message);
+                    status = FAILURE;
+                },
+            }
+        }
+        for id in ids {
+            if nohup {
+                if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+                    job.nohup = true;
+                }
+            } else {
+                self.remove(id);
+            }
+        }
+        status
+    }
+
+    /// Sends `SIGHUP` to the jobs that are still running when an interactive shell exits, as the
+    /// terminal would if it were closed. Stopped jobs are continued so that they get the signal.
+    pub fn hang_up(&mut self) {
+        self.update();
+        let ids: Vec<usize> = self.jobs
+                                  .iter()
+                                  .filter(|job| !job.nohup && match job.state { JobState::Done(_) => false, _ => true })
+                                  .map(|job| job.id)
+                                  .collect();
+        for id in ids {
+            self.signal(id, libc::SIGHUP);
+        }
+    }
+
+    /// Lists the jobs with their state, process IDs and code. The current job is marked with a `+`
+    /// and the previous job with a `-`. Jobs that are done are listed one last time, and then
+    /// removed from the table.
+    pub fn list(&mut self, args: &[String]) -> i32 {
+        let pids_only = args.get(1).map_or(false, |arg| arg == "-p");
+        self.update();
+        for job in self.jobs.iter() {
+            let pids: Vec<String> = job.pids.iter().map(|pid| pid.to_string()).collect();
+            if pids_only {
+                println!("{}", pids.join("\n"));
+                continue;
+            }
+            println!("[{}]{} {:<8} {:<10} {}", job.id, self.mark(job.id), pids.join(","), job.state.to_string(), job.command);
+        }
+        for job in self.jobs.iter_mut() {
+            job.reported = true;
+        }
+        self.remove_done();
+        SUCCESS
+    }
+
+    /// Tells the user about the jobs that finished or stopped in the background since the last
+    /// time, such as `[1]+ Done       sleep 10`, which the shell does before it shows the prompt.
+    /// The jobs that finished are removed.
+    pub fn report_changes(&mut self) {
+        self.update();
+        for job in self.jobs.iter().filter(|job| !job.reported) {
+            println!("[{}]{} {:<10} {}", job.id, self.mark(job.id), job.state.to_string(), job.command);
+        }
+        for job in self.jobs.iter_mut() {
+            job.reported = true;
+        }
+        self.remove_done();
+    }
+
+    /// The mark `jobs` shows for the job, which is `+` for the current job, `-` for the previous
+    /// one and a space for the others
+    fn mark(&self, id: usize) -> char {
+        if Some(id) == self.current() {
+            '+'
+        } else if Some(id) == self.previous() {
+            '-'
+        } else {
+            ' '
+        }
+    }
+
+    fn remove_done(&mut self) {
+        let done: Vec<usize> = self.jobs
+                                   .iter()
+                                   .filter(|job| match job.state { JobState::Done(_) => true, _ => false })
+                                   .map(|job| job.id)
+                                   .collect();
+        for id in done {
+            self.remove(id);
+        }
+    }
+}
+
+/// The exit status of a process from the status `waitpid` reported for it. Processes that were
+/// ended by a signal have the status that shells give them, which is 128 plus the signal.
+pub fn exit_status(status: c_int) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        TERMINATED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn number_jobs_with_the_lowest_unused_number() {
+        let mut jobs = JobTable::new();
+        assert_eq!(1, jobs.add("a &".to_string(), vec![]));
+        assert_eq!(2, jobs.add("b &".to_string(), vec![]));
+        assert_eq!("a &", jobs.remove(1).unwrap().command);
+        assert_eq!(1, jobs.add("c &".to_string(), vec![]));
+        assert_eq!(3, jobs.add("d &".to_string(), vec![]));
+        let ids: Vec<usize> = jobs.jobs().iter().map(|job| job.id).collect();
+        assert_eq!(vec![2, 1, 3], ids);
+    }
+
+    #[test]
+    fn current_and_previous_jobs() {
+        let mut jobs = JobTable::new();
+        assert_eq!(None, jobs.current());
+        jobs.add("a &".to_string(), vec![]);
+        assert_eq!((Some(1), None), (jobs.current(), jobs.previous()));
+        jobs.add("b &".to_string(), vec![]);
+        jobs.add("c &".to_string(), vec![]);
+        assert_eq!((Some(3), Some(2)), (jobs.current(), jobs.previous()));
+        jobs.touch(1);
+        assert_eq!((Some(1), Some(3)), (jobs.current(), jobs.previous()));
+        jobs.remove(1);
+        assert_eq!((Some(3), Some(2)), (jobs.current(), jobs.previous()));
+        jobs.list(&["jobs".to_string()]);
+        assert!(jobs.jobs().is_empty());
+    }
+
+    #[test]
+    fn collect_finished_jobs() {
+        let mut jobs = JobTable::new();
+        let first = Command::new("true").spawn().unwrap().id();
+        let last = Command::new("sh").args(&["-c", "exit 3"]).spawn().unwrap().id();
+        let id = jobs.add("true | sh -c 'exit 3' &".to_string(), vec![first, last]);
+        let sleep = Command::new("sleep").arg("10").spawn().unwrap();
+        let running = jobs.add("sleep 10 &".to_string(), vec![sleep.id()]);
+        for _ in 0..100 {
+            jobs.update();
+            if jobs.get(id).unwrap().state != JobState::Running {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(JobState::Done(3), jobs.get(id).unwrap().state);
+        assert_eq!(JobState::Running, jobs.get(running).unwrap().state);
+        unsafe { libc::kill(sleep.id() as pid_t, libc::SIGKILL) };
+    }
+
+    #[test]
+    fn find_jobs() {
+        let mut jobs = JobTable::new();
+        assert_eq!(Err("no current job".to_string()), jobs.find(None));
+        jobs.add("a &".to_string(), vec![]);
+        jobs.add("b &".to_string(), vec![]);
+        assert_eq!(Ok(2), jobs.find(None));
+        assert_eq!(Ok(1), jobs.find(Some(&"%1".to_string())));
+        assert_eq!(Ok(1), jobs.find(Some(&"1".to_string())));
+        assert_eq!(Err("%3: no such job".to_string()), jobs.find(Some(&"%3".to_string())));
+        jobs.add("cargo build &".to_string(), vec![]);
+        let find = |spec: &str| jobs.find(Some(&spec.to_string()));
+        assert_eq!(Ok(3), find("%+"));
+        assert_eq!(Ok(3), find("%%"));
+        assert_eq!(Ok(2), find("%-"));
+        assert_eq!(Ok(3), find("%cargo"));
+        assert_eq!(Ok(3), find("%?build"));
+        assert_eq!(Ok(1), find("%a"));
+        assert_eq!(Err("%? &: ambiguous job spec".to_string()), find("%? &"));
+        assert_eq!(Err("%make: no such job".to_string()), find("%make"));
+        assert_eq!(Err("cargo: no such job".to_string()), find("cargo"));
+    }
+
+    #[test]
+    fn add_stopped_foreground_jobs() {
+        let mut jobs = JobTable::new();
+        let child = Command::new("sh").args(&["-c", "exit 2"]).spawn().unwrap();
+        assert_eq!(2, jobs.wait_for_foreground("sh".to_string(), vec![child.id()]));
+        assert!(jobs.jobs().is_empty());
+        let child = Command::new("sh").args(&["-c", "kill -STOP $$"]).spawn().unwrap();
+        assert_eq!(::status::STOPPED, jobs.wait_for_foreground("sh".to_string(), vec![child.id()]));
+        assert_eq!(Some(1), jobs.current());
+        assert_eq!(JobState::Stopped, jobs.get(1).unwrap().state);
+        unsafe { libc::kill(child.id() as pid_t, libc::SIGKILL) };
+        assert_eq!(128 + libc::SIGKILL, jobs.wait_for(1));
+    }
+
+    #[test]
+    fn wait_for_jobs() {
+        let mut jobs = JobTable::new();
+        let first = Command::new("sh").args(&["-c", "exit 5"]).spawn().unwrap().id();
+        let second = Command::new("sh").args(&["-c", "exit 6"]).spawn().unwrap().id();
+        jobs.add("sh &".to_string(), vec![first]);
+        jobs.add("sh &".to_string(), vec![second]);
+        assert_eq!(6, jobs.wait(&["wait".to_string(), second.to_string()]));
+        assert_eq!(5, jobs.wait(&["wait".to_string(), "%1".to_string()]));
+        assert!(jobs.jobs().is_empty());
+        let child = Command::new("true").spawn().unwrap().id();
+        jobs.add("true &".to_string(), vec![child]);
+        assert_eq!(::status::SUCCESS, jobs.wait(&["wait".to_string()]));
+        assert!(jobs.jobs().is_empty());
+        assert_eq!(::status::NO_SUCH_COMMAND, jobs.wait(&["wait".to_string(), "%1".to_string()]));
+    }
+
+    #[test]
+    fn signals_end_waiting_for_jobs() {
+        // A trapped signal is used rather than Ctrl-C, which would stop the commands that other
+        // tests are running. It is sent to this thread until it arrives, since it might otherwise
+        // arrive before the wait starts.
+        let mut traps = ::signals::Traps::new();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        traps.trap(&args(&["trap", "true", "ALRM"]));
+        let mut jobs = JobTable::new();
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let id = jobs.add("sleep 10 &".to_string(), vec![child.id()]);
+        let waiting = Arc::new(AtomicBool::new(true));
+        let thread = unsafe { libc::pthread_self() };
+        let sender = {
+            let waiting = waiting.clone();
+            thread::spawn(move || while waiting.load(Ordering::SeqCst) {
+                unsafe { libc::pthread_kill(thread, libc::SIGALRM) };
+                thread::sleep(Duration::from_millis(50));
+            })
+        };
+        let status = jobs.wait(&args(&["wait", "%1"]));
+        waiting.store(false, Ordering::SeqCst);
+        sender.join().unwrap();
+        assert!(status > 128);
+        assert_eq!(JobState::Running, jobs.get(id).unwrap().state);
+        ::signals::take_pending();
+        traps.trap(&args(&["trap", "-", "ALRM"]));
+        child.kill().unwrap();
+        assert_eq!(128 + libc::SIGKILL, jobs.wait_for(id));
+    }
+
+    #[test]
+    fn disown_jobs() {
+        let mut jobs = JobTable::new();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        let mut kept = Command::new("sleep").arg("10").spawn().unwrap();
+        let mut hung_up = Command::new("sleep").arg("10").spawn().unwrap();
+        let first = jobs.add("sleep 10 &".to_string(), vec![kept.id()]);
+        let second = jobs.add("sleep 10 &".to_string(), vec![hung_up.id()]);
+        let third = jobs.add("true &".to_string(), vec![]);
+        assert_eq!(::status::SUCCESS, jobs.disown(&args(&["disown"])));
+        assert!(jobs.get(third).is_none());
+        assert_eq!(::status::FAILURE, jobs.disown(&args(&["disown", "%3"])));
+        assert_eq!(::status::SUCCESS, jobs.disown(&args(&["disown", "-h", "%1"])));
+        assert!(jobs.get(first).unwrap().nohup);
+        assert!(!jobs.get(second).unwrap().nohup);
+        jobs.hang_up();
+        assert_eq!(128 + libc::SIGHUP, jobs.wait_for(second));
+        assert_eq!(JobState::Running, jobs.get(first).unwrap().state);
+        kept.kill().unwrap();
+        assert_eq!(128 + libc::SIGKILL, jobs.wait_for(first));
+        let _ = hung_up.wait();
+        let _ = kept.wait();
+    }
+
+    #[test]
+    fn kill_jobs_and_processes() {
+        let mut jobs = JobTable::new();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        let mut first = Command::new("sleep").arg("10").spawn().unwrap();
+        let mut second = Command::new("sleep").arg("10").spawn().unwrap();
+        let id = jobs.add("sleep 10 &".to_string(), vec![first.id()]);
+        assert_eq!(::status::SUCCESS, jobs.kill(&args(&["kill", "-INT", "%1"])));
+        assert_eq!(128 + libc::SIGINT, jobs.wait_for(id));
+        assert_eq!(::status::SUCCESS, jobs.kill(&args(&["kill", "-s", "USR1", &second.id().to_string()])));
+        assert_eq!(Some(libc::SIGUSR1), ::std::os::unix::process::ExitStatusExt::signal(&second.wait().unwrap()));
+        assert_eq!(::status::FAILURE, jobs.kill(&args(&["kill", "-NOTASIGNAL", "1"])));
+        assert_eq!(::status::FAILURE, jobs.kill(&args(&["kill", "%1"])));
+        assert_eq!(::status::FAILURE, jobs.kill(&args(&["kill"])));
+        assert_eq!(::status::SUCCESS, jobs.kill(&args(&["kill", "-l", "130", "TERM"])));
+        let _ = first.wait();
+    }
+
+    #[test]
+    fn report_finished_jobs_once() {
+        let mut jobs = JobTable::new();
+        let child = Command::new("true").spawn().unwrap().id();
+        let finished = jobs.add("true &".to_string(), vec![child]);
+        let mut sleep = Command::new("sleep").arg("10").spawn().unwrap();
+        let running = jobs.add("sleep 10 &".to_string(), vec![sleep.id()]);
+        while jobs.get(finished).map_or(false, |job| job.reported) {
+            jobs.update();
+            thread::sleep(Duration::from_millis(10));
+        }
+        jobs.report_changes();
+        assert!(jobs.get(finished).is_none());
+        assert!(jobs.get(running).unwrap().reported);
+        sleep.kill().unwrap();
+        let _ = sleep.wait();
+    }
+
+    #[test]
+    fn stop_and_resume_jobs() {
+        let mut jobs = JobTable::new();
+        let child = Command::new("sh").args(&["-c", "kill -STOP $$; exit 4"]).spawn().unwrap();
+        let id = jobs.add("sh &".to_string(), vec![child.id()]);
+        assert_eq!(::status::STOPPED, jobs.wait_for(id));
+        assert_eq!(JobState::Stopped, jobs.get(id).unwrap().state);
+        jobs.resume(id);
+        assert_eq!(4, jobs.wait_for(id));
+        assert!(jobs.get(id).is_none());
+    }
+}
@@ -4,58 +4,115 @@
 #![plugin(peg_syntax_ext)]
 
 extern crate glob;
+extern crate libc;
 extern crate regex;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
 
+use std::cmp;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{stdout, Read, Write};
+use std::io::{self, stdout, stderr, Read, Write};
 use std::env;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::process;
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc::pid_t;
 
 use self::directory_stack::DirectoryStack;
 use self::input_editor::readln;
-use self::peg::{parse, Pipeline};
+use self::peg::{parse_statements, parse_statements_recovering, Pipeline, Job, Condition, Redirection, Target, Statement, Test, Value, Word};
 use self::variables::Variables;
 use self::history::History;
-use self::flow_control::{FlowControl, is_flow_control_command, Statement};
-use self::status::{SUCCESS, NO_SUCH_COMMAND};
+use self::flow_control::{compare, errexit_applies};
+use self::status::{SUCCESS, FAILURE, NO_SUCH_COMMAND, STOPPED};
 use self::function::Function;
-use self::pipe::execute_pipeline;
+use self::execute::{execute_pipeline, execute_background, execute_in_group, pipeline_status, redirect_input, redirect_shell, CommandCache};
+use self::jobs::{exit_status, JobState, JobTable};
+use self::signals::Traps;
+use self::substitution::{substitute_pipeline, Substitution, DEFAULT_IFS};
+use self::usage::format_duration;
 
-pub mod pipe;
+pub mod execute;
+pub mod expansion;
+pub mod arithmetic;
+pub mod builtin_args;
 pub mod directory_stack;
+pub mod environment;
 pub mod to_num;
 pub mod input_editor;
 pub mod peg;
+pub mod signals;
+pub mod syntax_tree;
+pub mod tokenizer;
 pub mod variables;
 pub mod history;
+pub mod jobs;
 pub mod flow_control;
 pub mod status;
+pub mod substitution;
+pub mod umask;
+pub mod usage;
 pub mod function;
 
 /// This struct will contain all of the data structures related to this
 /// instance of the shell.
 pub struct Shell {
     variables: Variables,
-    flow_control: FlowControl,
     directory_stack: DirectoryStack,
     history: History,
-    functions: HashMap<String, Function>
+    functions: HashMap<String, Function>,
+    command_cache: CommandCache,
+    jobs: JobTable,
+    /// Whether the builtin that is running wants its redirections to stay in place once it
+    /// finishes, which is how `exec > log` redirects the shell itself
+    keep_redirections: bool,
+    traps: Traps,
+    /// Whether the user is typing the commands at a terminal, rather than the shell running a
+    /// script, in which case it shows prompts and controls jobs
+    interactive: bool,
+    /// How many `if` and `while` tests are running, whose failures do not end the shell with
+    /// `set -e`
+    tests: usize,
+    /// The descriptors that the shell writes to and reads from each coprocess, by the name given
+    /// to `coproc`
+    coprocs: HashMap<String, (RawFd, RawFd)>,
 }
 
 impl Shell {
+    /// Creates a shell for a user at a terminal if it is interactive, which shows prompts and
+    /// controls jobs, or else for running a script, which ends at the first syntax error. The
+    /// mode can be read from `$INTERACTIVE_ENABLED`.
+    ///
     /// Panics if DirectoryStack construction fails
-    pub fn new() -> Self {
+    pub fn new(interactive: bool) -> Self {
+        let mut new_shell = Shell::with_defaults(interactive);
+        new_shell.evaluate_init_file();
+        new_shell
+    }
+
+    /// Creates a shell with its default variables, without running the init file
+    fn with_defaults(interactive: bool) -> Self {
         let mut new_shell = Shell {
             variables: Variables::new(),
-            flow_control: FlowControl::new(),
             directory_stack: DirectoryStack::new().expect(""),
             history: History::new(),
-            functions: HashMap::new()
+            functions: HashMap::new(),
+            command_cache: CommandCache::new(),
+            jobs: JobTable::new(),
+            keep_redirections: false,
+            traps: Traps::new(),
+            interactive: interactive,
+            tests: 0,
+            coprocs: HashMap::new(),
         };
+        new_shell.variables.inherit_environment();
         new_shell.initialize_default_variables();
-        new_shell.evaluate_init_file();
-        return new_shell;
+        new_shell
     }
 
     /// This function will initialize the default variables used by the shell. This function will
@@ -65,7 +122,14 @@ impl Shell {
         self.variables.set_var("HISTORY_SIZE", "1000");
         self.variables.set_var("HISTORY_FILE_ENABLED", "1");
         self.variables.set_var("HISTORY_FILE_SIZE", "1000");
+        self.variables.set_var("RECURSIVE_GLOB_ENABLED", "1");
+        self.variables.set_var("PIPEFAIL_ENABLED", "0");
+        self.variables.set_var("NOCLOBBER_ENABLED", "0");
+        self.variables.set_var("RUSAGE_ENABLED", "0");
+        self.variables.set_var("ERREXIT_ENABLED", "0");
         self.variables.set_var("PROMPT", "ion:$PWD# ");
+        self.variables.set_var("INTERACTIVE_ENABLED", if self.interactive { "1" } else { "0" });
+        self.variables.set_var("?", &SUCCESS.to_string());
 
         {   // Initialize the HISTORY_FILE variable
             let mut history_path = std::env::home_dir().unwrap();
@@ -74,16 +138,18 @@ impl Shell {
         }
 
         {   // Initialize the PWD (Present Working Directory) variable
-            match std::env::current_dir() {
+            match directory_stack::logical_dir(&self.variables) {
                 Ok(path) => self.variables.set_var("PWD", path.to_str().unwrap_or("?")),
                 Err(_)   => self.variables.set_var("PWD", "?")
             }
         }
 
         {   // Initialize the HOME variable
-            match std::env::home_dir() {
-                Some(path) => self.variables.set_var("HOME", path.to_str().unwrap_or("?")),
-                None       => self.variables.set_var("HOME", "?")
+            if self.variables.get_var("HOME").is_none() {
+                match std::env::home_dir() {
+                    Some(path) => self.variables.set_var("HOME", path.to_str().unwrap_or("?")),
+                    None       => self.variables.set_var("HOME", "?")
+                }
             }
         }
     }
@@ -93,7 +159,7 @@ impl Shell {
     /// the current working directory.
     fn update_variables(&mut self) {
         {   // Update the PWD (Present Working Directory) variable
-            match std::env::current_dir() {
+            match directory_stack::logical_dir(&self.variables) {
                 Ok(path) => self.variables.set_var("PWD", path.to_str().unwrap()),
                 Err(_)   => self.variables.set_var("PWD", "?")
             }
@@ -112,7 +178,7 @@ impl Shell {
             if let Err(message) = file.read_to_string(&mut command_list) {
                 println!("{}: Failed to read {:?}", message, source_file.clone());
             } else {
-                self.on_command(&command_list, commands);
+                self.run_code(&command_list, commands);
             }
         } else {
             if let Err(message) = File::create(source_file) {
@@ -122,125 +188,882 @@ impl Shell {
     }
 
     pub fn print_prompt(&self) {
-        self.print_prompt_prefix();
-        match self.flow_control.current_statement {
-            Statement::For(_, _) => self.print_for_prompt(),
-            Statement::Function(_, _) => self.print_function_prompt(),
-            _ => self.print_default_prompt(),
+        if !self.interactive {
+            return;
         }
+        self.print_default_prompt();
         if let Err(message) = stdout().flush() {
             println!("{}: failed to flush prompt to stdout", message);
         }
 
     }
 
-    // TODO eventually this thing should be gone
-    fn print_prompt_prefix(&self) {
-        let prompt_prefix = self.flow_control.modes.iter().rev().fold(String::new(), |acc, mode| {
-            acc +
-            if mode.value {
-                "+ "
-            } else {
-                "- "
-            }
-        });
-        print!("{}", prompt_prefix);
+    fn print_continuation_prompt(&self) {
+        if !self.interactive {
+            return;
+        }
+        print!("> ");
+        if let Err(message) = stdout().flush() {
+            println!("{}: failed to flush prompt to stdout", message);
+        }
     }
 
-    fn print_for_prompt(&self) {
-        print!("for> ");
+    fn print_default_prompt(&self) {
+        print!("{}", self.variables.expand_string(&self.variables.expand_string("$PROMPT")));
     }
 
-    fn print_function_prompt(&self) {
-        print!("fn> ");
+    fn on_command(&mut self, command_string: &str, commands: &Builtins) {
+        self.history.add(command_string.to_string(), &self.variables);
+        let start = Instant::now();
+        self.run_script_code(command_string, commands);
+        self.notify_long_command(command_string, start.elapsed());
     }
 
-    fn print_default_prompt(&self) {
-        print!("{}", self.variables.expand_string(&self.variables.expand_string("$PROMPT")));
+    /// Lets the user know that a command which took at least `$NOTIFY_SECONDS` has finished, so
+    /// that they can do something else while it runs. The terminal bell is rung, unless
+    /// `NOTIFY_OSC_ENABLED` is `1`, in which case the terminal is asked to show a notification
+    /// with the command and its exit status.
+    fn notify_long_command(&self, command: &str, elapsed: Duration) {
+        let seconds = match self.variables.expand_string("$NOTIFY_SECONDS").parse::<u64>() {
+            Ok(seconds) if seconds > 0 => seconds,
+            _ => return,
+        };
+        if elapsed.as_secs() < seconds {
+            return;
+        }
+        let message = if self.variables.expand_string("$NOTIFY_OSC_ENABLED") == "1" {
+            format!("\x1b]9;{} finished with status {}\x07", command, self.history.previous_status)
+        } else {
+            "\x07".to_string()
+        };
+        let mut stderr = stderr();
+        let _ = stderr.write_all(message.as_bytes());
+        let _ = stderr.flush();
     }
 
-    fn on_command(&mut self, command_string: &str, commands: &HashMap<&str, Command>) {
-        self.history.add(command_string.to_string(), &self.variables);
+    /// Parses and runs the code in the shell, without adding it to the history. Returns false if
+    /// the code does not parse, after reporting why and setting `$?` to FAILURE, so that `eval`
+    /// and traps carry on after a syntax error.
+    fn run_code(&mut self, code: &str, commands: &Builtins) -> bool {
+        let statements = match parse_statements(code) {
+            Ok(statements) => statements,
+            Err(_) => {
+                for err in parse_statements_recovering(code).1 {
+                    println!("ion: {}", err);
+                }
+                self.set_status(FAILURE);
+                return false;
+            }
+        };
+        self.run_block(&statements, commands);
+        true
+    }
 
-        let mut pipelines = parse(command_string);
-
-        // Execute commands
-        for pipeline in pipelines.drain(..) {
-            if self.flow_control.collecting_block {
-                // TODO move this logic into "end" command
-                if pipeline.jobs[0].command == "end" {
-                    self.flow_control.collecting_block = false;
-                    let block_jobs: Vec<Pipeline> = self.flow_control
-                                                   .current_block
-                                                   .pipelines
-                                                   .drain(..)
-                                                   .collect();
-                    match self.flow_control.current_statement.clone() {
-                        Statement::For(ref var, ref vals) => {
-                            let variable = var.clone();
-                            let values = vals.clone();
-                            for value in values {
-                                self.variables.set_var(&variable, &value);
-                                for pipeline in block_jobs.iter() {
-                                    self.run_pipeline(&pipeline, commands);
-                                }
-                            }
-                        },
-                        Statement::Function(ref name, ref args) => {
-                            self.functions.insert(name.clone(), Function { name: name.clone(), pipelines: block_jobs.clone(), args: args.clone() });
-                        },
-                        _ => {}
-                    }
-                    self.flow_control.current_statement = Statement::Default;
+    /// Runs the code of the script the shell is running, or a line typed at the terminal. A
+    /// script ends at the first syntax error, while an interactive shell keeps going.
+    fn run_script_code(&mut self, code: &str, commands: &Builtins) {
+        if !self.run_code(code, commands) && !self.interactive {
+            self.exit(FAILURE);
+        }
+    }
+
+    /// Runs the statement, returning the exit status of the last pipeline it ran
+    fn run_statement(&mut self, statement: &Statement, commands: &Builtins) -> Option<i32> {
+        match *statement {
+            Statement::Pipeline(ref pipeline) => self.run_pipeline(pipeline, commands),
+            Statement::Subshell { ref body, condition } => {
+                if self.should_run(condition) {
+                    self.run_subshell(body, commands)
                 } else {
-                    self.flow_control.current_block.pipelines.push(pipeline);
+                    None
                 }
-            } else {
-                if self.flow_control.skipping() && !is_flow_control_command(&pipeline.jobs[0].command) {
-                    continue;
+            },
+            Statement::Group { ref body, ref redirections, condition } => {
+                if !self.should_run(condition) {
+                    return None;
                 }
-                self.run_pipeline(&pipeline, commands);
+                self.update_noclobber();
+                if let Some(_streams) = redirect_shell(&self.variables.expand_redirections(redirections)) {
+                    self.run_block(body, commands)
+                } else {
+                    self.set_status(FAILURE);
+                    Some(FAILURE)
+                }
+            },
+            Statement::If { ref test, ref success, ref failure } => {
+                if self.run_test(test, commands) {
+                    self.run_block(success, commands)
+                } else {
+                    self.run_block(failure, commands)
+                }
+            },
+            Statement::While { ref test, ref body } => {
+                let mut exit_status = None;
+                while !signals::interrupted() && self.run_test(test, commands) {
+                    exit_status = self.run_block(body, commands);
+                }
+                exit_status
+            },
+            Statement::For { ref variable, ref values, ref body } => {
+                let values: Vec<String> = self.substitute_arguments(values, commands)
+                                              .iter()
+                                              .flat_map(|value| self.variables.expand_arguments(value))
+                                              .collect();
+                let mut exit_status = None;
+                for value in values {
+                    if signals::interrupted() {
+                        break;
+                    }
+                    self.variables.set_var(variable, &value);
+                    exit_status = self.run_block(body, commands);
+                }
+                exit_status
+            },
+            Statement::Assignment { ref name, ref value } => {
+                let words = match *value {
+                    Value::Word(ref word) => vec![word],
+                    Value::Array(ref words) => words.iter().collect(),
+                };
+                for word in words {
+                    if let Err(message) = self.variables.assign_word_parameters(word) {
+                        println!("ion: {}", message);
+                        self.set_status(FAILURE);
+                        return Some(FAILURE);
+                    }
+                }
+                match *value {
+                    Value::Word(ref word) => {
+                        let word = self.substitute_word(word, commands);
+                        let value = self.variables.expand_word(&word);
+                        self.variables.set_var(name, &value);
+                    },
+                    Value::Array(ref words) => {
+                        let values = self.substitute_arguments(words, commands)
+                                         .iter()
+                                         .flat_map(|word| self.variables.expand_arguments(word))
+                                         .collect();
+                        self.variables.set_array(name, values);
+                    },
+                }
+                self.set_status(SUCCESS);
+                Some(SUCCESS)
+            },
+            Statement::Function { ref name, ref args, ref body } => {
+                self.functions.insert(name.clone(), Function { name: name.clone(), statements: body.clone(), args: args.clone() });
+                None
+            },
+        }
+    }
+
+    /// Runs the statements in a copy of the shell in a child process, so that nothing they do
+    /// changes the shell, including changing directories, `exit` and `exec`
+    fn run_subshell(&mut self, statements: &[Statement], commands: &Builtins) -> Option<i32> {
+        let _ = stdout().flush();
+        let _ = stderr().flush();
+        let status = match unsafe { libc::fork() } {
+            -1 => {
+                println!("ion: failed to start a subshell: {}", io::Error::last_os_error());
+                FAILURE
+            },
+            0 => {
+                self.jobs.start_subshell();
+                let status = self.run_block(statements, commands).unwrap_or(self.history.previous_status);
+                let _ = stdout().flush();
+                let _ = stderr().flush();
+                process::exit(status);
+            },
+            pid => {
+                if self.jobs.job_control() {
+                    // The group is also set here, so that it exists before the shell gives it
+                    // the terminal
+                    unsafe { libc::setpgid(pid, pid) };
+                }
+                self.jobs.wait_for_foreground("( ... )".to_string(), vec![pid as u32])
+            },
+        };
+        self.set_status(status);
+        Some(status)
+    }
+
+    /// Runs a substitution, returning the text that replaces it. The pipes of process
+    /// substitutions are added to the list, and are kept open until the command they are part of
+    /// finishes.
+    fn run_substitution(&mut self, substitution: Substitution, pipes: &mut Vec<(File, pid_t)>, commands: &Builtins) -> String {
+        let (pipelines, fd) = match substitution {
+            Substitution::Command(pipelines) | Substitution::Input(pipelines) => (pipelines, libc::STDOUT_FILENO),
+            Substitution::Output(pipelines) => (pipelines, libc::STDIN_FILENO),
+        };
+        let (mut file, pid) = match self.fork_substitution(pipelines, fd, commands) {
+            Ok(substitution) => substitution,
+            Err(err) => {
+                println!("ion: failed to start a substitution: {}", err);
+                return String::new();
+            },
+        };
+        match substitution {
+            Substitution::Command(_) => {
+                let mut output = vec![];
+                if let Err(err) = file.read_to_end(&mut output) {
+                    println!("ion: failed to read the output of a substitution: {}", err);
+                }
+                close_substitutions(vec![(file, pid)]);
+                String::from_utf8_lossy(&output).into_owned()
+            },
+            _ => {
+                let path = format!("/dev/fd/{}", file.as_raw_fd());
+                pipes.push((file, pid));
+                path
+            },
+        }
+    }
+
+    /// The characters that the output of command substitutions is split on
+    fn ifs(&self) -> String {
+        self.variables.get_var("IFS").cloned().unwrap_or(DEFAULT_IFS.to_string())
+    }
+
+    /// Replaces the substitutions in a word that is not split into arguments, such as the value
+    /// of an assignment or one side of a comparison
+    fn substitute_word(&mut self, word: &Word, commands: &Builtins) -> Word {
+        let mut pipes = vec![];
+        let word = substitution::substitute_word(word, &mut |substitution| {
+            self.run_substitution(substitution, &mut pipes, commands)
+        });
+        close_substitutions(pipes);
+        word
+    }
+
+    /// Replaces the substitutions in words that are expanded into arguments, such as the values of
+    /// a `for` loop
+    fn substitute_arguments(&mut self, words: &[Word], commands: &Builtins) -> Vec<Word> {
+        let mut pipes = vec![];
+        let ifs = self.ifs();
+        let words = words.iter()
+                         .flat_map(|word| {
+                             substitution::substitute_arguments(word, &ifs, &mut |substitution| {
+                                 self.run_substitution(substitution, &mut pipes, commands)
+                             })
+                         })
+                         .collect();
+        close_substitutions(pipes);
+        words
+    }
+
+    /// Runs the pipelines of a substitution in a forked copy of the shell, with one of its
+    /// descriptors connected to a pipe. Returns the other end of the pipe, and the process ID of
+    /// the copy.
+    fn fork_substitution(&mut self, pipelines: &[Pipeline], fd: RawFd, commands: &Builtins) -> io::Result<(File, pid_t)> {
+        let (reader, writer) = try!(execute::pipe());
+        let (ours, theirs) = if fd == libc::STDOUT_FILENO { (reader, writer) } else { (writer, reader) };
+        let _ = stdout().flush();
+        let _ = stderr().flush();
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                self.jobs.start_substitution();
+                drop(ours);
+                unsafe { libc::dup2(theirs.as_raw_fd(), fd) };
+                drop(theirs);
+                for pipeline in pipelines {
+                    self.run_pipeline(pipeline, commands);
+                }
+                let _ = stdout().flush();
+                let _ = stderr().flush();
+                process::exit(self.history.previous_status);
+            },
+            pid => Ok((ours, pid)),
+        }
+    }
+
+    /// Records the exit status of the last pipeline, which `&&`, `||` and `$?` use
+    fn set_status(&mut self, code: i32) {
+        self.variables.set_var("?", &code.to_string());
+        self.history.previous_status = code;
+    }
+
+    /// Ends the shell, with the exit status as the status of the process. Output that has not been
+    /// written yet is flushed first.
+    fn exit(&mut self, status: i32) -> ! {
+        if let Some(code) = self.traps.take_exit() {
+            self.run_code(&code, &Command::map());
+        }
+        if self.interactive {
+            self.jobs.hang_up();
+        }
+        let _ = stdout().flush();
+        let _ = stderr().flush();
+        process::exit(status);
+    }
+
+    /// Runs the traps of the signals that have arrived since the last time. The exit status of the
+    /// commands that were interrupted is kept.
+    fn run_traps(&mut self, commands: &Builtins) {
+        for signal in signals::take_pending() {
+            if let Some(code) = self.traps.get(signal).cloned() {
+                let status = self.history.previous_status;
+                self.run_code(&code, commands);
+                self.set_status(status);
             }
         }
     }
 
-    fn run_pipeline(&mut self, pipeline: &Pipeline, commands: &HashMap<&str, Command>) -> Option<i32> {
-        let mut pipeline = self.variables.expand_pipeline(pipeline);
-        pipeline.expand_globs();
-        let exit_status = if let Some(command) = commands.get(pipeline.jobs[0].command.as_str()) {
-            Some((*command.main)(pipeline.jobs[0].args.as_slice(), self))
+    /// Whether a pipeline with the condition should run after the previous one
+    fn should_run(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::OnSuccess => self.history.previous_status == SUCCESS,
+            Condition::OnFailure => self.history.previous_status != SUCCESS,
+            Condition::Always => true,
+        }
+    }
+
+    fn run_block(&mut self, statements: &[Statement], commands: &Builtins) -> Option<i32> {
+        let mut exit_status = None;
+        for (index, statement) in statements.iter().enumerate() {
+            if signals::interrupted() {
+                break;
+            }
+            exit_status = self.run_statement(statement, commands);
+            if errexit_applies(statement, statements.get(index + 1), exit_status, self.tests > 0) &&
+               self.variables.expand_string("$ERREXIT_ENABLED") == "1" {
+                self.exit(exit_status.unwrap_or(FAILURE));
+            }
+        }
+        exit_status
+    }
+
+    /// Returns whether the test of an `if` or `while` passes. Comparisons are evaluated by the
+    /// shell, and pipelines pass when they succeed.
+    fn run_test(&mut self, test: &Test, commands: &Builtins) -> bool {
+        match *test {
+            Test::Comparison(ref left, ref comparison, ref right) => {
+                let left = self.substitute_word(left, commands);
+                let right = self.substitute_word(right, commands);
+                let left = self.variables.expand_word(&left);
+                let right = self.variables.expand_word(&right);
+                match compare(&left, comparison, &right) {
+                    Ok(value) => value,
+                    Err(message) => {
+                        println!("ion: {}", message);
+                        false
+                    }
+                }
+            },
+            Test::Pipeline(ref pipeline) => {
+                self.tests += 1;
+                let passed = self.run_pipeline(pipeline, commands) == Some(SUCCESS);
+                self.tests -= 1;
+                passed
+            },
+        }
+    }
+
+    fn run_pipeline(&mut self, pipeline: &Pipeline, commands: &Builtins) -> Option<i32> {
+        if !self.should_run(pipeline.condition) {
+            return None;
+        }
+        self.update_noclobber();
+        if let Err(message) = self.variables.assign_parameters(pipeline) {
+            println!("ion: {}", message);
+            self.set_status(FAILURE);
+            return Some(FAILURE);
+        }
+        let text = pipeline.to_string();
+        // Substitutions are run first, and the pipeline gets the paths of the pipes of process
+        // substitutions
+        let mut substitutions: Vec<(File, pid_t)> = vec![];
+        let ifs = self.ifs();
+        let pipeline = substitute_pipeline(pipeline, &ifs, &mut |substitution| {
+            self.run_substitution(substitution, &mut substitutions, commands)
+        });
+        for &(ref file, _) in substitutions.iter() {
+            // Only the pipeline is given the pipes, so that the substitutions do not hold each
+            // other's open
+            unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFD, 0) };
+        }
+        let pipeline = self.variables.expand_pipeline(&pipeline);
+        let background = pipeline.is_background();
+        let negated = pipeline.negated;
+        let timed = pipeline.timed;
+        let start = Instant::now();
+        // The status of each job, and what the jobs used, when the pipeline is run by programs
+        // rather than the shell
+        let mut statuses = None;
+        let mut usage = None;
+        // Builtins and functions run in the shell, so its own streams are redirected for them
+        let exit_status = if pipeline.jobs.len() == 1 && pipeline.jobs[0].args == [""] {
+            // Substitutions that leave no command, such as `$(true)`, are only run for what they do
+            Some(SUCCESS)
+        } else if background && pipeline.jobs.iter().any(|job| self.runs_in_shell(job, commands)) {
+            match self.fork_background(&pipeline, commands) {
+                Ok(pid) => {
+                    self.variables.set_var("!", &pid.to_string());
+                    let id = self.jobs.add(text, vec![pid as u32]);
+                    let _ = writeln!(stderr(), "[{}] {}", id, pid);
+                    Some(SUCCESS)
+                },
+                Err(err) => {
+                    println!("ion: failed to run {}: {}", text, err);
+                    Some(FAILURE)
+                },
+            }
+        } else if pipeline.jobs.len() > 1 && pipeline.jobs.iter().any(|job| self.runs_in_shell(job, commands)) {
+            let (status, stages) = self.run_shell_pipeline(&pipeline, commands);
+            statuses = Some(stages);
+            Some(status)
+        } else if let Some(command) = commands.get(pipeline.jobs[0].command.as_str()) {
+            match redirect_shell(&pipeline.jobs[0].redirections) {
+                Some(streams) => {
+                    let status = command.run(pipeline.jobs[0].args.as_slice(), self);
+                    if self.keep_redirections {
+                        self.keep_redirections = false;
+                        streams.keep();
+                    }
+                    Some(status)
+                },
+                None => Some(FAILURE),
+            }
         } else if let Some(function) = self.functions.get(pipeline.jobs[0].command.as_str()).cloned() {
-            if pipeline.jobs[0].args.len() - 1 != function.args.len() {
-                println!("This function takes {} arguments, but you provided {}", function.args.len(), pipeline.jobs[0].args.len()-1);
-                Some(NO_SUCH_COMMAND) // not sure if this is the right error code
+            let streams = redirect_shell(&pipeline.jobs[0].redirections);
+            if streams.is_none() {
+                Some(FAILURE)
             } else {
-                let mut variables_backup: HashMap<&str, Option<String>> = HashMap::new();
-                for (name, value) in function.args.iter().zip(pipeline.jobs[0].args.iter().skip(1)) {
-                    variables_backup.insert(name, self.variables.get_var(name).cloned());
-                    self.variables.set_var(name, value);
+                self.run_function(&function, &pipeline.jobs[0].args, commands)
+            }
+        } else if pipeline.jobs.len() == 1 && self.functions.contains_key("command_not_found") &&
+                  self.command_cache.find(&pipeline.jobs[0].command, self.variables.environment().get("PATH").map(|path| path.as_str())).is_none() {
+            // The user's handler decides what to do with commands that are not found instead
+            let function = self.functions["command_not_found"].clone();
+            let args = vec![function.name.clone(), pipeline.jobs[0].command.clone()];
+            self.run_function(&function, &args, commands)
+        } else if background {
+            let pids = execute_background(&pipeline, self.variables.environment(), &mut self.command_cache, self.jobs.job_control());
+            if let Some(pid) = pids.last().cloned() {
+                self.variables.set_var("!", &pid.to_string());
+                let id = self.jobs.add(text, pids);
+                let _ = writeln!(stderr(), "[{}] {}", id, pid);
+                Some(SUCCESS)
+            } else {
+                Some(NO_SUCH_COMMAND)
+            }
+        } else {
+            let pipefail = self.variables.expand_string("$PIPEFAIL_ENABLED") == "1";
+            let (status, stages) = execute_pipeline(&pipeline, text, self.variables.environment(), &mut self.command_cache, &mut self.jobs, pipefail);
+            statuses = Some(stages);
+            usage = Some(self.jobs.last_usage());
+            Some(status)
+        };
+        if timed {
+            let _ = writeln!(stderr(), "real\t{}", format_duration(start.elapsed()));
+            if let Some(usage) = usage {
+                let _ = writeln!(stderr(), "user\t{}", format_duration(usage.user));
+                let _ = writeln!(stderr(), "sys\t{}", format_duration(usage.system));
+            }
+        }
+        if let Some(usage) = usage {
+            if self.variables.expand_string("$RUSAGE_ENABLED") == "1" {
+                let _ = writeln!(stderr(),
+                                 "{} user, {} sys, {} KiB max rss",
+                                 format_duration(usage.user),
+                                 format_duration(usage.system),
+                                 usage.max_rss);
+            }
+        }
+        if let Some(code) = exit_status {
+            let statuses = statuses.unwrap_or(vec![code]);
+            self.variables.set_array("PIPESTATUS", statuses.iter().map(|status| status.to_string()).collect());
+        }
+        let exit_status = if negated {
+            exit_status.map(|code| if code == SUCCESS { FAILURE } else { SUCCESS })
+        } else {
+            exit_status
+        };
+        if background {
+            // The job only holds the stages of the pipeline, so the substitutions are waited for
+            // on their own
+            thread::spawn(move || close_substitutions(substitutions));
+        } else {
+            close_substitutions(substitutions);
+        }
+        if let Some(code) = exit_status {
+            self.set_status(code);
+        }
+        self.run_traps(commands);
+        exit_status
+    }
+
+    /// Lets the redirections that are opened next know whether the `noclobber` option is on
+    fn update_noclobber(&self) {
+        execute::set_noclobber(self.variables.expand_string("$NOCLOBBER_ENABLED") == "1");
+    }
+
+    /// Whether the job is run by the shell itself, as a builtin or function
+    fn runs_in_shell(&self, job: &Job, commands: &Builtins) -> bool {
+        commands.contains_key(job.command.as_str()) || self.functions.contains_key(&job.command)
+    }
+
+    /// Runs a pipeline that has builtins or functions in it, returning its exit status and the
+    /// status of each job. Each job but the last runs in a forked copy of the shell, with pipes
+    /// between them. A builtin or function at the end runs in the shell itself, so that
+    /// `ls | read first` sets the variable. The copies stay in the process group of the shell,
+    /// like substitutions, since the shell keeps the terminal while it reads the pipe.
+    fn run_shell_pipeline(&mut self, pipeline: &Pipeline, commands: &Builtins) -> (i32, Vec<i32>) {
+        let end = pipeline.jobs.len() - 1;
+        let mut stages: Vec<Result<pid_t, i32>> = vec![];
+        let mut input: Option<File> = None;
+        for (index, job) in pipeline.jobs.iter().enumerate() {
+            if index == end && self.runs_in_shell(job, commands) {
+                break;
+            }
+            match self.fork_job(job, input.take(), index < end, commands) {
+                Ok((pid, output)) => {
+                    stages.push(Ok(pid));
+                    input = output;
+                },
+                Err(err) => {
+                    println!("ion: failed to run {}: {}", job.command, err);
+                    stages.push(Err(FAILURE));
+                },
+            }
+        }
+        let last = if stages.len() == end {
+            let _stdin = input.map(redirect_input);
+            Some(self.run_job(&pipeline.jobs[end], commands))
+        } else {
+            None
+        };
+        let mut statuses: Vec<i32> = stages.into_iter()
+                                           .map(|stage| stage.map(wait_for_process).unwrap_or_else(|status| status))
+                                           .collect();
+        statuses.extend(last);
+        let pipefail = self.variables.expand_string("$PIPEFAIL_ENABLED") == "1";
+        (pipeline_status(&statuses, pipefail), statuses)
+    }
+
+    /// Runs a pipeline with builtins or functions in it in the background, in a forked copy of
+    /// the shell that the job table keeps track of like any other background job. Returns the
+    /// process ID of the copy.
+    fn fork_background(&mut self, pipeline: &Pipeline, commands: &Builtins) -> io::Result<pid_t> {
+        let _ = stdout().flush();
+        let _ = stderr().flush();
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                self.jobs.start_background();
+                let status = if pipeline.jobs.len() > 1 {
+                    self.run_shell_pipeline(pipeline, commands).0
+                } else {
+                    self.run_job(&pipeline.jobs[0], commands)
+                };
+                let _ = stdout().flush();
+                let _ = stderr().flush();
+                process::exit(status);
+            },
+            pid => {
+                if self.jobs.job_control() {
+                    // The group is also set here, so that it exists before it is signalled
+                    unsafe { libc::setpgid(pid, pid) };
                 }
-                let mut return_value = None;
-                for function_pipeline in function.pipelines.iter() {
-                    return_value = self.run_pipeline(function_pipeline, commands)
+                Ok(pid)
+            },
+        }
+    }
+
+    /// Runs the job in a forked copy of the shell, with its input read from the file if there is
+    /// one. Returns the process ID of the copy and, if the output is piped, the end of the pipe
+    /// that it can be read from.
+    fn fork_job(&mut self, job: &Job, input: Option<File>, piped: bool, commands: &Builtins) -> io::Result<(pid_t, Option<File>)> {
+        let (reader, writer) = if piped {
+            let (reader, writer) = try!(execute::pipe());
+            (Some(reader), Some(writer))
+        } else {
+            (None, None)
+        };
+        let _ = stdout().flush();
+        let _ = stderr().flush();
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                self.jobs.start_substitution();
+                // The copy must not hold the other end of its own output, or it would not see
+                // that the next job stopped reading it
+                drop(reader);
+                if let Some(input) = input {
+                    unsafe { libc::dup2(input.as_raw_fd(), libc::STDIN_FILENO) };
+                }
+                if let Some(writer) = writer {
+                    unsafe { libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO) };
+                }
+                let status = self.run_job(job, commands);
+                let _ = stdout().flush();
+                let _ = stderr().flush();
+                process::exit(status);
+            },
+            pid => Ok((pid, reader)),
+        }
+    }
+
+    /// Runs a single job of a pipeline, which is a builtin, a function or a program
+    fn run_job(&mut self, job: &Job, commands: &Builtins) -> i32 {
+        if let Some(command) = commands.get(job.command.as_str()) {
+            match redirect_shell(&job.redirections) {
+                Some(_streams) => command.run(job.args.as_slice(), self),
+                None => FAILURE,
+            }
+        } else if let Some(function) = self.functions.get(job.command.as_str()).cloned() {
+            match redirect_shell(&job.redirections) {
+                Some(_streams) => self.run_function(&function, &job.args, commands).unwrap_or(SUCCESS),
+                None => FAILURE,
+            }
+        } else {
+            let pipeline = Pipeline::new(vec![job.clone()], Condition::Always);
+            execute_pipeline(&pipeline, job.args.join(" "), self.variables.environment(), &mut self.command_cache, &mut self.jobs, false).0
+        }
+    }
+
+    /// Runs the code that the arguments make up again and again, clearing the screen before
+    /// each run, until Ctrl-C is pressed. `watch -n 0.5 ls` waits half a second between runs
+    /// instead of two seconds, and `watch 'ls | wc -l'` watches a pipeline.
+    fn watch(&mut self, args: &[String], commands: &Builtins) -> i32 {
+        let (seconds, command) = match builtin_args::watch(args) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                println!("ion: watch: {}", message);
+                return FAILURE;
+            },
+        };
+        let statements = match parse_command("watch", command) {
+            Some(statements) => statements,
+            None => return FAILURE,
+        };
+        let interval = Duration::from_millis((seconds * 1000.0) as u64);
+        let mut status = SUCCESS;
+        while !signals::interrupted() {
+            print!("\x1b[H\x1b[2J");
+            println!("Every {}s: {}\n", seconds, command.join(" "));
+            self.run_block(&statements, commands);
+            status = self.history.previous_status;
+            let _ = stdout().flush();
+            let start = Instant::now();
+            // The pause is short enough that Ctrl-C is noticed right away
+            while start.elapsed() < interval && !signals::interrupted() {
+                thread::sleep(cmp::min(interval, Duration::from_millis(50)));
+            }
+        }
+        status
+    }
+
+    /// Runs the code that the arguments make up the given number of times, as
+    /// `repeat 5 cargo test` does, returning the status of the last run. With `-e` it stops at
+    /// the first run that fails, and it always stops when Ctrl-C is pressed. Like with `watch`,
+    /// `repeat 5 'cargo test | tail -1'` repeats a pipeline.
+    fn repeat(&mut self, args: &[String], commands: &Builtins) -> i32 {
+        let (stop_on_failure, count, command) = match builtin_args::repeat(args) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                println!("ion: repeat: {}", message);
+                return FAILURE;
+            },
+        };
+        let statements = match parse_command("repeat", command) {
+            Some(statements) => statements,
+            None => return FAILURE,
+        };
+        let mut status = SUCCESS;
+        for _ in 0..count {
+            if signals::interrupted() {
+                break;
+            }
+            self.run_block(&statements, commands);
+            status = self.history.previous_status;
+            if stop_on_failure && status != SUCCESS {
+                break;
+            }
+        }
+        status
+    }
+
+    /// Runs a program once for each of the inputs after `--`, with `{}` in its arguments replaced
+    /// by the input, or the input added to the end if there is no `{}`. As many run at once as
+    /// there are processors, or as `-j` gives. Returns the exit status of the first one that
+    /// failed, if any did.
+    fn parallel(&mut self, args: &[String]) -> i32 {
+        let processors = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        let processors = if processors > 0 { processors as usize } else { 1 };
+        let (limit, template, inputs) = match builtin_args::parallel(args, processors) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                println!("ion: parallel: {}", message);
+                return FAILURE;
+            },
+        };
+        let placeholder = template.iter().any(|arg| arg.contains("{}"));
+        let mut inputs = inputs.iter();
+        // The jobs that are running share a process group, which has the terminal, so Ctrl-C and
+        // Ctrl-Z reach all of them
+        let mut running: Vec<usize> = vec![];
+        let mut pgid: pid_t = 0;
+        let mut status = SUCCESS;
+        loop {
+            while running.len() < limit && !signals::interrupted() {
+                let input = match inputs.next() {
+                    Some(input) => input,
+                    None => break,
+                };
+                let mut command: Vec<String> = template.iter().map(|arg| arg.replace("{}", input)).collect();
+                if !placeholder {
+                    command.push(input.clone());
                 }
-                for (name, value_option) in variables_backup.iter() {
-                    match *value_option {
-                        Some(ref value) => self.variables.set_var(name, value),
-                        None => {
-                            self.variables.unset_var(name);
-                        }
+                let pipeline = Pipeline::new(vec![job_from_args(&command)], Condition::Always);
+                let pids = execute_in_group(&pipeline, self.variables.environment(), &mut self.command_cache, self.jobs.job_control(), pgid);
+                if pids.is_empty() {
+                    if status == SUCCESS {
+                        status = NO_SUCH_COMMAND;
                     }
+                    continue;
+                }
+                if pgid == 0 {
+                    pgid = pids[0] as pid_t;
                 }
-                return_value
+                running.push(self.jobs.add_in_group(command.join(" "), pids, pgid as u32));
+            }
+            if running.is_empty() {
+                break;
+            }
+            let id = match self.jobs.wait_any(&running) {
+                Some(id) => id,
+                None => {
+                    for &id in running.iter() {
+                        self.jobs.remove(id);
+                    }
+                    break;
+                },
+            };
+            match self.jobs.get(id).map(|job| job.state) {
+                Some(JobState::Done(code)) => {
+                    self.jobs.remove(id);
+                    running.retain(|&running| running != id);
+                    if code == 128 + libc::SIGINT {
+                        // Ctrl-C went to the programs rather than the shell
+                        signals::interrupt();
+                    }
+                    if code != SUCCESS && status == SUCCESS {
+                        status = code;
+                    }
+                },
+                // Stopped with Ctrl-Z, so the jobs are left in the table to be continued, and
+                // the inputs that are left are not run
+                _ => return STOPPED,
+            }
+            // The group is gone once all of its processes have been collected
+            if running.is_empty() {
+                pgid = 0;
+            }
+        }
+        status
+    }
+
+    /// Starts a program in the background as a coprocess, with its input and output connected to
+    /// pipes that the shell keeps open. `coproc bc` sets `$COPROC_INPUT` and `$COPROC_OUTPUT` to
+    /// the descriptors that its input is written to and its output is read from, so that
+    /// `echo 1+1 > /dev/fd/$COPROC_INPUT` and `read line < /dev/fd/$COPROC_OUTPUT` talk to it, and
+    /// `$COPROC_PID` to its process ID. `-n name` uses `name` instead of `COPROC`.
+    fn coproc(&mut self, args: &[String]) -> i32 {
+        let (name, command) = if args.get(1).map_or(false, |arg| arg == "-n") {
+            match args.get(2) {
+                Some(name) if Variables::is_valid_variable_name(name) => (name.as_str(), &args[3..]),
+                _ => {
+                    println!("ion: coproc: -n: expected a variable name");
+                    return FAILURE;
+                },
             }
         } else {
-            Some(execute_pipeline(pipeline))
+            ("COPROC", &args[1..])
         };
-        if let Some(code) = exit_status {
-            self.variables.set_var("?", &code.to_string());
-            self.history.previous_status = code;
+        if command.is_empty() {
+            println!("ion: coproc: expected a command");
+            return FAILURE;
         }
-        exit_status
+        let pipes = execute::pipe().and_then(|input| execute::pipe().map(|output| (input, output)));
+        let ((input_reader, input_writer), (output_reader, output_writer)) = match pipes {
+            Ok(pipes) => pipes,
+            Err(err) => {
+                println!("ion: coproc: failed to create a pipe: {}", err);
+                return FAILURE;
+            },
+        };
+        let redirections = vec![Redirection::new(0, Target::Duplicate(input_reader.as_raw_fd())),
+                                Redirection::new(1, Target::Duplicate(output_writer.as_raw_fd()))];
+        let job = Job::new(command.iter().map(|arg| Word::literal(arg)).collect(), redirections, true);
+        let pipeline = Pipeline::new(vec![job], Condition::Always);
+        let pids = execute_background(&pipeline, self.variables.environment(), &mut self.command_cache, self.jobs.job_control());
+        // The coprocess has its own ends of the pipes now, and it sees the end of its input once
+        // the shell and the programs it runs have closed theirs
+        drop(input_reader);
+        drop(output_writer);
+        let pid = match pids.last().cloned() {
+            Some(pid) => pid,
+            None => return NO_SUCH_COMMAND,
+        };
+        // The copies are kept above the descriptors that redirections usually use. They are not
+        // passed on to programs, which would keep the coprocess from seeing the end of its input,
+        // but the shell opens their `/dev/fd` paths for the programs' redirections.
+        let input = unsafe { libc::fcntl(input_writer.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 10) };
+        let output = unsafe { libc::fcntl(output_reader.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 10) };
+        // A coprocess of the same name that is replaced sees the end of its input
+        if let Some((previous_input, previous_output)) = self.coprocs.insert(name.to_string(), (input, output)) {
+            unsafe {
+                libc::close(previous_input);
+                libc::close(previous_output);
+            }
+        }
+        self.variables.set_var(&format!("{}_INPUT", name), &input.to_string());
+        self.variables.set_var(&format!("{}_OUTPUT", name), &output.to_string());
+        self.variables.set_var(&format!("{}_PID", name), &pid.to_string());
+        let id = self.jobs.add(command.join(" "), pids);
+        let _ = writeln!(stderr(), "[{}] {}", id, pid);
+        SUCCESS
+    }
+
+    /// Runs the function with the given arguments, the first of which is the name it was called
+    /// by. The variables of its parameters are restored afterwards.
+    fn run_function(&mut self, function: &Function, args: &[String], commands: &Builtins) -> Option<i32> {
+        if args.len() - 1 != function.args.len() {
+            println!("This function takes {} arguments, but you provided {}", function.args.len(), args.len()-1);
+            return Some(NO_SUCH_COMMAND); // not sure if this is the right error code
+        }
+        let mut variables_backup: HashMap<&str, Option<String>> = HashMap::new();
+        for (name, value) in function.args.iter().zip(args.iter().skip(1)) {
+            variables_backup.insert(name, self.variables.get_var(name).cloned());
+            self.variables.set_var(name, value);
+        }
+        let return_value = self.run_block(&function.statements, commands);
+        for (name, value_option) in variables_backup.iter() {
+            match *value_option {
+                Some(ref value) => self.variables.set_var(name, value),
+                None => {
+                    self.variables.unset_var(name);
+                }
+            }
+        }
+        return_value
+    }
+
+    /// Runs a script with the arguments as its positional parameters. A `#!` line at the start
+    /// is skipped.
+    fn run_script(&mut self, path: &str, args: &[String], commands: &Builtins) {
+        let mut code = String::new();
+        if let Err(err) = File::open(path).and_then(|mut file| file.read_to_string(&mut code)) {
+            println!("ion: failed to read {}: {}", path, err);
+            self.set_status(if err.kind() == io::ErrorKind::NotFound { NO_SUCH_COMMAND } else { FAILURE });
+            return;
+        }
+        if code.starts_with("#!") {
+            // The line break is kept, so that the lines are numbered as in the file
+            let end = code.find('\n').unwrap_or(code.len());
+            code = code[end..].to_string();
+        }
+        self.variables.set_arguments(path, args);
+        self.run_script_code(&code, commands);
     }
 
     /// Evaluates the given file and returns 'SUCCESS' if it succeeds.
@@ -289,22 +1112,60 @@ pub struct Command {
     pub main: Box<Fn(&[String], &mut Shell) -> i32>,
 }
 
+/// A command that the shell runs itself, which is found before any program on the `PATH` with
+/// the same name. Builtins use the standard streams of the shell, and can change its state.
+pub trait Builtin {
+    fn name(&self) -> &'static str;
+    fn help(&self) -> &'static str;
+    /// Runs the builtin with the arguments of the job, the first of which is its name, returning
+    /// its exit status
+    fn run(&self, args: &[String], shell: &mut Shell) -> i32;
+}
+
+/// The builtins of the shell, by their names
+pub type Builtins = HashMap<&'static str, Box<Builtin>>;
+
+impl Builtin for Command {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn run(&self, args: &[String], shell: &mut Shell) -> i32 {
+        (*self.main)(args, shell)
+    }
+}
+
 impl Command {
     /// Return the map from command names to commands
-    pub fn map() -> HashMap<&'static str, Self> {
-        let mut commands: HashMap<&str, Self> = HashMap::new();
+    pub fn map() -> Builtins {
+        let mut commands: Builtins = HashMap::new();
 
         commands.insert("cd",
-                        Command {
+                        box Command {
                             name: "cd",
-                            help: "Change the current directory\n    cd <path>",
+                            help: "Change the current directory, looking for relative paths in $CDPATH, or with - \
+                                   to the previous directory and with no path to $HOME\n    cd [path | -]",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.directory_stack.cd(args, &shell.variables)
+                                shell.directory_stack.cd(args, &mut shell.variables)
+                            },
+                        });
+
+        commands.insert("pwd",
+                        box Command {
+                            name: "pwd",
+                            help: "Print the current directory, or with -P with its symbolic links \
+                                   resolved\n    pwd [-L | -P]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                directory_stack::pwd(args, &shell.variables)
                             },
                         });
 
         commands.insert("dirs",
-                        Command {
+                        box Command {
                             name: "dirs",
                             help: "Display the current directory stack",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
@@ -313,21 +1174,139 @@ impl Command {
                         });
 
         commands.insert("exit",
-                        Command {
+                        box Command {
                             name: "exit",
-                            help: "To exit the curent session",
+                            help: "Exit the current session, with the given status or the status of \
+                                   the last command\n    exit [status]",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                if let Some(status) = args.get(1) {
-                                    if let Ok(status) = status.parse::<i32>() {
-                                        process::exit(status);
-                                    }
+                                let status = match args.get(1) {
+                                    Some(status) => {
+                                        match status.parse::<i32>() {
+                                            Ok(status) => status,
+                                            Err(_) => {
+                                                println!("ion: exit: {}: numeric argument required", status);
+                                                return FAILURE;
+                                            }
+                                        }
+                                    },
+                                    None => shell.history.previous_status,
+                                };
+                                shell.exit(status);
+                            },
+                        });
+
+        commands.insert("exec",
+                        box Command {
+                            name: "exec",
+                            help: "Replace the shell with a command, or apply the redirections to \
+                                   the shell itself if there is none\n    exec [command \
+                                   [arguments...]] [redirections...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                if args.len() < 2 {
+                                    shell.keep_redirections = true;
+                                    return SUCCESS;
+                                }
+                                let status = execute::exec(&args[1..],
+                                                           shell.variables.environment(),
+                                                           &mut shell.command_cache);
+                                // The signals for job control were reset for the program
+                                if shell.jobs.job_control() {
+                                    shell.jobs.enable_job_control();
                                 }
-                                process::exit(shell.history.previous_status);
+                                status
+                            },
+                        });
+
+        commands.insert("export",
+                        box Command {
+                            name: "export",
+                            help: "Export variables to the programs that are run, or list the \
+                                   exported variables\n    export [name[=value]...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.variables.export(args)
+                            },
+                        });
+
+        commands.insert("set",
+                        box Command {
+                            name: "set",
+                            help: "Turn shell options on with -o or off with +o, or list them\n    set \
+                                   [-o|+o name...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.variables.set_options(args)
+                            },
+                        });
+
+        commands.insert("fg",
+                        box Command {
+                            name: "fg",
+                            help: "Continue a job in the foreground, which is the current job if none \
+                                   is given\n    fg [%job]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.jobs.fg(args)
+                            },
+                        });
+
+        commands.insert("bg",
+                        box Command {
+                            name: "bg",
+                            help: "Continue a stopped job in the background, which is the current \
+                                   job if none is given\n    bg [%job]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.jobs.bg(args)
+                            },
+                        });
+
+        commands.insert("kill",
+                        box Command {
+                            name: "kill",
+                            help: "Send a signal, which is TERM unless one is given, to processes or \
+                                   jobs, or list the signals with -l\n    kill [-s signal | -signal] \
+                                   pid | %job...\n    kill -l [status]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.jobs.kill(args)
+                            },
+                        });
+
+        commands.insert("disown",
+                        box Command {
+                            name: "disown",
+                            help: "Remove jobs from the table, or with -h keep them running when the \
+                                   shell exits, which is the current job if none is given\n    \
+                                   disown [-h] [-a] [%job...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.jobs.disown(args)
+                            },
+                        });
+
+        commands.insert("suspend",
+                        box Command {
+                            name: "suspend",
+                            help: "Stop the shell until it is continued, which a login shell only does \
+                                   with -f\n    suspend [-f]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                // Login shells are started with a name that begins with a dash
+                                let login = env::args().next().map_or(false, |name| name.starts_with('-'));
+                                if login && args.get(1).map_or(true, |arg| arg != "-f") {
+                                    println!("ion: suspend: cannot suspend a login shell");
+                                    return FAILURE;
+                                }
+                                shell.jobs.suspend()
+                            },
+                        });
+
+        commands.insert("jobs",
+                        box Command {
+                            name: "jobs",
+                            help: "List the jobs running in the background, or only their process \
+                                   IDs with -p\n    jobs [-p]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.jobs.list(args)
                             },
                         });
 
         commands.insert("let",
-                        Command {
+                        box Command {
                             name: "let",
                             help: "View, set or unset variables",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
@@ -336,7 +1315,7 @@ impl Command {
                         });
 
         commands.insert("read",
-                        Command {
+                        box Command {
                             name: "read",
                             help: "Read some variables\n    read <variable>",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
@@ -345,25 +1324,25 @@ impl Command {
                         });
 
         commands.insert("pushd",
-                        Command {
+                        box Command {
                             name: "pushd",
                             help: "Push a directory to the stack",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.directory_stack.pushd(args, &shell.variables)
+                                shell.directory_stack.pushd(args, &mut shell.variables)
                             },
                         });
 
         commands.insert("popd",
-                        Command {
+                        box Command {
                             name: "popd",
                             help: "Pop a directory from the stack",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.directory_stack.popd(args)
+                                shell.directory_stack.popd(args, &mut shell.variables)
                             },
                         });
 
         commands.insert("history",
-                        Command {
+                        box Command {
                             name: "history",
                             help: "Display a log of all commands previously executed",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
@@ -371,54 +1350,154 @@ impl Command {
                             },
                         });
 
-        commands.insert("if",
-                        Command {
-                            name: "if",
-                            help: "Conditionally execute code",
+        commands.insert("source",
+                        box Command {
+                            name: "source",
+                            help: "Evaluate the file following the command or re-initialize the init file",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.flow_control.if_(args)
+                                shell.source_command(args)
+
                             },
                         });
 
-        commands.insert("else",
-                        Command {
-                            name: "else",
-                            help: "Execute code if a previous condition was false",
+        commands.insert("eval",
+                        box Command {
+                            name: "eval",
+                            help: "Join the arguments with spaces and run them as code in the \
+                                   current shell\n    eval [arguments...]",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.flow_control.else_(args)
+                                let code = args[1..].join(" ");
+                                if code.trim().is_empty() {
+                                    return SUCCESS;
+                                }
+                                shell.run_code(&code, &Command::map());
+                                shell.history.previous_status
                             },
                         });
 
-        commands.insert("end",
-                        Command {
-                            name: "end",
-                            help: "End a code block",
+        commands.insert("trap",
+                        box Command {
+                            name: "trap",
+                            help: "Run code when the shell receives signals, or when it exits with \
+                                   EXIT\n    trap [code|''|-] [signals...]",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.flow_control.end(args)
+                                shell.traps.trap(args)
                             },
                         });
 
-        commands.insert("for",
-                        Command {
-                            name: "for",
-                            help: "Iterate through a list",
+        commands.insert("times",
+                        box Command {
+                            name: "times",
+                            help: "Print the user and system time used by the shell, and by the \
+                                   programs it has run",
+                            main: box |_: &[String], _: &mut Shell| -> i32 {
+                                usage::times()
+                            },
+                        });
+
+        commands.insert("umask",
+                        box Command {
+                            name: "umask",
+                            help: "Show the permissions that new files are created without, or set them in \
+                                   octal or like chmod\n    umask [-S] [mode]",
+                            main: box |args: &[String], _: &mut Shell| -> i32 {
+                                umask::umask(args)
+                            },
+                        });
+
+        commands.insert("coproc",
+                        box Command {
+                            name: "coproc",
+                            help: "Start a program in the background with its input and output \
+                                   connected to the shell, through the descriptors in \
+                                   $COPROC_INPUT and $COPROC_OUTPUT\n    coproc [-n name] program \
+                                   [args...]",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.flow_control.for_(args)
+                                shell.coproc(args)
                             },
                         });
 
-        commands.insert("source",
-                        Command {
-                            name: "source",
-                            help: "Evaluate the file following the command or re-initialize the init file",
+        commands.insert("parallel",
+                        box Command {
+                            name: "parallel",
+                            help: "Run a program for each input, with {} replaced by it, running as \
+                                   many at once as there are processors or as -j gives\n    \
+                                   parallel [-j count] program [args...] -- inputs...",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.source_command(args)
+                                shell.parallel(args)
+                            },
+                        });
+
+        commands.insert("repeat",
+                        box Command {
+                            name: "repeat",
+                            help: "Run a command a number of times, stopping at the first failure \
+                                   with -e\n    repeat [-e] count command [args...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.repeat(args, &Command::map())
+                            },
+                        });
 
+        commands.insert("watch",
+                        box Command {
+                            name: "watch",
+                            help: "Run a command every two seconds, or as often as given, clearing the \
+                                   screen before each run, until Ctrl-C is pressed\n    watch [-n \
+                                   seconds] command [args...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.watch(args, &Command::map())
+                            },
+                        });
+
+        commands.insert("echo",
+                        box Command {
+                            name: "echo",
+                            help: "Display the arguments, separated by spaces\n    echo [-n] \
+                                   <arguments...>",
+                            main: box |args: &[String], _: &mut Shell| -> i32 {
+                                let newline = args.get(1).map_or(true, |arg| arg != "-n");
+                                let skip = if newline { 1 } else { 2 };
+                                let words: Vec<&str> = args.iter().skip(skip).map(|arg| arg.as_str()).collect();
+                                let stdout = stdout();
+                                let mut stdout = stdout.lock();
+                                let _ = write!(stdout, "{}", words.join(" "));
+                                if newline {
+                                    let _ = writeln!(stdout, "");
+                                }
+                                let _ = stdout.flush();
+                                SUCCESS
+                            },
+                        });
+
+        commands.insert("hash",
+                        box Command {
+                            name: "hash",
+                            help: "Display where the commands that have been run were found, or \
+                                   forget them with -r\n    hash [-r]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                if args.get(1).map_or(false, |arg| arg == "-r") {
+                                    shell.command_cache.clear();
+                                } else {
+                                    for (command, program) in shell.command_cache.commands() {
+                                        println!("{}\t{}", command, program.display());
+                                    }
+                                }
+                                SUCCESS
+                            },
+                        });
+
+        commands.insert("wait",
+                        box Command {
+                            name: "wait",
+                            help: "Wait for background jobs to finish, or for the jobs or processes \
+                                   given\n    wait [%job | pid...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.jobs.wait(args)
                             },
                         });
 
         commands.insert("true",
-                        Command {
+                        box Command {
                             name: "true",
                             help: "Do nothing, successfully",
                             main: box |_: &[String], _: &mut Shell| -> i32 {
@@ -427,7 +1506,7 @@ impl Command {
                         });
 
         commands.insert("false",
-                        Command {
+                        box Command {
                             name: "false",
                             help: "Do nothing, unsuccessfully",
                             main: box |_: &[String], _: &mut Shell| -> i32 {
@@ -435,23 +1514,14 @@ impl Command {
                             },
                         });
 
-        commands.insert("fn",
-                        Command {
-                            name: "fn",
-                            help: "Create a function",
-                            main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.flow_control.fn_(args)
-                            },
-                        });
-
         let command_helper: HashMap<&'static str, &'static str> = commands.iter()
                                                                           .map(|(k, v)| {
-                                                                              (*k, v.help)
+                                                                              (*k, v.help())
                                                                           })
                                                                           .collect();
 
         commands.insert("help",
-                        Command {
+                        box Command {
                             name: "help",
                             help: "Display helpful information about a given command, or list \
                                    commands if none specified\n    help <command>",
@@ -480,45 +1550,241 @@ impl Command {
     }
 }
 
+/// A job that runs the arguments as they are, such as the command given to a builtin that runs
+/// it
+fn job_from_args(args: &[String]) -> Job {
+    Job::new(args.iter().map(|arg| Word::literal(arg)).collect(), vec![], false)
+}
+
+/// Parses the code that a builtin such as `watch` or `repeat` runs, which is its arguments joined with
+/// spaces like the code given to `eval`, reporting syntax errors with the name of the builtin
+fn parse_command(name: &str, command: &[String]) -> Option<Vec<Statement>> {
+    match parse_statements(&command.join(" ")) {
+        Ok(statements) => Some(statements),
+        Err(err) => {
+            println!("ion: {}: {}", name, err);
+            None
+        },
+    }
+}
+
+/// Waits for a process that the shell forked, returning its exit status
+fn wait_for_process(pid: pid_t) -> i32 {
+    let mut status = 0;
+    while unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            return FAILURE;
+        }
+    }
+    exit_status(status)
+}
+
+/// Closes the pipes of substitutions and waits for the copies of the shell that run them. They
+/// see the end of their input, or get an error when writing output, once the pipes are closed.
+fn close_substitutions(substitutions: Vec<(File, pid_t)>) {
+    for (file, pid) in substitutions {
+        drop(file);
+        while unsafe { libc::waitpid(pid, ptr::null_mut(), 0) } < 0 {
+            if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                break;
+            }
+        }
+    }
+}
+
+/// Whether the lines read so far need more lines to be complete, such as when they end in the
+/// middle of a quoted word or after a pipe. The final newline is ignored so that a line ending
+/// with a backslash continues onto the next line.
+fn is_incomplete(lines: &str) -> bool {
+    let code = lines.trim_right_matches('\n').trim_right_matches('\r');
+    match parse_statements(code) {
+        Err(err) => err.is_incomplete(),
+        Ok(_) => false,
+    }
+}
+
 fn main() {
+    signals::init();
     let commands = Command::map();
-    let mut shell = Shell::new();
+    // Output that goes to a program instead of a terminal, like `ion | tee log`, is kept free of
+    // prompts
+    let interactive = env::args().len() < 2 &&
+                      unsafe { libc::isatty(libc::STDIN_FILENO) != 0 && libc::isatty(libc::STDOUT_FILENO) != 0 };
+    let mut shell = Shell::new(interactive);
 
-    let mut dash_c = false;
-    for arg in env::args().skip(1) {
+    let mut args = env::args().skip(1);
+    if let Some(arg) = args.next() {
         if arg == "-c" {
-            dash_c = true;
-        } else {
-            if dash_c {
-                shell.on_command(&arg, &commands);
-            } else {
-                match File::open(&arg) {
-                    Ok(mut file) => {
-                        let mut command_list = String::new();
-                        match file.read_to_string(&mut command_list) {
-                            Ok(_) => shell.on_command(&command_list, &commands),
-                            Err(err) => println!("ion: failed to read {}: {}", arg, err)
-                        }
-                    },
-                    Err(err) => println!("ion: failed to open {}: {}", arg, err)
-                }
+            // `ion -c code name args...` runs the code with `$0` set to the name and `$1` onward
+            // to the arguments after it, like a script
+            match args.next() {
+                Some(code) => {
+                    let code_args: Vec<String> = args.collect();
+                    match code_args.split_first() {
+                        Some((name, rest)) => shell.variables.set_arguments(name, rest),
+                        None => shell.variables.set_arguments("ion", &[]),
+                    }
+                    shell.run_script_code(&code, &commands);
+                },
+                None => {
+                    println!("ion: -c: option requires an argument");
+                    shell.set_status(FAILURE);
+                },
             }
+        } else {
+            let script_args: Vec<String> = args.collect();
+            shell.run_script(&arg, &script_args, &commands);
+        }
 
-            // Exit with the previous command's exit status.
-            process::exit(shell.history.previous_status);
+        // Exit with the previous command's exit status.
+        let status = shell.history.previous_status;
+        shell.exit(status);
+    }
+
+    // Without a terminal to type into, stdin is a script, such as with `echo ls | ion`
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+        let mut code = String::new();
+        match io::stdin().read_to_string(&mut code) {
+            Ok(_) => shell.run_script_code(&code, &commands),
+            Err(err) => {
+                println!("ion: failed to read stdin: {}", err);
+                shell.set_status(FAILURE);
+            }
         }
+        let status = shell.history.previous_status;
+        shell.exit(status);
     }
 
+    if shell.interactive {
+        shell.jobs.enable_job_control();
+    }
     shell.print_prompt();
+    let mut buffer = String::new();
     while let Some(command) = readln() {
-        let command = command.trim();
+        buffer.push_str(&command);
+        if is_incomplete(&buffer) {
+            shell.print_continuation_prompt();
+            continue;
+        }
+        let command = buffer.trim().to_string();
+        buffer.clear();
         if !command.is_empty() {
-            shell.on_command(command, &commands);
+            signals::clear();
+            shell.on_command(&command, &commands);
         }
+        shell.run_traps(&commands);
         shell.update_variables();
+        shell.jobs.report_changes();
         shell.print_prompt();
     }
 
     // Exit with the previous command's exit status.
-    process::exit(shell.history.previous_status);
+    let status = shell.history.previous_status;
+    shell.exit(status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+    use libc;
+    use status::SUCCESS;
+
+    fn temporary_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ion-shell-{}-{}", name, unsafe { libc::getpid() }));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn read(path: &PathBuf) -> String {
+        let mut text = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut text)).unwrap();
+        let _ = fs::remove_file(path);
+        text
+    }
+
+    #[test]
+    fn run_functions_in_the_background() {
+        let commands = Command::map();
+        let mut shell = Shell::with_defaults(false);
+        let path = temporary_file("function");
+        shell.run_code(&format!("fn write_done\necho done > {}\nend\nwrite_done &", path.display()), &commands);
+        assert_eq!(SUCCESS, shell.history.previous_status);
+        assert_eq!(1, shell.jobs.jobs().len());
+        let id = shell.jobs.jobs()[0].id;
+        assert_eq!(SUCCESS, shell.jobs.wait_for(id));
+        assert_eq!("done\n", read(&path));
+    }
+
+    #[test]
+    fn pipe_between_builtins_and_programs() {
+        let commands = Command::map();
+        let mut shell = Shell::with_defaults(false);
+        let path = temporary_file("pipe");
+        shell.run_code(&format!("echo hello | cat > {}", path.display()), &commands);
+        assert_eq!(SUCCESS, shell.history.previous_status);
+        assert_eq!("hello\n", read(&path));
+        // A builtin at the end runs in the shell, so the variable it sets is kept
+        shell.run_code("printf 'first\\nsecond\\n' | read line", &commands);
+        assert_eq!(Some(&"first".to_string()), shell.variables.get_var("line"));
+    }
+
+    #[test]
+    fn repeat_pipelines() {
+        let commands = Command::map();
+        let mut shell = Shell::with_defaults(false);
+        let path = temporary_file("repeat");
+        shell.run_code(&format!("repeat 3 'echo run | cat >> {}'", path.display()), &commands);
+        assert_eq!(SUCCESS, shell.history.previous_status);
+        assert_eq!("run\nrun\nrun\n", read(&path));
+        shell.run_code("repeat 2 'echo |'", &commands);
+        assert_eq!(FAILURE, shell.history.previous_status);
+    }
+
+    #[test]
+    fn coprocesses_see_the_end_of_their_input() {
+        let commands = Command::map();
+        let mut shell = Shell::with_defaults(false);
+        shell.run_code("coproc cat", &commands);
+        let fd = |shell: &Shell, name: &str| shell.variables.get_var(name).unwrap().parse::<RawFd>().unwrap();
+        let (input, output) = (fd(&shell, "COPROC_INPUT"), fd(&shell, "COPROC_OUTPUT"));
+        assert!(unsafe { libc::fcntl(input, libc::F_GETFD) } & libc::FD_CLOEXEC != 0);
+        // A program that is still running must not hold the input of the coprocess open
+        shell.run_code("sleep 5 &", &commands);
+        let start = Instant::now();
+        let mut writer = unsafe { File::from_raw_fd(input) };
+        writer.write_all(b"hello\n").unwrap();
+        drop(writer);
+        let mut text = String::new();
+        unsafe { File::from_raw_fd(output) }.read_to_string(&mut text).unwrap();
+        assert_eq!("hello\n", text);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // Replacing a coprocess closes the descriptors of the one before, so it finishes
+        shell.run_code("coproc -n CALC cat", &commands);
+        let first = unsafe { libc::fcntl(fd(&shell, "CALC_OUTPUT"), libc::F_DUPFD_CLOEXEC, 0) };
+        shell.run_code("coproc -n CALC cat", &commands);
+        let mut text = String::new();
+        unsafe { File::from_raw_fd(first) }.read_to_string(&mut text).unwrap();
+        assert_eq!("", text);
+        for job in shell.jobs.jobs() {
+            job.signal(libc::SIGKILL);
+        }
+    }
+
+    #[test]
+    fn run_pipelines_with_builtins_in_the_background() {
+        let commands = Command::map();
+        let mut shell = Shell::with_defaults(false);
+        let path = temporary_file("background");
+        shell.run_code(&format!("echo hello | cat > {} &", path.display()), &commands);
+        assert_eq!(1, shell.jobs.jobs().len());
+        let id = shell.jobs.jobs()[0].id;
+        assert_eq!(SUCCESS, shell.jobs.wait_for(id));
+        assert_eq!("hello\n", read(&path));
+    }
 }
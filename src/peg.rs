@@ -1,325 +1,2939 @@
+use std::fmt;
+use std::path::Path;
 use std::process::Command;
 
-use self::grammar::pipelines;
-use glob::glob;
+use super::arithmetic::Expression;
+
+use self::grammar::{statements, spanned_statements, pipelines, here_document_body};
+
+/// A statement of a script, which is what the parser produces for each line or `;` separated
+/// part of the code. Blocks contain the statements up to their `end`.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Statement {
+    /// Jobs connected by pipes, such as `ls | wc -l`
+    Pipeline(Pipeline),
+    /// `name = value` or `name=value`, which sets a shell variable
+    Assignment {
+        name: String,
+        value: Value,
+    },
+    /// Statements in parentheses, which run without changing the variables, functions or
+    /// directory of the shell, such as `(cd /tmp; ls)`
+    Subshell {
+        body: Vec<Statement>,
+        condition: Condition,
+    },
+    /// Statements in braces, which share their redirections and act as a single command in
+    /// `&&` and `||` chains, such as `{ make; make test; } > log.txt`
+    Group {
+        body: Vec<Statement>,
+        redirections: Vec<Redirection>,
+        condition: Condition,
+    },
+    /// `if test`, which runs the success block if the test passes and the failure block after
+    /// the `else` otherwise
+    If {
+        test: Test,
+        success: Vec<Statement>,
+        failure: Vec<Statement>,
+    },
+    /// `while test`, which runs the body until the test fails
+    While {
+        test: Test,
+        body: Vec<Statement>,
+    },
+    /// `for variable in values`, which runs the body with the variable set to each value
+    For {
+        variable: String,
+        values: Vec<Word>,
+        body: Vec<Statement>,
+    },
+    /// `fn name args`, which defines a function that runs the body
+    Function {
+        name: String,
+        args: Vec<String>,
+        body: Vec<Statement>,
+    },
+}
+
+impl Statement {
+    /// Sets the condition of statements that can be part of `&&` and `||` chains
+    pub fn with_condition(self, condition: Condition) -> Statement {
+        match self {
+            Statement::Pipeline(mut pipeline) => {
+                pipeline.condition = condition;
+                Statement::Pipeline(pipeline)
+            },
+            Statement::Subshell { body, .. } => Statement::Subshell { body: body, condition: condition },
+            Statement::Group { body, redirections, .. } => {
+                Statement::Group { body: body, redirections: redirections, condition: condition }
+            },
+            statement => statement,
+        }
+    }
+
+    /// The condition on the statement before it, which is `Always` for statements that cannot be
+    /// part of `&&` and `||` chains
+    pub fn condition(&self) -> Condition {
+        match *self {
+            Statement::Pipeline(ref pipeline) => pipeline.condition,
+            Statement::Subshell { condition, .. } | Statement::Group { condition, .. } => condition,
+            _ => Condition::Always,
+        }
+    }
+}
+
+/// What a variable is set to by an assignment
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Value {
+    /// A single word, such as `value` or `"a b"`
+    Word(Word),
+    /// Words in brackets, which each become an element of an array (`[ /tmp /var /home ]`)
+    Array(Vec<Word>),
+}
+
+/// What decides which block of an `if` runs, or whether a `while` loop keeps running
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Test {
+    /// Two words compared with `==`, `!=`, `>`, `>=`, `<` or `<=`, such as `$x == 3`
+    Comparison(Word, String, Word),
+    /// A pipeline that passes when it succeeds, such as `grep -q foo bar`
+    Pipeline(Pipeline),
+}
+
+/// Whether a pipeline always runs, or only runs depending on the exit status of the pipeline
+/// before it (`&&` and `||`).
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Condition {
+    Always,
+    OnSuccess,
+    OnFailure,
+}
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Pipeline {
     pub jobs: Vec<Job>,
-    pub stdout_file: Option<String>,
-    pub stdin_file: Option<String>,
+    pub condition: Condition,
+    /// Whether the exit status is inverted (`! grep -q foo bar`)
+    pub negated: bool,
+    /// Whether the time the pipeline takes is reported once it finishes (`time cargo build`)
+    pub timed: bool,
+}
+
+impl Pipeline {
+
+    pub fn new(jobs: Vec<Job>, condition: Condition) -> Self {
+        Pipeline {
+            jobs: jobs,
+            condition: condition,
+            negated: false,
+            timed: false,
+        }
+    }
+
+    /// Starts building a pipeline out of jobs, without parsing any code
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder { pipeline: Pipeline::new(vec![], Condition::Always) }
+    }
+
+    /// Whether the pipeline runs without the shell waiting for it (`make &`)
+    pub fn is_background(&self) -> bool {
+        self.jobs.last().map_or(false, |job| job.background)
+    }
+}
+
+impl fmt::Display for Pipeline {
+    /// Displays the pipeline as code, leaving out the condition it is run on
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.timed {
+            try!(write!(f, "time "));
+        }
+        if self.negated {
+            try!(write!(f, "! "));
+        }
+        let jobs: Vec<String> = self.jobs.iter().map(|job| job.to_string()).collect();
+        write!(f, "{}", jobs.join(" | "))
+    }
+}
+
+/// Builds a pipeline, such as `Pipeline::builder().job(ls).job(wc).build()` for `ls | wc`.
+pub struct PipelineBuilder {
+    pipeline: Pipeline,
+}
+
+impl PipelineBuilder {
+    /// Adds a job, which reads the output of the job before it
+    pub fn job(mut self, job: Job) -> Self {
+        self.pipeline.jobs.push(job);
+        self
+    }
+
+    pub fn condition(mut self, condition: Condition) -> Self {
+        self.pipeline.condition = condition;
+        self
+    }
+
+    pub fn negated(mut self) -> Self {
+        self.pipeline.negated = true;
+        self
+    }
+
+    pub fn timed(mut self) -> Self {
+        self.pipeline.timed = true;
+        self
+    }
+
+    /// Returns the pipeline, which will panic when it is run if it has no jobs
+    pub fn build(self) -> Pipeline {
+        self.pipeline
+    }
+}
+
+/// What a redirected file descriptor is connected to.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Target {
+    /// A file opened for reading (`<`)
+    Input(Word),
+    /// A file that is truncated before being written to (`>`)
+    Output(Word),
+    /// A file that is truncated even when `noclobber` keeps `>` from doing so (`>|`)
+    Clobber(Word),
+    /// A file that is written to from its end (`>>`)
+    Append(Word),
+    /// Another of the job's file descriptors (`2>&1` or `0<&3`)
+    Duplicate(i32),
+    /// Nothing, since the descriptor is closed (`2>&-`)
+    Close,
+    /// The body of a here document (`<<EOF`), which is read from the lines after the job
+    HereDocument(Word),
+    /// A single word followed by a newline (`<<< word`)
+    HereString(Word),
+}
+
+/// Connects one of a job's file descriptors to a target instead of the descriptor it would
+/// otherwise inherit from the shell or the pipeline.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Redirection {
+    pub fd: i32,
+    pub target: Target,
+}
+
+impl Redirection {
+    pub fn new(fd: i32, target: Target) -> Self {
+        Redirection {
+            fd: fd,
+            target: target,
+        }
+    }
+}
+
+impl Target {
+    pub fn input(path: &str) -> Self {
+        Target::Input(Word::literal(path))
+    }
+
+    pub fn output(path: &str) -> Self {
+        Target::Output(Word::literal(path))
+    }
+
+    pub fn append(path: &str) -> Self {
+        Target::Append(Word::literal(path))
+    }
+}
+
+/// A piece of a word as it was written, before any expansion has taken place.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum WordSegment {
+    /// Text that is used as is
+    Literal(String),
+    /// Unquoted text containing glob characters, such as `*.rs`, that is matched against paths
+    Pattern(String),
+    /// A variable, such as `$HOME`, that is replaced by its value
+    Variable(String),
+    /// A variable with an operator for when it is unset or empty, such as `${name:-default}`
+    Parameter(String, ParameterOperator, Word),
+    /// An array variable, such as `@dirs` or `@dirs[1..3]`, that is replaced by its elements
+    Array(String, Option<Index>),
+    /// A method called on a variable, such as `$join(dirs, ",")`, with the name of the method,
+    /// the name of the variable, and the rest of the arguments
+    Method(String, String, Vec<Word>),
+    /// Pipelines, such as `$(ls | wc -l)`, that are replaced by their output, and whether the
+    /// output is split into separate arguments, which it is unless it is in double quotes
+    CommandSubstitution(Vec<Pipeline>, bool),
+    /// Alternatives, such as `{old,new}`, that each produce a separate argument
+    Brace(Vec<Word>),
+    /// A range of numbers, such as `{1..10}`, with the start, end, and step size
+    Range(i64, i64, i64),
+    /// An arithmetic expression to evaluate (`$((1 + 2))`)
+    Arithmetic(Expression),
+    /// A path to read the output of the pipelines from (`<(ls)`)
+    InputSubstitution(Vec<Pipeline>),
+    /// A path to write the input of the pipelines to (`>(wc -l)`)
+    OutputSubstitution(Vec<Pipeline>),
+}
+
+/// What a parameter expansion does with its word, which is only expanded when it is needed.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ParameterOperator {
+    /// Uses the word if the variable is unset or empty (`${name:-word}`)
+    Default,
+    /// Sets the variable to the word if it is unset or empty, then uses it (`${name:=word}`)
+    Assign,
+    /// Uses the word if the variable is set and not empty, and nothing otherwise (`${name:+word}`)
+    Alternative,
+    /// Fails with the word as the message if the variable is unset or empty (`${name:?word}`)
+    Error,
+}
+
+/// Which elements of an array are expanded. Negative indices count from the end of the array.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Index {
+    /// A single element (`@dirs[2]`)
+    Single(i64),
+    /// The elements from the start up to, but not including, the end (`@dirs[1..3]`). Either
+    /// side can be left out to start at the first element or end after the last one.
+    Range(Option<i64>, Option<i64>),
+}
+
+impl Index {
+    /// Returns the elements that the index selects, which may be none of them
+    pub fn select<'a>(&self, elements: &'a [String]) -> &'a [String] {
+        let length = elements.len() as i64;
+        let position = |index: i64| {
+            let index = if index < 0 { length + index } else { index };
+            if index < 0 { 0 } else if index > length { length } else { index }
+        };
+        let (start, end) = match *self {
+            Index::Single(index) if index >= length || index < -length => return &[],
+            Index::Single(index) => (position(index), position(index) + 1),
+            Index::Range(start, end) => (position(start.unwrap_or(0)), position(end.unwrap_or(length))),
+        };
+        if start >= end {
+            &[]
+        } else {
+            &elements[start as usize..end as usize]
+        }
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Index::Single(index) => write!(f, "{}", index),
+            Index::Range(start, end) => {
+                if let Some(start) = start {
+                    try!(write!(f, "{}", start));
+                }
+                try!(write!(f, ".."));
+                if let Some(end) = end {
+                    try!(write!(f, "{}", end));
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl ParameterOperator {
+    fn symbol(&self) -> &'static str {
+        match *self {
+            ParameterOperator::Default => ":-",
+            ParameterOperator::Assign => ":=",
+            ParameterOperator::Alternative => ":+",
+            ParameterOperator::Error => ":?",
+        }
+    }
+}
+
+impl WordSegment {
+    /// Creates a segment from unquoted text, which is a pattern if it contains glob characters.
+    pub fn unquoted(text: &str) -> Self {
+        if text.contains(|character| character == '*' || character == '?' || character == '[') {
+            WordSegment::Pattern(text.to_string())
+        } else {
+            WordSegment::Literal(text.to_string())
+        }
+    }
+}
+
+/// The byte offsets in the parsed code where something starts and ends
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span {
+            start: start,
+            end: end,
+        }
+    }
+}
+
+/// A single argument that is made of segments which are expanded and joined together.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Word {
+    pub segments: Vec<WordSegment>,
+    pub span: Span,
+}
+
+/// Words are equal when their segments are, wherever they were parsed from.
+impl PartialEq for Word {
+    fn eq(&self, other: &Word) -> bool {
+        self.segments == other.segments
+    }
+}
+
+impl Word {
+    /// Adjacent literal segments are merged and empty ones are dropped.
+    pub fn new(segments: Vec<WordSegment>) -> Self {
+        let mut merged: Vec<WordSegment> = vec![];
+        for segment in segments {
+            if let WordSegment::Literal(ref text) = segment {
+                if text.is_empty() {
+                    continue;
+                }
+                if let Some(&mut WordSegment::Literal(ref mut previous)) = merged.last_mut() {
+                    previous.push_str(text);
+                    continue;
+                }
+            }
+            merged.push(segment);
+        }
+        Word {
+            segments: merged,
+            span: Span::default(),
+        }
+    }
+
+    pub fn literal(text: &str) -> Self {
+        Word::new(vec![WordSegment::Literal(text.to_string())])
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+}
+
+impl fmt::Display for Word {
+    /// Displays the word with its quoting removed and its expansions unexpanded
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, segment) in self.segments.iter().enumerate() {
+            match *segment {
+                WordSegment::Literal(ref text) => try!(write!(f, "{}", text)),
+                WordSegment::Pattern(ref pattern) => try!(write!(f, "{}", pattern)),
+                WordSegment::Variable(ref name) => {
+                    // Braces keep the name apart from text that could otherwise be part of it
+                    let joined = match self.segments.get(index + 1) {
+                        Some(&WordSegment::Literal(ref text)) | Some(&WordSegment::Pattern(ref text)) => {
+                            text.starts_with(|c: char| c.is_alphanumeric() || c == '_')
+                        },
+                        _ => false,
+                    };
+                    if joined {
+                        try!(write!(f, "${{{}}}", name))
+                    } else {
+                        try!(write!(f, "${}", name))
+                    }
+                },
+                WordSegment::Parameter(ref name, operator, ref word) => {
+                    try!(write!(f, "${{{}{}{}}}", name, operator.symbol(), word))
+                },
+                WordSegment::Method(ref method, ref variable, ref args) => {
+                    try!(write!(f, "${}({}", method, variable));
+                    for arg in args.iter() {
+                        try!(write!(f, ", {}", arg));
+                    }
+                    try!(write!(f, ")"));
+                },
+                WordSegment::Array(ref name, None) => try!(write!(f, "@{}", name)),
+                WordSegment::Array(ref name, Some(index)) => try!(write!(f, "@{}[{}]", name, index)),
+                WordSegment::CommandSubstitution(ref pipelines, _) => {
+                    try!(write!(f, "$({})", display_pipelines(pipelines)))
+                },
+                WordSegment::InputSubstitution(ref pipelines) => {
+                    try!(write!(f, "<({})", display_pipelines(pipelines)))
+                },
+                WordSegment::OutputSubstitution(ref pipelines) => {
+                    try!(write!(f, ">({})", display_pipelines(pipelines)))
+                },
+                WordSegment::Brace(ref alternatives) => {
+                    let alternatives: Vec<String> = alternatives.iter()
+                                                                .map(|word| word.to_string())
+                                                                .collect();
+                    try!(write!(f, "{{{}}}", alternatives.join(",")));
+                },
+                WordSegment::Arithmetic(ref expression) => try!(write!(f, "$(({}))", expression)),
+                WordSegment::Range(start, end, 1) => try!(write!(f, "{{{}..{}}}", start, end)),
+                WordSegment::Range(start, end, step) => {
+                    try!(write!(f, "{{{}..{}..{}}}", start, end, step))
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Displays the pipelines of a substitution as code, with `&&` and `||` before those that only
+/// run depending on the pipeline before them
+fn display_pipelines(pipelines: &[Pipeline]) -> String {
+    let mut code = String::new();
+    for (index, pipeline) in pipelines.iter().enumerate() {
+        if index > 0 {
+            code.push_str(match pipeline.condition {
+                Condition::Always => "; ",
+                Condition::OnSuccess => " && ",
+                Condition::OnFailure => " || ",
+            });
+        }
+        code.push_str(&pipeline.to_string());
+    }
+    code
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Job {
+    pub command: String,
+    pub args: Vec<String>,
+    pub words: Vec<Word>,
+    pub redirections: Vec<Redirection>,
+    pub background: bool,
+    /// Environment variables set only for the job (`RUST_LOG=debug cargo run`)
+    pub env: Vec<(String, Word)>,
+    pub span: Span,
+}
+
+/// Jobs are equal when everything but their spans is.
+impl PartialEq for Job {
+    fn eq(&self, other: &Job) -> bool {
+        self.command == other.command && self.args == other.args && self.words == other.words &&
+        self.redirections == other.redirections && self.background == other.background &&
+        self.env == other.env
+    }
+}
+
+impl Job {
+
+    /// The job's args are the text of its words, which become the arguments that are executed
+    /// once the words only contain literals.
+    pub fn new(words: Vec<Word>, redirections: Vec<Redirection>, background: bool) -> Self {
+        let args: Vec<String> = words.iter().map(|word| word.to_string()).collect();
+        let command = args[0].clone();
+        Job {
+            command: command,
+            args: args,
+            words: words,
+            redirections: redirections,
+            background: background,
+            env: vec![],
+            span: Span::default(),
+        }
+    }
+
+    pub fn with_env(mut self, env: Vec<(String, Word)>) -> Self {
+        self.env = env;
+        self
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Starts building a job that runs the command, without parsing any code
+    pub fn builder(command: &str) -> JobBuilder {
+        JobBuilder {
+            words: vec![Word::literal(command)],
+            redirections: vec![],
+            background: false,
+            env: vec![],
+        }
+    }
+
+    /// Builds the command that runs the program at the path with the job's arguments. The
+    /// program gets only the environment variables given, and those set for the job.
+    pub fn build_command<'a, I>(&self, program: &Path, environment: I) -> Command
+        where I: IntoIterator<Item = (&'a String, &'a String)>
+    {
+        let mut command = Command::new(program);
+        command.env_clear();
+        for (name, value) in environment {
+            command.env(name, value);
+        }
+        for i in 1..self.args.len() {
+            if let Some(arg) = self.args.get(i) {
+                command.arg(arg);
+            }
+        }
+        for &(ref name, ref value) in self.env.iter() {
+            command.env(name, value.to_string());
+        }
+        command
+    }
+}
+
+/// Builds a job, such as
+/// `Job::builder("cargo").arg("build").env("RUST_LOG", "debug").stdout(Target::append("log"))`.
+/// The arguments and values are used as they are, without any expansion.
+pub struct JobBuilder {
+    words: Vec<Word>,
+    redirections: Vec<Redirection>,
+    background: bool,
+    env: Vec<(String, Word)>,
+}
+
+impl JobBuilder {
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.words.push(Word::literal(arg));
+        self
+    }
+
+    pub fn args<I: IntoIterator>(mut self, args: I) -> Self
+        where I::Item: AsRef<str>
+    {
+        self.words.extend(args.into_iter().map(|arg| Word::literal(arg.as_ref())));
+        self
+    }
+
+    /// Adds an argument that is expanded when the job runs, like the words of parsed code
+    pub fn word(mut self, word: Word) -> Self {
+        self.words.push(word);
+        self
+    }
+
+    /// Sets an environment variable for the job only
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env.push((name.to_string(), Word::literal(value)));
+        self
+    }
+
+    pub fn redirect(mut self, fd: i32, target: Target) -> Self {
+        self.redirections.push(Redirection::new(fd, target));
+        self
+    }
+
+    pub fn stdin(self, target: Target) -> Self {
+        self.redirect(0, target)
+    }
+
+    pub fn stdout(self, target: Target) -> Self {
+        self.redirect(1, target)
+    }
+
+    pub fn stderr(self, target: Target) -> Self {
+        self.redirect(2, target)
+    }
+
+    pub fn background(mut self) -> Self {
+        self.background = true;
+        self
+    }
+
+    pub fn build(self) -> Job {
+        Job::new(self.words, self.redirections, self.background).with_env(self.env)
+    }
+}
+
+impl fmt::Display for Job {
+    /// Displays the job as code that parses back into the same job, with the arguments quoted
+    /// where they need to be. Here documents are displayed as here strings.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts: Vec<String> = vec![];
+        for &(ref name, ref value) in self.env.iter() {
+            parts.push(format!("{}={}", name, quote_word(value)));
+        }
+        for (index, word) in self.words.iter().enumerate() {
+            let keyword = match word.segments.first() {
+                Some(&WordSegment::Literal(ref text)) => KEYWORDS.contains(&text.as_str()),
+                _ => false,
+            };
+            if index == 0 && keyword && word.segments.len() == 1 {
+                parts.push(format!("'{}'", word));
+            } else {
+                parts.push(quote_word(word));
+            }
+        }
+        for redirection in self.redirections.iter() {
+            parts.push(display_redirection(redirection));
+        }
+        if self.background {
+            parts.push("&".to_string());
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Words that are parsed as something other than a command at the start of a job
+const KEYWORDS: &'static [&'static str] = &["if", "else", "fi", "end", "while", "for", "fn", "and", "or",
+                                            "not", "time", "let"];
+
+/// Quotes the word so that it parses back into the same segments
+fn quote_word(word: &Word) -> String {
+    if word.segments.is_empty() {
+        return "''".to_string();
+    }
+    let is_pattern = |index: usize| {
+        match word.segments.get(index) {
+            Some(&WordSegment::Pattern(_)) => true,
+            _ => false,
+        }
+    };
+    let mut quoted = String::new();
+    for (index, segment) in word.segments.iter().enumerate() {
+        match *segment {
+            WordSegment::Literal(ref text) => {
+                // Unquoted text next to a pattern would become part of the pattern
+                let next_to_pattern = is_pattern(index + 1) || (index > 0 && is_pattern(index - 1));
+                quoted.push_str(&quote_text(text, index == 0, next_to_pattern));
+            },
+            WordSegment::Pattern(ref pattern) => {
+                for (position, character) in pattern.chars().enumerate() {
+                    if character == '\n' {
+                        quoted.push_str("$'\\n'");
+                        continue;
+                    }
+                    let comment = character == '#' && index == 0 && position == 0;
+                    if comment || " \t\r;&|<>()\\$`{\"'".contains(character) {
+                        quoted.push('\\');
+                    }
+                    quoted.push(character);
+                }
+            },
+            WordSegment::Variable(ref name) if index + 1 < word.segments.len() => {
+                quoted.push_str(&format!("${{{}}}", name));
+            },
+            WordSegment::CommandSubstitution(ref pipelines, false) => {
+                quoted.push_str(&format!("\"$({})\"", display_pipelines(pipelines)));
+            },
+            _ => quoted.push_str(&Word::new(vec![segment.clone()]).to_string()),
+        }
+    }
+    quoted
+}
+
+/// Single quotes the text unless every character in it is used as is outside of quotes
+fn quote_text(text: &str, word_start: bool, force: bool) -> String {
+    let safe = |character: char| character.is_alphanumeric() || "_-./:,+%^@".contains(character);
+    if force || text.is_empty() || (word_start && text.starts_with('@')) || !text.chars().all(safe) {
+        format!("'{}'", text.replace("'", "'\\''"))
+    } else {
+        text.to_string()
+    }
+}
+
+fn display_redirection(redirection: &Redirection) -> String {
+    let (default_fd, operator, target) = match redirection.target {
+        Target::Input(ref path) => (0, "< ".to_string(), quote_word(path)),
+        Target::Output(ref path) => (1, "> ".to_string(), quote_word(path)),
+        Target::Clobber(ref path) => (1, ">| ".to_string(), quote_word(path)),
+        Target::Append(ref path) => (1, ">> ".to_string(), quote_word(path)),
+        Target::Duplicate(fd) if redirection.fd == 0 => (0, "<&".to_string(), fd.to_string()),
+        Target::Duplicate(fd) => (1, ">&".to_string(), fd.to_string()),
+        Target::Close if redirection.fd == 0 => (0, "<&".to_string(), "-".to_string()),
+        Target::Close => (1, ">&".to_string(), "-".to_string()),
+        Target::HereString(ref word) => (0, "<<< ".to_string(), quote_word(word)),
+        Target::HereDocument(ref word) => {
+            // A here string ends with a newline, like the body of a here document does
+            let mut body = word.clone();
+            let ends_with_newline = match body.segments.last_mut() {
+                Some(&mut WordSegment::Literal(ref mut text)) if text.ends_with('\n') => {
+                    text.pop();
+                    true
+                },
+                _ => false,
+            };
+            let body = if ends_with_newline { Word::new(body.segments) } else { body };
+            (0, "<<< ".to_string(), quote_word(&body))
+        },
+    };
+    if redirection.fd == default_fd {
+        format!("{}{}", operator, target)
+    } else {
+        format!("{}{}{}", redirection.fd, operator, target)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseError {
+    /// The code does not match the grammar. The text is the whole line the error is on.
+    Syntax {
+        line: usize,
+        column: usize,
+        offset: usize,
+        expected: Vec<String>,
+        text: String,
+    },
+    /// The code ended before it was complete, such as in a quoted word or after a pipe
+    Incomplete {
+        expected: Vec<String>,
+    },
+    /// The code ended before the delimiter of a here document
+    UnterminatedHereDocument(String),
+}
+
+impl fmt::Display for ParseError {
+    /// Displays syntax errors followed by the line they are on, with a caret under the column
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Syntax { line, column, ref expected, ref text, .. } => {
+                try!(write!(f, "syntax error at line {}, column {}", line, column));
+                if !expected.is_empty() {
+                    try!(write!(f, ": expected one of {}", expected.join(", ")));
+                }
+                let indentation: String = text.chars()
+                                              .take(column - 1)
+                                              .map(|c| if c == '\t' { '\t' } else { ' ' })
+                                              .collect();
+                write!(f, "\n{}\n{}^", text, indentation)
+            },
+            ParseError::Incomplete { ref expected } => {
+                try!(write!(f, "unexpected end of input"));
+                if !expected.is_empty() {
+                    try!(write!(f, ": expected one of {}", expected.join(", ")));
+                }
+                Ok(())
+            },
+            ParseError::UnterminatedHereDocument(ref delimiter) => {
+                write!(f, "here document not terminated by {}", delimiter)
+            },
+        }
+    }
+}
+
+impl ParseError {
+    /// Whether more code could complete the code that failed to parse, rather than it being
+    /// invalid. An interactive shell can keep reading lines until the code is complete.
+    pub fn is_incomplete(&self) -> bool {
+        match *self {
+            ParseError::Incomplete { .. } | ParseError::UnterminatedHereDocument(_) => true,
+            ParseError::Syntax { .. } => false,
+        }
+    }
+
+    /// Moves the position of a syntax error in code that started after the given number of
+    /// lines and bytes of the code it is part of
+    fn shifted(self, lines: usize, offset: usize) -> ParseError {
+        match self {
+            ParseError::Syntax { line, column, offset: error_offset, expected, text } => {
+                ParseError::Syntax {
+                    line: line + lines,
+                    column: column,
+                    offset: error_offset + offset,
+                    expected: expected,
+                    text: text,
+                }
+            },
+            error => error,
+        }
+    }
+
+    fn syntax(error: grammar::ParseError, code: &str) -> ParseError {
+        let mut expected: Vec<String> = error.expected.iter().map(|token| token.to_string()).collect();
+        expected.sort();
+        if error.offset >= code.trim_right().len() {
+            return ParseError::Incomplete { expected: expected };
+        }
+        let text = code.lines().nth(error.line - 1).unwrap_or("");
+        ParseError::Syntax {
+            line: error.line,
+            column: error.column,
+            offset: error.offset,
+            expected: expected,
+            text: text.trim_right_matches('\r').to_string(),
+        }
+    }
+}
+
+/// Parses the code into the statements it is made of
+pub fn parse_statements(code: &str) -> Result<Vec<Statement>, ParseError> {
+    let (remaining, bodies) = try!(collect_here_documents(code));
+    let mut statements = try!(statements(&remaining).map_err(|error| ParseError::syntax(error, code)));
+    attach_here_documents(&mut statements, &mut bodies.into_iter());
+    Ok(statements)
+}
+
+/// Parses the code into statements, reporting every syntax error instead of only the first one.
+/// After an error, parsing starts again at the line after it, and the statements on the lines
+/// before it are kept if they parse on their own. An error inside of a block may be followed by
+/// another one for the end of the block.
+pub fn parse_statements_recovering(code: &str) -> (Vec<Statement>, Vec<ParseError>) {
+    let mut statements = vec![];
+    let mut errors = vec![];
+    let mut offset = 0;
+    let mut line = 0;
+    while offset < code.len() {
+        let rest = &code[offset..];
+        match parse_statements(rest) {
+            Ok(parsed) => {
+                statements.extend(parsed);
+                break;
+            },
+            Err(error) => {
+                let error_offset = match error {
+                    ParseError::Syntax { offset, .. } => offset,
+                    _ => {
+                        // The code ended too soon, so keep the lines before the incomplete code
+                        let mut ends: Vec<usize> = rest.match_indices('\n').map(|(index, _)| index + 1).collect();
+                        ends.reverse();
+                        for end in ends {
+                            if let Ok(parsed) = parse_statements(&rest[..end]) {
+                                statements.extend(parsed);
+                                break;
+                            }
+                        }
+                        errors.push(error);
+                        break;
+                    },
+                };
+                let line_start = rest[..error_offset].rfind('\n').map_or(0, |index| index + 1);
+                if let Ok(parsed) = parse_statements(&rest[..line_start]) {
+                    statements.extend(parsed);
+                }
+                errors.push(error.shifted(line, offset));
+                let line_end = rest[error_offset..].find('\n').map_or(rest.len(), |index| error_offset + index + 1);
+                line += rest[..line_end].matches('\n').count();
+                offset += line_end;
+            },
+        }
+    }
+    (statements, errors)
+}
+
+/// Parses the code into the statements of each top level `;` or newline separated item, with
+/// the span of the code the item was parsed from. The code is also returned with the bodies of
+/// its here documents replaced by whitespace, which is the code that the grammar parsed.
+pub fn parse_spanned_statements(code: &str) -> Result<(String, Vec<(Vec<Statement>, Span)>), ParseError> {
+    let (remaining, bodies) = try!(collect_here_documents(code));
+    let mut items = try!(spanned_statements(&remaining).map_err(|error| ParseError::syntax(error, code)));
+    let mut bodies = bodies.into_iter();
+    for &mut (ref mut statements, _) in items.iter_mut() {
+        attach_here_documents(statements, &mut bodies);
+    }
+    Ok((remaining, items))
 }
 
-impl Pipeline {
+/// Parses code that is only made of pipelines, such as the code of a single command. Keywords
+/// like `if` are parsed as the names of commands.
+pub fn parse(code: &str) -> Result<Vec<Pipeline>, ParseError> {
+    let (remaining, bodies) = try!(collect_here_documents(code));
+    let pipelines = try!(pipelines(&remaining).map_err(|error| ParseError::syntax(error, code)));
+    let mut statements: Vec<Statement> = pipelines.into_iter().map(Statement::Pipeline).collect();
+    attach_here_documents(&mut statements, &mut bodies.into_iter());
+    Ok(statements.into_iter()
+                 .filter_map(|statement| {
+                     match statement {
+                         Statement::Pipeline(pipeline) => Some(pipeline),
+                         _ => None,
+                     }
+                 })
+                 .collect())
+}
+
+/// The bodies of here documents can not be parsed by the grammar, since they end at a line that
+/// is chosen by the user. This takes the bodies out of the code, in the order their redirections
+/// appear, and replaces them with whitespace so that the rest of the code keeps its position.
+fn collect_here_documents(code: &str) -> Result<(String, Vec<Word>), ParseError> {
+    let mut remaining = String::with_capacity(code.len());
+    let mut bodies: Vec<Word> = vec![];
+    let mut lines = code.split('\n');
+    let mut first_line = true;
+    while let Some(line) = lines.next() {
+        if !first_line {
+            remaining.push('\n');
+        }
+        first_line = false;
+        remaining.push_str(line);
+        for (delimiter, quoted, strip_tabs) in here_document_delimiters(line) {
+            let mut body = String::new();
+            let mut terminated = false;
+            while let Some(body_line) = lines.next() {
+                remaining.push('\n');
+                for character in body_line.chars() {
+                    for _ in 0..character.len_utf8() {
+                        remaining.push(' ');
+                    }
+                }
+                let body_line = if strip_tabs { body_line.trim_left_matches('\t') } else { body_line };
+                if body_line.trim_right_matches('\r') == delimiter {
+                    terminated = true;
+                    break;
+                }
+                body.push_str(body_line);
+                body.push('\n');
+            }
+            if !terminated {
+                return Err(ParseError::UnterminatedHereDocument(delimiter));
+            }
+            if quoted {
+                bodies.push(Word::literal(&body));
+            } else {
+                bodies.push(here_document_body(&body).unwrap_or(Word::literal(&body)));
+            }
+        }
+    }
+    Ok((remaining, bodies))
+}
+
+/// Finds the here documents started on the line, returning their delimiters, whether the
+/// delimiter was quoted so the body should not be expanded, and whether leading tabs should be
+/// stripped from the body (`<<-`).
+fn here_document_delimiters(line: &str) -> Vec<(String, bool, bool)> {
+    let mut delimiters = vec![];
+    let mut chars = line.chars().peekable();
+    let mut single_quoted = false;
+    let mut double_quoted = false;
+    let mut previous = ' ';
+    while let Some(character) = chars.next() {
+        let word_start = previous == ' ' || previous == '\t' || ";&|()".contains(previous);
+        previous = character;
+        match character {
+            '\\' if !single_quoted => {
+                chars.next();
+            },
+            '\'' if !double_quoted => single_quoted = !single_quoted,
+            '"' if !single_quoted => double_quoted = !double_quoted,
+            '#' if !single_quoted && !double_quoted && word_start => break,
+            '<' if !single_quoted && !double_quoted && chars.peek() == Some(&'<') => {
+                chars.next();
+                if chars.peek() == Some(&'<') {
+                    chars.next();
+                    continue;
+                }
+                let strip_tabs = chars.peek() == Some(&'-');
+                if strip_tabs {
+                    chars.next();
+                }
+                while chars.peek() == Some(&' ') || chars.peek() == Some(&'\t') {
+                    chars.next();
+                }
+                let mut delimiter = String::new();
+                let mut quoted = false;
+                loop {
+                    match chars.peek().cloned() {
+                        Some(quote) if quote == '\'' || quote == '"' => {
+                            quoted = true;
+                            chars.next();
+                            while let Some(character) = chars.next() {
+                                if character == quote {
+                                    break;
+                                }
+                                delimiter.push(character);
+                            }
+                        },
+                        Some('\\') => {
+                            quoted = true;
+                            chars.next();
+                            if let Some(character) = chars.next() {
+                                delimiter.push(character);
+                            }
+                        },
+                        Some(character) if !" \t\r;&|<>()#".contains(character) => {
+                            delimiter.push(character);
+                            chars.next();
+                        },
+                        _ => break,
+                    }
+                }
+                if !delimiter.is_empty() {
+                    delimiters.push((delimiter, quoted, strip_tabs));
+                }
+            },
+            _ => (),
+        }
+    }
+    delimiters
+}
+
+/// Replaces the placeholders left by the grammar for here documents with their bodies.
+fn attach_here_documents<I: Iterator<Item = Word>>(statements: &mut [Statement], bodies: &mut I) {
+    for statement in statements.iter_mut() {
+        match *statement {
+            Statement::Pipeline(ref mut pipeline) => attach_pipeline_here_documents(pipeline, bodies),
+            Statement::If { ref mut test, ref mut success, ref mut failure } => {
+                if let Test::Pipeline(ref mut pipeline) = *test {
+                    attach_pipeline_here_documents(pipeline, bodies);
+                }
+                attach_here_documents(success, bodies);
+                attach_here_documents(failure, bodies);
+            },
+            Statement::While { ref mut test, ref mut body } => {
+                if let Test::Pipeline(ref mut pipeline) = *test {
+                    attach_pipeline_here_documents(pipeline, bodies);
+                }
+                attach_here_documents(body, bodies);
+            },
+            Statement::Group { ref mut body, ref mut redirections, .. } => {
+                attach_here_documents(body, bodies);
+                attach_redirection_here_documents(redirections, bodies);
+            },
+            Statement::Subshell { ref mut body, .. } |
+            Statement::For { ref mut body, .. } |
+            Statement::Function { ref mut body, .. } => {
+                attach_here_documents(body, bodies);
+            },
+            Statement::Assignment { .. } => (),
+        }
+    }
+}
+
+fn attach_pipeline_here_documents<I: Iterator<Item = Word>>(pipeline: &mut Pipeline, bodies: &mut I) {
+    for job in pipeline.jobs.iter_mut() {
+        attach_redirection_here_documents(&mut job.redirections, bodies);
+    }
+}
+
+fn attach_redirection_here_documents<I>(redirections: &mut [Redirection], bodies: &mut I)
+    where I: Iterator<Item = Word>
+{
+    for redirection in redirections.iter_mut() {
+        if let Target::HereDocument(ref mut body) = redirection.target {
+            if let Some(next) = bodies.next() {
+                *body = next;
+            }
+        }
+    }
+}
+
+/// Returns the character with the code written in the digits, which is used for escapes like
+/// `\x41` in ANSI-C quoted words. Codes that are not characters produce nothing.
+fn character_from_code(digits: &str, radix: u32) -> String {
+    u32::from_str_radix(digits, radix).ok()
+                                      .and_then(::std::char::from_u32)
+                                      .map_or(String::new(), |character| character.to_string())
+}
+
+peg! grammar(r#"
+use super::Statement;
+use super::Test;
+use super::Value;
+use super::Pipeline;
+use super::Condition;
+use super::Job;
+use super::Redirection;
+use super::Target;
+use super::Word;
+use super::WordSegment;
+use super::ParameterOperator;
+use super::Index;
+use super::Span;
+use super::character_from_code;
+use arithmetic::{Expression, Operator};
+
+
+#[pub]
+statements -> Vec<Statement>
+    = (unused* newline)* items:statement_item ++ statement_separator (job_ending unused*)* {
+        items.into_iter().flat_map(|item| item).collect()
+    }
+    / (unused*) ** newline { vec![] }
+
+#[pub]
+spanned_statements -> Vec<(Vec<Statement>, Span)>
+    = (unused* newline)* items:spanned_statement_item ++ statement_separator (job_ending unused*)* {
+        items
+    }
+    / (unused*) ** newline { vec![] }
+
+spanned_statement_item -> (Vec<Statement>, Span)
+    = item:statement_item { (item, Span::new(start_pos, pos)) }
+
+statement_item -> Vec<Statement>
+    = whitespace? statement:compound_statement whitespace? comment? { vec![statement] }
+    / whitespace? ("let" whitespace)? statement:assignment whitespace? comment? &(job_ending / !.) {
+        vec![statement]
+    }
+    / whitespace? condition:condition_keyword chain:statement_chain {
+        let mut chain = chain;
+        let first = chain.remove(0).with_condition(condition);
+        chain.insert(0, first);
+        chain
+    }
+    / !(whitespace? keyword) chain:statement_chain { chain }
+
+condition_keyword -> Condition
+    = "and" whitespace { Condition::OnSuccess }
+    / "or" whitespace { Condition::OnFailure }
+
+assignment -> Statement
+    = name:variable_identifier whitespace? "=" ![=] whitespace? value:assignment_value? {
+        Statement::Assignment { name: name, value: value.unwrap_or(Value::Word(Word::new(vec![]))) }
+    }
+
+assignment_value -> Value
+    = values:array_literal { Value::Array(values) }
+    / word:word { Value::Word(word) }
+
+array_literal -> Vec<Word>
+    = "[" values:array_element* whitespace? "]" array_end { values }
+
+array_element -> Word
+    = whitespace !("]" array_end) word:word { word }
+
+array_end -> ()
+    = &(whitespace / job_ending / !.)
+
+variable_identifier -> String
+    = [a-zA-Z_] [a-zA-Z0-9_]* { match_str.to_string() }
+
+statement_chain -> Vec<Statement>
+    = first:chain_element rest:conditional_element* {
+        let mut statements = vec![first];
+        statements.extend(rest);
+        statements
+    }
+
+chain_element -> Statement
+    = whitespace? statement:subshell whitespace? comment? { statement }
+    / whitespace? statement:group { statement }
+    / pipeline:pipeline { Statement::Pipeline(pipeline) }
+
+conditional_element -> Statement
+    = condition:condition element:chain_element { element.with_condition(condition) }
+
+group -> Statement
+    = "{" &(whitespace / job_ending) body:statements "}" redirections:redirection* whitespace? comment? {
+        Statement::Group {
+            body: body,
+            redirections: redirections.into_iter().flat_map(|redirection| redirection).collect(),
+            condition: Condition::Always,
+        }
+    }
+
+subshell -> Statement
+    = "(" body:statements ")" { Statement::Subshell { body: body, condition: Condition::Always } }
+
+statement_separator -> ()
+    = (job_ending+ unused*)+
+
+compound_statement -> Statement
+    = if_statement
+    / while_statement
+    / for_statement
+    / function_definition
+
+if_statement -> Statement
+    = "if" whitespace test:test success:block failure:else_block?
+      whitespace? ("fi" / "end") keyword_boundary {
+        Statement::If { test: test, success: success, failure: failure.unwrap_or(vec![]) }
+    }
+
+else_block -> Vec<Statement>
+    = whitespace? "else" whitespace "if" whitespace test:test success:block failure:else_block? {
+        vec![Statement::If { test: test, success: success, failure: failure.unwrap_or(vec![]) }]
+    }
+    / whitespace? "else" keyword_boundary whitespace? comment? body:block { body }
+
+while_statement -> Statement
+    = "while" whitespace test:test body:block whitespace? "end" keyword_boundary {
+        Statement::While { test: test, body: body }
+    }
+
+for_statement -> Statement
+    = "for" whitespace variable:identifier whitespace "in" keyword_boundary values:for_value*
+      whitespace? comment? body:block whitespace? "end" keyword_boundary {
+        Statement::For { variable: variable, values: values, body: body }
+    }
+
+for_value -> Word
+    = whitespace !comment word:word { word }
+
+function_definition -> Statement
+    = "fn" whitespace name:function_name args:function_argument* whitespace? comment? body:block
+      whitespace? "end" keyword_boundary {
+        Statement::Function { name: name, args: args, body: body }
+    }
+
+function_name -> String
+    = [^ \t\r\n;&|<>()#$`"'\\{}]+ { match_str.to_string() }
+
+function_argument -> String
+    = whitespace !comment name:identifier { name }
+
+identifier -> String
+    = [a-zA-Z0-9_]+ { match_str.to_string() }
+
+test -> Test
+    = comparison
+    / pipeline:pipeline { Test::Pipeline(pipeline) }
+
+comparison -> Test
+    = whitespace? left:word whitespace operator:comparison_operator whitespace right:word
+      whitespace? comment? &(job_ending / !.) {
+        Test::Comparison(left, operator, right)
+    }
+
+comparison_operator -> String
+    = ("==" / "!=" / ">=" / "<=" / ">" / "<") { match_str.to_string() }
+
+block -> Vec<Statement>
+    = statement_separator items:block_item* { items.into_iter().flat_map(|item| item).collect() }
+
+block_item -> Vec<Statement>
+    = item:statement_item statement_separator { item }
+
+block_end -> ()
+    = ("else" / "fi" / "end") keyword_boundary
+
+keyword -> ()
+    = ("if" / "while" / "for" / "fn") whitespace
+    / block_end
+    / "}"
+
+keyword_boundary -> ()
+    = ![^ \t\r\n;&|<>()]
+
+#[pub]
+pipelines -> Vec<Pipeline>
+    = (unused* newline)* chains:chain ++ ((job_ending+ unused*)+) (job_ending unused*)* {
+        chains.into_iter().flat_map(|chain| chain).collect()
+    }
+    / (unused*) ** newline { vec![] }
+
+chain -> Vec<Pipeline>
+    = first:pipeline rest:conditional_pipeline* {
+        let mut pipelines = vec![first];
+        pipelines.extend(rest);
+        pipelines
+    }
+
+conditional_pipeline -> Pipeline
+    = condition:condition pipeline:pipeline {
+        let mut pipeline = pipeline;
+        pipeline.condition = condition;
+        pipeline
+    }
+
+condition -> Condition
+    = whitespace? "&&" continuation { Condition::OnSuccess }
+    / whitespace? "||" continuation { Condition::OnFailure }
+
+continuation -> ()
+    = (unused / newline)*
+
+pipeline -> Pipeline
+    = whitespace? timed:time_keyword? negation:negation? res:job ++ pipeline_sep whitespace? comment? {
+        let mut pipeline = Pipeline::new(res, Condition::Always);
+        pipeline.negated = negation.is_some();
+        pipeline.timed = timed.is_some();
+        pipeline
+    }
+
+time_keyword -> ()
+    = "time" whitespace
+
+negation -> ()
+    = [!] whitespace
+    / "not" whitespace
+
+job -> Job
+    = env:environment_assignment* args:arg ++ whitespace redirections:redirection* background:background_token? {
+        Job::new(args,
+                 redirections.into_iter().flat_map(|redirection| redirection).collect(),
+                 background.is_some())
+            .with_env(env)
+            .with_span(Span::new(start_pos, pos))
+    }
+
+environment_assignment -> (String, Word)
+    = name:variable_identifier "=" value:word? whitespace {
+        (name, value.unwrap_or(Word::new(vec![])))
+    }
+
+arg -> Word
+    = !comment !([0-9]+ [<>]) word:word { word }
+
+redirection -> Vec<Redirection>
+    = whitespace? "&>" whitespace? file:word {
+        vec![Redirection::new(1, Target::Output(file)),
+             Redirection::new(2, Target::Duplicate(1))]
+    }
+    / whitespace? source:fd? ">&" [-] {
+        vec![Redirection::new(source.unwrap_or(1), Target::Close)]
+    }
+    / whitespace? source:fd? ">&" destination:fd {
+        vec![Redirection::new(source.unwrap_or(1), Target::Duplicate(destination))]
+    }
+    / whitespace? source:fd? "<&" [-] {
+        vec![Redirection::new(source.unwrap_or(0), Target::Close)]
+    }
+    / whitespace? source:fd? "<&" destination:fd {
+        vec![Redirection::new(source.unwrap_or(0), Target::Duplicate(destination))]
+    }
+    / whitespace? source:fd? ">>" whitespace? file:word {
+        vec![Redirection::new(source.unwrap_or(1), Target::Append(file))]
+    }
+    / whitespace? source:fd? ">|" whitespace? file:word {
+        vec![Redirection::new(source.unwrap_or(1), Target::Clobber(file))]
+    }
+    / whitespace? source:fd? [>] whitespace? file:word {
+        vec![Redirection::new(source.unwrap_or(1), Target::Output(file))]
+    }
+    / whitespace? source:fd? "<<<" whitespace? string:word {
+        vec![Redirection::new(source.unwrap_or(0), Target::HereString(string))]
+    }
+    / whitespace? source:fd? "<<" [-]? whitespace? word {
+        vec![Redirection::new(source.unwrap_or(0), Target::HereDocument(Word::new(vec![])))]
+    }
+    / whitespace? source:fd? [<] whitespace? file:word {
+        vec![Redirection::new(source.unwrap_or(0), Target::Input(file))]
+    }
+
+fd -> i32
+    = [0-9]+ { match_str.parse().unwrap_or(::std::i32::MAX) }
+
+pipeline_sep -> ()
+    = (whitespace? [|] ![|] continuation) { }
+
+background_token -> ()
+    = whitespace? &background_operator
+
+background_operator -> ()
+    = [&] ![&>]
+
+word -> Word
+    = word:unspanned_word { word.with_span(Span::new(start_pos, pos)) }
+
+unspanned_word -> Word
+    = array:array_expansion &([ \t\r\n;&|<>()] / !.) { Word::new(vec![array]) }
+    / parts:word_part+ { Word::new(parts.into_iter().flat_map(|part| part).collect()) }
+
+array_expansion -> WordSegment
+    = [@] name:variable_identifier index:array_index? { WordSegment::Array(name, index) }
+
+array_index -> Index
+    = "[" start:integer? ".." end:integer? "]" { Index::Range(start, end) }
+    / "[" index:integer "]" { Index::Single(index) }
+
+word_part -> Vec<WordSegment>
+    = word:ansi_c_quoted_word { word.segments }
+    / word:double_quoted_word { word.segments }
+    / word:single_quoted_word { word.segments }
+    / part:bare_part { vec![part] }
+
+bare_part -> WordSegment
+    = line_continuation { WordSegment::Literal(String::new()) }
+    / arithmetic_expansion
+    / inner:substituted_pipelines { WordSegment::CommandSubstitution(inner, true) }
+    / process_substitution
+    / method
+    / variable
+    / [\\] [^\r\n] { WordSegment::Literal(match_str[1..].to_string()) }
+    / [^ \t\r\n;&|<>()\\$`{"']+ { WordSegment::unquoted(match_str) }
+    / range_expansion
+    / brace_expansion
+    / [${] { WordSegment::Literal(match_str.to_string()) }
+
+brace_expansion -> WordSegment
+    = "{" first:brace_alternative "," rest:brace_alternative ++ "," "}" {
+        let mut alternatives = vec![first];
+        alternatives.extend(rest);
+        WordSegment::Brace(alternatives)
+    }
+
+range_expansion -> WordSegment
+    = "{" start:integer ".." end:integer step:range_step? "}" {
+        WordSegment::Range(start, end, step.unwrap_or(1))
+    }
+
+range_step -> i64
+    = ".." step:integer { step }
+
+integer -> i64
+    = [-+]? [0-9]+ { match_str.parse().unwrap_or(0) }
+
+brace_alternative -> Word
+    = parts:brace_word_part* { Word::new(parts.into_iter().flat_map(|part| part).collect()) }
+
+brace_word_part -> Vec<WordSegment>
+    = word:ansi_c_quoted_word { word.segments }
+    / word:double_quoted_word { word.segments }
+    / word:single_quoted_word { word.segments }
+    / part:brace_part { vec![part] }
+
+brace_part -> WordSegment
+    = line_continuation { WordSegment::Literal(String::new()) }
+    / arithmetic_expansion
+    / command_substitution
+    / variable
+    / [\\] [^\r\n] { WordSegment::Literal(match_str[1..].to_string()) }
+    / [^ \t\r\n;&|<>()\\$`{},"']+ { WordSegment::unquoted(match_str) }
+    / range_expansion
+    / brace_expansion
+    / [$] { WordSegment::Literal(match_str.to_string()) }
+
+variable -> WordSegment
+    = "${" name:variable_name "}" { WordSegment::Variable(name) }
+    / "${" name:variable_name operator:parameter_operator word:parameter_word "}" {
+        WordSegment::Parameter(name, operator, word)
+    }
+    / [$] name:variable_name { WordSegment::Variable(name) }
+
+parameter_operator -> ParameterOperator
+    = ":-" { ParameterOperator::Default }
+    / ":=" { ParameterOperator::Assign }
+    / ":+" { ParameterOperator::Alternative }
+    / ":?" { ParameterOperator::Error }
+
+parameter_word -> Word
+    = parts:parameter_part* { Word::new(parts.into_iter().flat_map(|part| part).collect()) }
+
+parameter_part -> Vec<WordSegment>
+    = word:ansi_c_quoted_word { word.segments }
+    / word:double_quoted_word { word.segments }
+    / word:single_quoted_word { word.segments }
+    / part:parameter_bare_part { vec![part] }
+
+parameter_bare_part -> WordSegment
+    = line_continuation { WordSegment::Literal(String::new()) }
+    / arithmetic_expansion
+    / command_substitution
+    / variable
+    / [\\] [^\r\n] { WordSegment::Literal(match_str[1..].to_string()) }
+    / [^}\\$`"']+ { WordSegment::Literal(match_str.to_string()) }
+    / [$] { WordSegment::Literal(match_str.to_string()) }
+
+method -> WordSegment
+    = [$] method:method_name "(" whitespace? variable:variable_identifier args:method_argument*
+      whitespace? ")" {
+        WordSegment::Method(method, variable, args)
+    }
+
+method_name -> String
+    = [a-z_]+ { match_str.to_string() }
+
+method_argument -> Word
+    = whitespace? "," whitespace? word:method_word { word }
+
+method_word -> Word
+    = parts:method_word_part+ { Word::new(parts.into_iter().flat_map(|part| part).collect()) }
+
+method_word_part -> Vec<WordSegment>
+    = word:ansi_c_quoted_word { word.segments }
+    / word:double_quoted_word { word.segments }
+    / word:single_quoted_word { word.segments }
+    / part:arithmetic_expansion { vec![part] }
+    / part:variable { vec![part] }
+    / [\\] [^\r\n] { vec![WordSegment::Literal(match_str[1..].to_string())] }
+    / [^ \t\r\n,()\\$`"']+ { vec![WordSegment::Literal(match_str.to_string())] }
+
+arithmetic_expansion -> WordSegment
+    = "$((" arithmetic_space expression:arithmetic_sum arithmetic_space "))" {
+        WordSegment::Arithmetic(expression)
+    }
+
+arithmetic_sum -> Expression
+    = first:arithmetic_product rest:arithmetic_sum_tail* { Expression::fold(first, rest) }
+
+arithmetic_sum_tail -> (Operator, Expression)
+    = arithmetic_space operator:arithmetic_sum_operator arithmetic_space operand:arithmetic_product {
+        (operator, operand)
+    }
+
+arithmetic_sum_operator -> Operator
+    = "+" { Operator::Add }
+    / "-" { Operator::Subtract }
+
+arithmetic_product -> Expression
+    = first:arithmetic_unary rest:arithmetic_product_tail* { Expression::fold(first, rest) }
+
+arithmetic_product_tail -> (Operator, Expression)
+    = arithmetic_space operator:arithmetic_product_operator arithmetic_space operand:arithmetic_unary {
+        (operator, operand)
+    }
+
+arithmetic_product_operator -> Operator
+    = "*" { Operator::Multiply }
+    / "/" { Operator::Divide }
+    / "%" { Operator::Remainder }
+
+arithmetic_unary -> Expression
+    = "-" arithmetic_space operand:arithmetic_unary { Expression::Negate(Box::new(operand)) }
+    / "+" arithmetic_space operand:arithmetic_unary { operand }
+    / arithmetic_primary
+
+arithmetic_primary -> Expression
+    = [0-9]+ { Expression::Number(match_str.parse().unwrap_or(::std::i64::MAX)) }
+    / [$]? name:arithmetic_variable { Expression::Variable(name) }
+    / "(" arithmetic_space expression:arithmetic_sum arithmetic_space ")" { expression }
+
+arithmetic_variable -> String
+    = [a-zA-Z_] [a-zA-Z0-9_]* { match_str.to_string() }
+
+arithmetic_space
+    = [ \t\r\n]*
+
+process_substitution -> WordSegment
+    = "<(" inner:pipelines ")" { WordSegment::InputSubstitution(inner) }
+    / ">(" inner:pipelines ")" { WordSegment::OutputSubstitution(inner) }
+
+command_substitution -> WordSegment
+    = inner:substituted_pipelines { WordSegment::CommandSubstitution(inner, false) }
+
+substituted_pipelines -> Vec<Pipeline>
+    = "$(" inner:pipelines ")" { inner }
+    / "`" inner:pipelines "`" { inner }
+
+variable_name -> String
+    = ([a-zA-Z0-9_]+ / [?!]) { match_str.to_string() }
+
+double_quoted_word -> Word
+    = ["] parts:double_quoted_part* ["] { Word::new(parts) }
+
+double_quoted_part -> WordSegment
+    = [\\] escaped:double_quoted_escape { WordSegment::Literal(escaped) }
+    / arithmetic_expansion
+    / command_substitution
+    / method
+    / variable
+    / [^"\\$`]+ { WordSegment::Literal(match_str.to_string()) }
+    / [$] { WordSegment::Literal(match_str.to_string()) }
+
+double_quoted_escape -> String
+    = ("\r\n" / newline) { String::new() }
+    / [n] { "\n".to_string() }
+    / [t] { "\t".to_string() }
+    / [r] { "\r".to_string() }
+    / ["\\$`] { match_str.to_string() }
+    / . { format!("\\{}", match_str) }
+
+#[pub]
+here_document_body -> Word
+    = parts:here_document_part* { Word::new(parts) }
+
+here_document_part -> WordSegment
+    = [\\] [\\$`] { WordSegment::Literal(match_str[1..].to_string()) }
+    / arithmetic_expansion
+    / command_substitution
+    / variable
+    / [^\\$`]+ { WordSegment::Literal(match_str.to_string()) }
+    / [\\$] { WordSegment::Literal(match_str.to_string()) }
+
+ansi_c_quoted_word -> Word
+    = "$'" parts:ansi_c_quoted_part* "'" { Word::literal(&parts.concat()) }
+
+ansi_c_quoted_part -> String
+    = [\\] escaped:ansi_c_escape { escaped }
+    / [^'\\]+ { match_str.to_string() }
+
+ansi_c_escape -> String
+    = [n] { "\n".to_string() }
+    / [t] { "\t".to_string() }
+    / [r] { "\r".to_string() }
+    / [a] { "\x07".to_string() }
+    / [b] { "\x08".to_string() }
+    / [eE] { "\x1b".to_string() }
+    / [f] { "\x0c".to_string() }
+    / [v] { "\x0b".to_string() }
+    / [x] digits:hex_digits { character_from_code(digits, 16) }
+    / [u] digits:unicode_digits { character_from_code(digits, 16) }
+    / [U] digits:long_unicode_digits { character_from_code(digits, 16) }
+    / [0-7] [0-7]? [0-7]? { character_from_code(match_str, 8) }
+    / ['"\\?] { match_str.to_string() }
+    / . { format!("\\{}", match_str) }
+
+hex_digits -> &'input str
+    = hex_digit hex_digit? { match_str }
+
+unicode_digits -> &'input str
+    = hex_digit hex_digit? hex_digit? hex_digit? { match_str }
+
+long_unicode_digits -> &'input str
+    = hex_digit hex_digit? hex_digit? hex_digit? hex_digit? hex_digit? hex_digit? hex_digit? {
+        match_str
+    }
+
+hex_digit
+    = [0-9a-fA-F]
+
+single_quoted_word -> Word
+    = ['] word:_single_quoted_word ['] { Word::literal(word) }
+
+_single_quoted_word -> &'input str
+    = [^']* { match_str }
+
+unused -> ()
+    = whitespace comment? { () }
+    / comment { () }
+
+comment -> ()
+    = [#] [^\r\n]*
+
+whitespace -> ()
+    = ([ \t] / line_continuation)+
+
+line_continuation -> ()
+    = [\\] ("\r\n" / newline)
+
+job_ending -> ()
+    = [;]
+    / background_operator
+    / newline
+
+newline -> ()
+    = [\r\n]
+"#);
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arithmetic::{Expression, Operator};
+    use super::grammar::*;
+
+    #[test]
+    fn single_job_no_args() {
+        let jobs = parse("cat").unwrap().remove(0).jobs;
+        assert_eq!(1, jobs.len());
+        assert_eq!("cat", jobs[0].command);
+        assert_eq!(1, jobs[0].args.len());
+    }
+
+    #[test]
+    fn single_job_with_args() {
+        let jobs = parse("ls -al dir").unwrap().remove(0).jobs;
+        assert_eq!(1, jobs.len());
+        assert_eq!("ls", jobs[0].command);
+        assert_eq!("-al", jobs[0].args[1]);
+        assert_eq!("dir", jobs[0].args[2]);
+    }
+
+    #[test]
+    fn multiple_jobs_with_args() {
+        let pipelines = parse("ls -al;cat tmp.txt").unwrap();
+        assert_eq!(2, pipelines.len());
+        assert_eq!("ls", pipelines[0].jobs[0].command);
+        assert_eq!("-al", pipelines[0].jobs[0].args[1]);
+        assert_eq!("cat", pipelines[1].jobs[0].command);
+        assert_eq!("tmp.txt", pipelines[1].jobs[0].args[1]);
+    }
+
+    #[test]
+    fn parse_empty_string() {
+        let pipelines = parse("").unwrap();
+        assert_eq!(0, pipelines.len());
+    }
+
+    #[test]
+    fn multiple_white_space_between_words() {
+        let jobs = parse("ls \t -al\t\tdir").unwrap().remove(0).jobs;
+        assert_eq!(1, jobs.len());
+        assert_eq!("ls", jobs[0].command);
+        assert_eq!("-al", jobs[0].args[1]);
+        assert_eq!("dir", jobs[0].args[2]);
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        let pipelines = parse("ls -al\t ").unwrap();
+        assert_eq!(1, pipelines.len());
+        assert_eq!("ls", pipelines[0].jobs[0].command);
+        assert_eq!("-al", pipelines[0].jobs[0].args[1]);
+    }
+
+    #[test]
+    fn double_quoting() {
+        let jobs = parse("echo \"Hello World\"").unwrap().remove(0).jobs;
+        assert_eq!(2, jobs[0].args.len());
+        assert_eq!("Hello World", jobs[0].args[1]);
+    }
+
+    #[test]
+    fn escaped_quotes_in_double_quotes() {
+        let jobs = parse(r#"echo "she said \"hi\"""#).unwrap().remove(0).jobs;
+        assert_eq!(2, jobs[0].args.len());
+        assert_eq!("she said \"hi\"", jobs[0].args[1]);
+    }
+
+    #[test]
+    fn escape_sequences_in_double_quotes() {
+        let jobs = parse(r#"echo "a\\b" "tab\there" "line\nbreak""#).unwrap().remove(0).jobs;
+        assert_eq!("a\\b", jobs[0].args[1]);
+        assert_eq!("tab\there", jobs[0].args[2]);
+        assert_eq!("line\nbreak", jobs[0].args[3]);
+    }
+
+    #[test]
+    fn unknown_escape_in_double_quotes_is_kept() {
+        let jobs = parse(r#"echo "\q""#).unwrap().remove(0).jobs;
+        assert_eq!("\\q", jobs[0].args[1]);
+    }
+
+    #[test]
+    fn escaped_whitespace_in_unquoted_word() {
+        let jobs = parse(r"cat my\ file.txt").unwrap().remove(0).jobs;
+        assert_eq!(2, jobs[0].args.len());
+        assert_eq!("my file.txt", jobs[0].args[1]);
+    }
+
+    #[test]
+    fn escaped_metacharacters_in_unquoted_word() {
+        let pipelines = parse(r"echo a\;b \| \& \\").unwrap();
+        assert_eq!(1, pipelines.len());
+        let jobs = &pipelines[0].jobs;
+        assert_eq!(1, jobs.len());
+        assert_eq!("a;b", jobs[0].args[1]);
+        assert_eq!("|", jobs[0].args[2]);
+        assert_eq!("&", jobs[0].args[3]);
+        assert_eq!("\\", jobs[0].args[4]);
+    }
+
+    #[test]
+    fn line_continuation() {
+        let pipelines = parse("echo foo \\\nbar").unwrap();
+        assert_eq!(1, pipelines.len());
+        let jobs = &pipelines[0].jobs;
+        assert_eq!(3, jobs[0].args.len());
+        assert_eq!("foo", jobs[0].args[1]);
+        assert_eq!("bar", jobs[0].args[2]);
+    }
+
+    #[test]
+    fn line_continuation_before_pipe() {
+        let pipelines = parse("cat foo \\\n  | wc -l").unwrap();
+        assert_eq!(1, pipelines.len());
+        assert_eq!(2, pipelines[0].jobs.len());
+        assert_eq!("wc", pipelines[0].jobs[1].command);
+    }
+
+    #[test]
+    fn line_continuation_inside_words() {
+        let jobs = parse("echo fo\\\no \"ba\\\nr\"").unwrap().remove(0).jobs;
+        assert_eq!(3, jobs[0].args.len());
+        assert_eq!("foo", jobs[0].args[1]);
+        assert_eq!("bar", jobs[0].args[2]);
+    }
+
+    #[test]
+    fn variable_segments() {
+        let jobs = parse("echo $HOME \"a $B c\" '$X' \\$Y").unwrap().remove(0).jobs;
+        assert_eq!(Word::new(vec![WordSegment::Variable("HOME".to_string())]), jobs[0].words[1]);
+        assert_eq!(Word::new(vec![WordSegment::Literal("a ".to_string()),
+                                  WordSegment::Variable("B".to_string()),
+                                  WordSegment::Literal(" c".to_string())]),
+                   jobs[0].words[2]);
+        assert_eq!(Word::literal("$X"), jobs[0].words[3]);
+        assert_eq!(Word::literal("$Y"), jobs[0].words[4]);
+        assert_eq!("$HOME", jobs[0].args[1]);
+    }
+
+    #[test]
+    fn variable_adjacent_to_text() {
+        let jobs = parse("echo pre$SUFFIX-$? $ $!").unwrap().remove(0).jobs;
+        assert_eq!(Word::new(vec![WordSegment::Literal("pre".to_string()),
+                                  WordSegment::Variable("SUFFIX".to_string()),
+                                  WordSegment::Literal("-".to_string()),
+                                  WordSegment::Variable("?".to_string())]),
+                   jobs[0].words[1]);
+        assert_eq!(Word::literal("$"), jobs[0].words[2]);
+        assert_eq!(Word::new(vec![WordSegment::Variable("!".to_string())]), jobs[0].words[3]);
+    }
+
+    #[test]
+    fn command_substitution() {
+        let jobs = parse("echo $(ls -l | wc -l) files").unwrap().remove(0).jobs;
+        assert_eq!(3, jobs[0].args.len());
+        match jobs[0].words[1].segments[0] {
+            WordSegment::CommandSubstitution(ref pipelines, true) => {
+                assert_eq!(1, pipelines.len());
+                assert_eq!(2, pipelines[0].jobs.len());
+                assert_eq!("ls", pipelines[0].jobs[0].command);
+                assert_eq!("-l", pipelines[0].jobs[1].args[1]);
+            },
+            ref segment => panic!("expected a command substitution, found {:?}", segment),
+        }
+        assert_eq!("files", jobs[0].args[2]);
+    }
+
+    #[test]
+    fn command_substitution_in_double_quotes() {
+        let jobs = parse("echo \"today is $( date +%A )!\"").unwrap().remove(0).jobs;
+        let segments = &jobs[0].words[1].segments;
+        assert_eq!(3, segments.len());
+        assert_eq!(WordSegment::Literal("today is ".to_string()), segments[0]);
+        match segments[1] {
+            WordSegment::CommandSubstitution(ref pipelines, false) => {
+                assert_eq!("+%A", pipelines[0].jobs[0].args[1]);
+            },
+            ref segment => panic!("expected a command substitution, found {:?}", segment),
+        }
+        assert_eq!(WordSegment::Literal("!".to_string()), segments[2]);
+    }
+
+    #[test]
+    fn nested_command_substitution() {
+        let jobs = parse("echo $(dirname \"$(which cargo)\")").unwrap().remove(0).jobs;
+        let inner = match jobs[0].words[1].segments[0] {
+            WordSegment::CommandSubstitution(ref pipelines, true) => pipelines[0].jobs[0].clone(),
+            ref segment => panic!("expected a command substitution, found {:?}", segment),
+        };
+        assert_eq!("dirname", inner.command);
+        match inner.words[1].segments[0] {
+            WordSegment::CommandSubstitution(ref pipelines, false) => {
+                assert_eq!(vec!["which", "cargo"], pipelines[0].jobs[0].args);
+            },
+            ref segment => panic!("expected a command substitution, found {:?}", segment),
+        }
+    }
+
+    #[test]
+    fn backtick_command_substitution() {
+        let jobs = parse("echo `which cargo` \"in `pwd`\"").unwrap().remove(0).jobs;
+        assert_eq!(3, jobs[0].args.len());
+        match jobs[0].words[1].segments[0] {
+            WordSegment::CommandSubstitution(ref pipelines, true) => {
+                assert_eq!("which", pipelines[0].jobs[0].command);
+                assert_eq!("cargo", pipelines[0].jobs[0].args[1]);
+            },
+            ref segment => panic!("expected a command substitution, found {:?}", segment),
+        }
+        assert_eq!(parse("echo \"in $(pwd)\"").unwrap().remove(0).jobs[0].words[1], jobs[0].words[2]);
+    }
+
+    #[test]
+    fn escaped_backtick() {
+        let jobs = parse("echo \\` \"\\`\"").unwrap().remove(0).jobs;
+        assert_eq!("`", jobs[0].args[1]);
+        assert_eq!("`", jobs[0].args[2]);
+    }
+
+    #[test]
+    fn unquoted_glob_patterns() {
+        let jobs = parse("ls src/*.rs \"*.rs\" '?' \\[a] ma[ai]n.rs").unwrap().remove(0).jobs;
+        assert_eq!(Word::new(vec![WordSegment::Pattern("src/*.rs".to_string())]), jobs[0].words[1]);
+        assert_eq!(Word::literal("*.rs"), jobs[0].words[2]);
+        assert_eq!(Word::literal("?"), jobs[0].words[3]);
+        assert_eq!(Word::literal("[a]"), jobs[0].words[4]);
+        assert_eq!(Word::new(vec![WordSegment::Pattern("ma[ai]n.rs".to_string())]), jobs[0].words[5]);
+    }
+
+    #[test]
+    fn brace_expansion() {
+        let jobs = parse("mv file.{old,new}").unwrap().remove(0).jobs;
+        assert_eq!(2, jobs[0].words.len());
+        assert_eq!(Word::new(vec![WordSegment::Literal("file.".to_string()),
+                                  WordSegment::Brace(vec![Word::literal("old"),
+                                                          Word::literal("new")])]),
+                   jobs[0].words[1]);
+    }
+
+    #[test]
+    fn nested_brace_expansion() {
+        let jobs = parse("echo a{b,c{d,}}e").unwrap().remove(0).jobs;
+        let nested = WordSegment::Brace(vec![Word::literal("d"), Word::literal("")]);
+        assert_eq!(Word::new(vec![WordSegment::Literal("a".to_string()),
+                                  WordSegment::Brace(vec![Word::literal("b"),
+                                                          Word::new(vec![WordSegment::Literal("c".to_string()),
+                                                                         nested])]),
+                                  WordSegment::Literal("e".to_string())]),
+                   jobs[0].words[1]);
+    }
+
+    #[test]
+    fn range_expansion() {
+        let jobs = parse("echo {1..10} x{10..-2..3} {a..b}").unwrap().remove(0).jobs;
+        assert_eq!(Word::new(vec![WordSegment::Range(1, 10, 1)]), jobs[0].words[1]);
+        assert_eq!(Word::new(vec![WordSegment::Literal("x".to_string()),
+                                  WordSegment::Range(10, -2, 3)]),
+                   jobs[0].words[2]);
+        assert_eq!(Word::literal("{a..b}"), jobs[0].words[3]);
+    }
+
+    #[test]
+    fn arithmetic_expansion() {
+        let jobs = parse("echo $((1 + x)) \"$(( (2) ))\" $(echo)").unwrap().remove(0).jobs;
+        let sum = Expression::Binary(Operator::Add,
+                                     Box::new(Expression::Number(1)),
+                                     Box::new(Expression::Variable("x".to_string())));
+        assert_eq!(Word::new(vec![WordSegment::Arithmetic(sum)]), jobs[0].words[1]);
+        assert_eq!(Word::new(vec![WordSegment::Arithmetic(Expression::Number(2))]),
+                   jobs[0].words[2]);
+        assert_eq!("$(echo)", jobs[0].words[3].to_string());
+    }
+
+    #[test]
+    fn process_substitution() {
+        let jobs = parse("diff <(ls a) <(ls b | sort) > out").unwrap().remove(0).jobs;
+        assert_eq!(3, jobs[0].words.len());
+        match jobs[0].words[2].segments[0] {
+            WordSegment::InputSubstitution(ref pipelines) => {
+                assert_eq!(1, pipelines.len());
+                assert_eq!(2, pipelines[0].jobs.len());
+                assert_eq!(vec!["ls".to_string(), "b".to_string()], pipelines[0].jobs[0].args);
+            },
+            ref segment => panic!("expected a process substitution, found {:?}", segment),
+        }
+        assert_eq!("<(ls a)", jobs[0].args[1]);
+        assert_eq!(vec![Redirection::new(1, Target::Output(Word::literal("out")))],
+                   jobs[0].redirections);
+    }
+
+    #[test]
+    fn output_process_substitution() {
+        let jobs = parse("tee >(wc -l) >log").unwrap().remove(0).jobs;
+        assert_eq!(">(wc -l)", jobs[0].args[1]);
+        assert_eq!(vec![Redirection::new(1, Target::Output(Word::literal("log")))],
+                   jobs[0].redirections);
+    }
+
+    #[test]
+    fn escaped_quotes_inside_quoted_words() {
+        let jobs = parse(r#"echo 'it'\''s' "say \"hi\"" "it's" 'say "hi"' \"\'"#).unwrap().remove(0).jobs;
+        assert_eq!(vec!["echo", "it's", "say \"hi\"", "it's", "say \"hi\"", "\"'"],
+                   jobs[0].args);
+        assert_eq!(Word::literal("it's"), jobs[0].words[1]);
+    }
+
+    #[test]
+    fn ansi_c_quoting() {
+        let jobs = parse(r"printf $'a\n\tb\x41\101\u00e9\'\\\q' pre$'\e'post $'' '$\n'").unwrap().remove(0).jobs;
+        assert_eq!(vec!["printf", "a\n\tbAA\u{e9}'\\\\q", "pre\x1bpost", "", "$\\n"], jobs[0].args);
+        assert_eq!(Word::literal("a\n\tbAA\u{e9}'\\\\q"), jobs[0].words[1]);
+    }
+
+    #[test]
+    fn empty_quoted_words() {
+        let jobs = parse("program \"\" '' a\"\"b '' ").unwrap().remove(0).jobs;
+        assert_eq!(vec!["program".to_string(), String::new(), String::new(), "ab".to_string(),
+                        String::new()],
+                   jobs[0].args);
+        assert_eq!(Word::new(vec![]), jobs[0].words[1]);
+    }
+
+    #[test]
+    fn adjacent_quoted_and_unquoted_segments() {
+        let jobs = parse("echo foo\"bar baz\"qux 'a'\"b\"c$D *'*' {x,\"y z\"}").unwrap().remove(0).jobs;
+        assert_eq!(5, jobs[0].args.len());
+        assert_eq!("foobar bazqux", jobs[0].args[1]);
+        assert_eq!(Word::new(vec![WordSegment::Literal("abc".to_string()),
+                                  WordSegment::Variable("D".to_string())]),
+                   jobs[0].words[2]);
+        assert_eq!(Word::new(vec![WordSegment::Pattern("*".to_string()),
+                                  WordSegment::Literal("*".to_string())]),
+                   jobs[0].words[3]);
+        assert_eq!(Word::new(vec![WordSegment::Brace(vec![Word::literal("x"), Word::literal("y z")])]),
+                   jobs[0].words[4]);
+    }
+
+    #[test]
+    fn braces_without_alternatives_are_literal() {
+        let jobs = parse("find . -exec rm {} \\; {a} \"{b,c}\"").unwrap().remove(0).jobs;
+        assert_eq!(Word::literal("{}"), jobs[0].words[4]);
+        assert_eq!(Word::literal(";"), jobs[0].words[5]);
+        assert_eq!(Word::literal("{a}"), jobs[0].words[6]);
+        assert_eq!(Word::literal("{b,c}"), jobs[0].words[7]);
+    }
+
+    #[test]
+    fn all_whitespace() {
+        let pipelines = parse("  \t ").unwrap();
+        assert_eq!(0, pipelines.len());
+    }
+
+    #[test]
+    fn not_background_job() {
+        let jobs = parse("echo hello world").unwrap().remove(0).jobs;
+        assert_eq!(false, jobs[0].background);
+    }
+
+    #[test]
+    fn background_job() {
+        let jobs = parse("echo hello world&").unwrap().remove(0).jobs;
+        assert_eq!(true, jobs[0].background);
+    }
+
+    #[test]
+    fn background_job_with_space() {
+        let jobs = parse("echo hello world &").unwrap().remove(0).jobs;
+        assert_eq!(true, jobs[0].background);
+    }
+
+    #[test]
+    fn background_job_followed_by_another_job() {
+        let pipelines = parse("sleep 10 & echo hi").unwrap();
+        assert_eq!(2, pipelines.len());
+        assert_eq!(true, pipelines[0].jobs[0].background);
+        assert_eq!("10", pipelines[0].jobs[0].args[1]);
+        assert_eq!(false, pipelines[1].jobs[0].background);
+        assert_eq!("echo", pipelines[1].jobs[0].command);
+    }
+
+    #[test]
+    fn trailing_job_ending() {
+        let pipelines = parse("ls;").unwrap();
+        assert_eq!(1, pipelines.len());
+        assert_eq!(false, pipelines[0].jobs[0].background);
+    }
+
+    #[test]
+    fn and_operator_is_not_background() {
+        let pipelines = parse("sleep 10&&echo hi").unwrap();
+        assert_eq!(2, pipelines.len());
+        assert_eq!(false, pipelines[0].jobs[0].background);
+        assert_eq!(false, pipelines[1].jobs[0].background);
+    }
+
+    #[test]
+    fn lone_comment() {
+        let pipelines = parse("# ; \t as!!+dfa").unwrap();
+        assert_eq!(0, pipelines.len());
+    }
+
+    #[test]
+    fn command_followed_by_comment() {
+        let pipelines = parse("cat # ; \t as!!+dfa").unwrap();
+        assert_eq!(1, pipelines.len());
+        assert_eq!(1, pipelines[0].jobs[0].args.len());
+    }
+
+    #[test]
+    fn comments_in_multiline_script() {
+        let pipelines = parse("echo\n# a comment;\necho#asfasdf").unwrap();
+        assert_eq!(2, pipelines.len());
+    }
+
+    #[test]
+    fn comments_around_jobs() {
+        let pipelines = parse("# before\n  # indented\nls -a # after ls\n\n# between\ncat a#b; wc # end\n# last")
+                            .unwrap();
+        assert_eq!(3, pipelines.len());
+        assert_eq!(vec!["ls", "-a"], pipelines[0].jobs[0].args);
+        assert_eq!(vec!["cat", "a#b"], pipelines[1].jobs[0].args);
+        assert_eq!(vec!["wc"], pipelines[2].jobs[0].args);
+    }
+
+    #[test]
+    fn hash_inside_words_is_not_a_comment() {
+        let jobs = parse("echo#not a comment").unwrap().remove(0).jobs;
+        assert_eq!(vec!["echo#not", "a", "comment"], jobs[0].args);
+        let jobs = parse("cat <<EOF # a comment\nbody\nEOF").unwrap().remove(0).jobs;
+        assert_eq!(Target::HereDocument(Word::literal("body\n")), jobs[0].redirections[0].target);
+    }
+
+    #[test]
+    fn multiple_newlines() {
+        let pipelines = parse("echo\n\ncat").unwrap();
+        assert_eq!(2, pipelines.len());
+    }
+
+    #[test]
+    fn leading_whitespace() {
+        let jobs = parse("    \techo").unwrap().remove(0).jobs;
+        assert_eq!(1, jobs.len());
+        assert_eq!("echo", jobs[0].command);
+    }
+
+    #[test]
+    fn indentation_on_multiple_lines() {
+        let pipelines = parse("echo\n  cat").unwrap();
+        assert_eq!(2, pipelines.len());
+        assert_eq!("echo", pipelines[0].jobs[0].command);
+        assert_eq!("cat", pipelines[1].jobs[0].command);
+    }
+
+    #[test]
+    fn single_quoting() {
+        let jobs = parse("echo '#!!;\"\\'").unwrap().remove(0).jobs;
+        assert_eq!("#!!;\"\\", jobs[0].args[1]);
+    }
+
+    #[test]
+    fn mixed_quoted_and_unquoted() {
+        let jobs = parse("echo '#!!;\"\\' and \t some \"more' 'stuff\"").unwrap().remove(0).jobs;
+        assert_eq!("#!!;\"\\", jobs[0].args[1]);
+        assert_eq!("and", jobs[0].args[2]);
+        assert_eq!("some", jobs[0].args[3]);
+        assert_eq!("more' 'stuff", jobs[0].args[4]);
+    }
+
+    #[test]
+    fn several_blank_lines() {
+        let pipelines = parse("\n\n\n").unwrap();
+        assert_eq!(0, pipelines.len());
+    }
 
-    pub fn new(jobs: Vec<Job>, stdin: Option<String>, stdout: Option<String>) -> Self {
-        Pipeline {
-            jobs: jobs,
-            stdin_file: stdin,
-            stdout_file: stdout,
-        }
+    #[test]
+    fn three_job_pipeline() {
+        let pipelines = parse("cat foo | grep bar | wc -l").unwrap();
+        assert_eq!(1, pipelines.len());
+        let jobs = &pipelines[0].jobs;
+        assert_eq!(3, jobs.len());
+        assert_eq!("cat", jobs[0].command);
+        assert_eq!("foo", jobs[0].args[1]);
+        assert_eq!("grep", jobs[1].command);
+        assert_eq!("bar", jobs[1].args[1]);
+        assert_eq!("wc", jobs[2].command);
+        assert_eq!("-l", jobs[2].args[1]);
     }
 
-    pub fn expand_globs(&mut self) {
-        let jobs = self.jobs.drain(..).map(|mut job| {
-            job.expand_globs();
-            job
-        }).collect();
-        self.jobs = jobs;
+    #[test]
+    fn pipeline_without_whitespace() {
+        let jobs = parse("ls|wc").unwrap().remove(0).jobs;
+        assert_eq!(2, jobs.len());
+        assert_eq!("ls", jobs[0].command);
+        assert_eq!(1, jobs[0].args.len());
+        assert_eq!("wc", jobs[1].command);
     }
-}
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct Job {
-    pub command: String,
-    pub args: Vec<String>,
-    pub background: bool,
-}
+    #[test]
+    fn and_operator() {
+        let pipelines = parse("make && make install").unwrap();
+        assert_eq!(2, pipelines.len());
+        assert_eq!(Condition::Always, pipelines[0].condition);
+        assert_eq!(1, pipelines[0].jobs[0].args.len());
+        assert_eq!(Condition::OnSuccess, pipelines[1].condition);
+        assert_eq!("install", pipelines[1].jobs[0].args[1]);
+    }
 
-impl Job {
+    #[test]
+    fn or_operator_without_whitespace() {
+        let pipelines = parse("false||true").unwrap();
+        assert_eq!(2, pipelines.len());
+        assert_eq!("false", pipelines[0].jobs[0].command);
+        assert_eq!(Condition::OnFailure, pipelines[1].condition);
+        assert_eq!("true", pipelines[1].jobs[0].command);
+    }
 
-    pub fn new(args: Vec<String>, background: bool) -> Self {
-        let command = args[0].clone();
-        Job {
-            command: command,
-            args: args,
-            background: background,
-        }
+    #[test]
+    fn mixed_conditional_chain() {
+        let pipelines = parse("cat foo | grep bar && echo found || echo missing; ls").unwrap();
+        assert_eq!(4, pipelines.len());
+        assert_eq!(2, pipelines[0].jobs.len());
+        assert_eq!(Condition::OnSuccess, pipelines[1].condition);
+        assert_eq!(Condition::OnFailure, pipelines[2].condition);
+        assert_eq!(Condition::Always, pipelines[3].condition);
+        assert_eq!(false, pipelines[0].jobs[1].background);
     }
 
-    pub fn expand_globs(&mut self) {
-        let mut new_args: Vec<String> = vec![];
-        for arg in self.args.drain(..) {
-            let mut pushed_glob = false;
-            if arg.contains(|chr| chr == '?' || chr == '*' || chr == '[') {
-                if let Ok(expanded) = glob(&arg) {
-                    for path in expanded.filter_map(Result::ok) {
-                        pushed_glob = true;
-                        new_args.push(path.to_string_lossy().into_owned());
-                    }
-                }
-            }
-            if !pushed_glob {
-                new_args.push(arg);
-            }
-        }
-        self.args = new_args;
+    #[test]
+    fn pipelines_with_redirection() {
+        let pipelines = parse("cat | echo hello | cat < stuff > other").unwrap();
+        assert_eq!(3, pipelines[0].jobs.len());
+        assert_eq!(vec![Redirection::new(0, Target::Input(Word::literal("stuff"))),
+                        Redirection::new(1, Target::Output(Word::literal("other")))],
+                   pipelines[0].jobs[2].redirections);
     }
 
-    pub fn build_command(&self) -> Command {
-        let mut command = Command::new(&self.command);
-        for i in 1..self.args.len() {
-            if let Some(arg) = self.args.get(i) {
-                command.arg(arg);
-            }
-        }
-        command
+    #[test]
+    fn pipelines_with_redirection_reverse_order() {
+        let pipelines = parse("cat | echo hello | cat > stuff < other").unwrap();
+        assert_eq!(3, pipelines[0].jobs.len());
+        assert_eq!(vec![Redirection::new(1, Target::Output(Word::literal("stuff"))),
+                        Redirection::new(0, Target::Input(Word::literal("other")))],
+                   pipelines[0].jobs[2].redirections);
     }
-}
 
-pub fn parse(code: &str) -> Vec<Pipeline> {
-    pipelines(code).unwrap_or(vec![])
-}
+    #[test]
+    fn input_redirection() {
+        let jobs = parse("sort < data.txt").unwrap().remove(0).jobs;
+        assert_eq!(1, jobs[0].args.len());
+        assert_eq!("sort", jobs[0].command);
+        assert_eq!(vec![Redirection::new(0, Target::Input(Word::literal("data.txt")))],
+                   jobs[0].redirections);
+    }
 
-peg! grammar(r#"
-use super::Pipeline;
-use super::Job;
+    #[test]
+    fn input_redirection_without_whitespace() {
+        let jobs = parse("sort<data.txt").unwrap().remove(0).jobs;
+        assert_eq!(1, jobs[0].args.len());
+        assert_eq!(vec![Redirection::new(0, Target::Input(Word::literal("data.txt")))],
+                   jobs[0].redirections);
+    }
 
+    #[test]
+    fn truncating_output_redirection() {
+        let jobs = parse("echo hi > out.txt").unwrap().remove(0).jobs;
+        assert_eq!(2, jobs[0].args.len());
+        assert_eq!(vec![Redirection::new(1, Target::Output(Word::literal("out.txt")))],
+                   jobs[0].redirections);
+    }
 
-#[pub]
-pipelines -> Vec<Pipeline>
-    = (unused* newline)* pipelines:pipeline ++ ((job_ending+ unused*)+) (newline unused*)* { pipelines }
-    / (unused*) ** newline { vec![] }
+    #[test]
+    fn clobbering_output_redirection() {
+        let pipelines = parse("echo hi >| out.txt | cat").unwrap();
+        assert_eq!(2, pipelines[0].jobs.len());
+        assert_eq!(vec![Redirection::new(1, Target::Clobber(Word::literal("out.txt")))],
+                   pipelines[0].jobs[0].redirections);
+        assert_eq!("echo hi >| out.txt | cat", pipelines[0].to_string());
+    }
 
-pipeline -> Pipeline
-    = whitespace? res:job ++ pipeline_sep whitespace? redir:redirection whitespace? comment? { Pipeline::new(res, redir.0, redir.1) }
+    #[test]
+    fn appending_output_redirection() {
+        let jobs = parse("echo hi >> out.txt").unwrap().remove(0).jobs;
+        assert_eq!(2, jobs[0].args.len());
+        assert_eq!(vec![Redirection::new(1, Target::Append(Word::literal("out.txt")))],
+                   jobs[0].redirections);
+    }
 
-job -> Job
-    = args:word ++ whitespace background:background_token? { 
-        Job::new(args.iter().map(|arg|arg.to_string()).collect(), background.is_some())
+    #[test]
+    fn here_document() {
+        let pipelines = parse("cat <<EOF\nhello $USER\n  \"quoted\"\nEOF\necho done").unwrap();
+        assert_eq!(2, pipelines.len());
+        let body = Word::new(vec![WordSegment::Literal("hello ".to_string()),
+                                  WordSegment::Variable("USER".to_string()),
+                                  WordSegment::Literal("\n  \"quoted\"\n".to_string())]);
+        assert_eq!(vec![Redirection::new(0, Target::HereDocument(body))],
+                   pipelines[0].jobs[0].redirections);
+        assert_eq!("echo", pipelines[1].jobs[0].command);
     }
 
-redirection -> (Option<String>, Option<String>)
-    = stdin:redirect_stdin whitespace? stdout:redirect_stdout? { (Some(stdin), stdout) }
-    / stdout:redirect_stdout whitespace? stdin:redirect_stdin? { (stdin, Some(stdout)) }
-    / { (None, None) }
+    #[test]
+    fn here_document_with_quoted_delimiter() {
+        let jobs = parse("cat << 'END' | wc -l\n$x `y`\nEND").unwrap().remove(0).jobs;
+        assert_eq!(2, jobs.len());
+        assert_eq!(vec![Redirection::new(0, Target::HereDocument(Word::literal("$x `y`\n")))],
+                   jobs[0].redirections);
+    }
 
-redirect_stdin -> String
-    = [<] whitespace? file:word { file.to_string() }
+    #[test]
+    fn here_document_stripping_tabs() {
+        let jobs = parse("cat <<-EOF\n\tindented\n\tEOF").unwrap().remove(0).jobs;
+        assert_eq!(vec![Redirection::new(0, Target::HereDocument(Word::literal("indented\n")))],
+                   jobs[0].redirections);
+    }
 
-redirect_stdout -> String
-    = [>] whitespace? file:word { file.to_string() }
+    #[test]
+    fn several_here_documents() {
+        let pipelines = parse("cat <<A; cat <<B\na\nA\nb\nB\n").unwrap();
+        assert_eq!(2, pipelines.len());
+        assert_eq!(Target::HereDocument(Word::literal("a\n")),
+                   pipelines[0].jobs[0].redirections[0].target);
+        assert_eq!(Target::HereDocument(Word::literal("b\n")),
+                   pipelines[1].jobs[0].redirections[0].target);
+    }
 
-pipeline_sep -> ()
-    = (whitespace? [|] whitespace?) { }
+    #[test]
+    fn here_string() {
+        let jobs = parse("grep foo <<< \"$text\" | wc").unwrap().remove(0).jobs;
+        assert_eq!(2, jobs.len());
+        assert_eq!(vec!["grep".to_string(), "foo".to_string()], jobs[0].args);
+        let string = Word::new(vec![WordSegment::Variable("text".to_string())]);
+        assert_eq!(vec![Redirection::new(0, Target::HereString(string))], jobs[0].redirections);
+    }
 
-background_token -> ()
-    = [&]
-    / whitespace [&]
+    #[test]
+    fn here_string_does_not_start_here_document() {
+        let pipelines = parse("cat <<<EOF\nEOF").unwrap();
+        assert_eq!(2, pipelines.len());
+        assert_eq!(Target::HereString(Word::literal("EOF")),
+                   pipelines[0].jobs[0].redirections[0].target);
+    }
 
-word -> &'input str
-    = double_quoted_word
-    / single_quoted_word
-    / [^ \t\r\n#;&|<>]+ { match_str }
+    #[test]
+    fn unterminated_here_document() {
+        assert_eq!(Err(super::ParseError::UnterminatedHereDocument("EOF".to_string())),
+                   parse("cat <<EOF\nhello\n"));
+    }
 
-double_quoted_word -> &'input str
-    = ["] word:_double_quoted_word ["] { word }
+    #[test]
+    fn collecting_here_documents_keeps_positions() {
+        let (code, bodies) = collect_here_documents("cat <<EOF\nbody\nEOF\nls").unwrap();
+        assert_eq!("cat <<EOF\n    \n   \nls", code);
+        assert_eq!(vec![Word::literal("body\n")], bodies);
+    }
 
-_double_quoted_word -> &'input str
-    = [^"]+ { match_str }
+    #[test]
+    fn pipelines_are_statements() {
+        let statements = parse_statements("ls | wc -l; echo done").unwrap();
+        assert_eq!(2, statements.len());
+        match statements[0] {
+            Statement::Pipeline(ref pipeline) => assert_eq!(2, pipeline.jobs.len()),
+            ref statement => panic!("expected a pipeline, found {:?}", statement),
+        }
+    }
 
-single_quoted_word -> &'input str
-    = ['] word:_single_quoted_word ['] { word }
+    #[test]
+    fn spans() {
+        let pipelines = parse("ls  \"a b\" > out | wc -l\necho $(ls x)").unwrap();
+        let jobs = &pipelines[0].jobs;
+        assert_eq!(Span::new(0, 15), jobs[0].span);
+        assert_eq!(Span::new(0, 2), jobs[0].words[0].span);
+        assert_eq!(Span::new(4, 9), jobs[0].words[1].span);
+        assert_eq!(Span::new(18, 23), jobs[1].span);
+        assert_eq!(Span::new(29, 36), pipelines[1].jobs[0].words[1].span);
+        match pipelines[1].jobs[0].words[1].segments[0] {
+            WordSegment::CommandSubstitution(ref inner, _) => {
+                assert_eq!(Span::new(34, 35), inner[0].jobs[0].words[1].span);
+            },
+            ref segment => panic!("expected a command substitution, found {:?}", segment),
+        }
+    }
 
-_single_quoted_word -> &'input str
-    = [^']+ { match_str }
+    #[test]
+    fn while_loop() {
+        let statements = parse_statements("while $x != 3\n  let x = 3\nend").unwrap();
+        match statements[0] {
+            Statement::While { ref test, ref body } => {
+                let x = Word::new(vec![WordSegment::Variable("x".to_string())]);
+                assert_eq!(Test::Comparison(x, "!=".to_string(), Word::literal("3")), *test);
+                assert_eq!(1, body.len());
+            },
+            ref statement => panic!("expected a while loop, found {:?}", statement),
+        }
+    }
 
-unused -> ()
-    = whitespace comment? { () }
-    / comment { () }
+    #[test]
+    fn pipeline_tests() {
+        let statements = parse_statements("if grep -q foo bar | cat; echo yes; else; echo no; fi").unwrap();
+        match statements[0] {
+            Statement::If { test: Test::Pipeline(ref pipeline), ref success, ref failure } => {
+                assert_eq!(2, pipeline.jobs.len());
+                assert_eq!(1, success.len());
+                assert_eq!(1, failure.len());
+            },
+            ref statement => panic!("expected an if statement with a pipeline, found {:?}", statement),
+        }
+    }
 
-comment -> ()
-    = [#] [^\r\n]*
+    #[test]
+    fn for_loop() {
+        let statements = parse_statements("for file in *.rs {a,b} # files\n  echo $file\nend; ls").unwrap();
+        assert_eq!(2, statements.len());
+        match statements[0] {
+            Statement::For { ref variable, ref values, ref body } => {
+                assert_eq!("file", variable);
+                assert_eq!(2, values.len());
+                assert_eq!(Word::new(vec![WordSegment::Pattern("*.rs".to_string())]), values[0]);
+                assert_eq!(1, body.len());
+            },
+            ref statement => panic!("expected a for loop, found {:?}", statement),
+        }
+    }
 
-whitespace -> ()
-    = [ \t]+
+    #[test]
+    fn function_definition() {
+        let statements = parse_statements("fn greet first second\n  echo $first $second\nend\ngreet a b")
+                             .unwrap();
+        assert_eq!(2, statements.len());
+        match statements[0] {
+            Statement::Function { ref name, ref args, ref body } => {
+                assert_eq!("greet", name);
+                assert_eq!(&vec!["first".to_string(), "second".to_string()], args);
+                assert_eq!(1, body.len());
+            },
+            ref statement => panic!("expected a function, found {:?}", statement),
+        }
+    }
 
-job_ending -> ()
-    = [;]
-    / newline
-    / newline
+    #[test]
+    fn keywords_as_arguments() {
+        let statements = parse_statements("echo if for end fi; iffy; ending").unwrap();
+        assert_eq!(3, statements.len());
+        assert!(statements.iter().all(|statement| match *statement {
+            Statement::Pipeline(_) => true,
+            _ => false,
+        }));
+    }
 
-newline -> ()
-    = [\r\n]
-"#);
+    #[test]
+    fn unterminated_blocks_are_incomplete() {
+        for code in &["if a == a", "if a == a\n  echo a", "for x in a b\n", "fn f\n", "if true\nelse"] {
+            assert!(parse_statements(code).unwrap_err().is_incomplete(), "{} should be incomplete", code);
+        }
+        assert!(parse_statements("end").is_err());
+    }
 
+    #[test]
+    fn subshell() {
+        let statements = parse_statements("(cd /tmp; ls) && (pwd) || echo failed").unwrap();
+        assert_eq!(3, statements.len());
+        match statements[0] {
+            Statement::Subshell { ref body, condition: Condition::Always } => assert_eq!(2, body.len()),
+            ref statement => panic!("expected a subshell, found {:?}", statement),
+        }
+        match statements[1] {
+            Statement::Subshell { ref body, condition: Condition::OnSuccess } => assert_eq!(1, body.len()),
+            ref statement => panic!("expected a subshell, found {:?}", statement),
+        }
+        match statements[2] {
+            Statement::Pipeline(ref pipeline) => assert_eq!(Condition::OnFailure, pipeline.condition),
+            ref statement => panic!("expected a pipeline, found {:?}", statement),
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use super::grammar::*;
+    #[test]
+    fn nested_subshell_is_not_command_substitution() {
+        let statements = parse_statements("( (echo $(pwd)) ; )").unwrap();
+        match statements[0] {
+            Statement::Subshell { ref body, .. } => {
+                match body[0] {
+                    Statement::Subshell { ref body, .. } => assert_eq!(1, body.len()),
+                    ref statement => panic!("expected a subshell, found {:?}", statement),
+                }
+            },
+            ref statement => panic!("expected a subshell, found {:?}", statement),
+        }
+    }
 
     #[test]
-    fn single_job_no_args() {
-        let jobs = parse("cat").remove(0).jobs;
-        assert_eq!(1, jobs.len());
-        assert_eq!("cat", jobs[0].command);
-        assert_eq!(1, jobs[0].args.len());
+    fn group() {
+        let statements = parse_statements("{ make; make test; } > log.txt 2>&1 && { echo ok\n}").unwrap();
+        assert_eq!(2, statements.len());
+        match statements[0] {
+            Statement::Group { ref body, ref redirections, condition: Condition::Always } => {
+                assert_eq!(2, body.len());
+                assert_eq!(vec![Redirection::new(1, Target::Output(Word::literal("log.txt"))),
+                                Redirection::new(2, Target::Duplicate(1))],
+                           *redirections);
+            },
+            ref statement => panic!("expected a group, found {:?}", statement),
+        }
+        match statements[1] {
+            Statement::Group { ref body, condition: Condition::OnSuccess, .. } => assert_eq!(1, body.len()),
+            ref statement => panic!("expected a group, found {:?}", statement),
+        }
     }
 
     #[test]
-    fn single_job_with_args() {
-        let jobs = parse("ls -al dir").remove(0).jobs;
-        assert_eq!(1, jobs.len());
-        assert_eq!("ls", jobs[0].command);
-        assert_eq!("-al", jobs[0].args[1]);
-        assert_eq!("dir", jobs[0].args[2]);
+    fn braces_that_are_not_groups() {
+        let statements = parse_statements("echo {a,b} } {}; echo { x").unwrap();
+        assert_eq!(2, statements.len());
+        match statements[1] {
+            Statement::Pipeline(ref pipeline) => assert_eq!(vec!["echo", "{", "x"], pipeline.jobs[0].args),
+            ref statement => panic!("expected a pipeline, found {:?}", statement),
+        }
     }
 
     #[test]
-    fn multiple_jobs_with_args() {
-        let pipelines = parse("ls -al;cat tmp.txt");
-        assert_eq!(2, pipelines.len());
-        assert_eq!("ls", pipelines[0].jobs[0].command);
-        assert_eq!("-al", pipelines[0].jobs[0].args[1]);
-        assert_eq!("cat", pipelines[1].jobs[0].command);
-        assert_eq!("tmp.txt", pipelines[1].jobs[0].args[1]);
+    fn negated_pipeline() {
+        let pipelines = parse("! grep -q pattern file | cat && !true; echo !").unwrap();
+        assert_eq!(3, pipelines.len());
+        assert!(pipelines[0].negated);
+        assert_eq!(2, pipelines[0].jobs.len());
+        assert_eq!("grep", pipelines[0].jobs[0].command);
+        assert!(!pipelines[1].negated);
+        assert_eq!("!true", pipelines[1].jobs[0].command);
+        assert_eq!(vec!["echo", "!"], pipelines[2].jobs[0].args);
     }
 
     #[test]
-    fn parse_empty_string() {
-        let pipelines = parse("");
-        assert_eq!(0, pipelines.len());
+    fn assignment() {
+        let statements = parse_statements("NAME = value; path=$HOME/bin\nempty=\nx= \"a b\" # set x").unwrap();
+        assert_eq!(vec![Statement::Assignment {
+                            name: "NAME".to_string(),
+                            value: Value::Word(Word::literal("value")),
+                        },
+                        Statement::Assignment {
+                            name: "path".to_string(),
+                            value: Value::Word(Word::new(vec![WordSegment::Variable("HOME".to_string()),
+                                                              WordSegment::Literal("/bin".to_string())])),
+                        },
+                        Statement::Assignment { name: "empty".to_string(), value: Value::Word(Word::new(vec![])) },
+                        Statement::Assignment { name: "x".to_string(), value: Value::Word(Word::literal("a b")) }],
+                   statements);
     }
 
     #[test]
-    fn multiple_white_space_between_words() {
-        let jobs = parse("ls \t -al\t\tdir").remove(0).jobs;
-        assert_eq!(1, jobs.len());
-        assert_eq!("ls", jobs[0].command);
-        assert_eq!("-al", jobs[0].args[1]);
-        assert_eq!("dir", jobs[0].args[2]);
+    fn prefix_environment_assignments() {
+        let jobs = parse("RUST_LOG=debug EMPTY= cargo run X=1 | A=$B less").unwrap().remove(0).jobs;
+        assert_eq!(vec!["cargo", "run", "X=1"], jobs[0].args);
+        assert_eq!(vec![("RUST_LOG".to_string(), Word::literal("debug")),
+                        ("EMPTY".to_string(), Word::new(vec![]))],
+                   jobs[0].env);
+        assert_eq!("less", jobs[1].command);
+        assert_eq!(vec![("A".to_string(), Word::new(vec![WordSegment::Variable("B".to_string())]))],
+                   jobs[1].env);
     }
 
     #[test]
-    fn trailing_whitespace() {
-        let pipelines = parse("ls -al\t ");
-        assert_eq!(1, pipelines.len());
-        assert_eq!("ls", pipelines[0].jobs[0].command);
-        assert_eq!("-al", pipelines[0].jobs[0].args[1]);
+    fn let_assignment() {
+        let statements = parse_statements("let x = 5; let y=$((x + 1)); let; let x").unwrap();
+        assert_eq!(Statement::Assignment { name: "x".to_string(), value: Value::Word(Word::literal("5")) },
+                   statements[0]);
+        match statements[1] {
+            Statement::Assignment { ref name, value: Value::Word(ref value) } => {
+                assert_eq!("y", name);
+                match value.segments[0] {
+                    WordSegment::Arithmetic(_) => (),
+                    ref segment => panic!("expected an arithmetic expansion, found {:?}", segment),
+                }
+            },
+            ref statement => panic!("expected an assignment, found {:?}", statement),
+        }
+        // Listing and removing variables is still done by the let builtin
+        match (&statements[2], &statements[3]) {
+            (&Statement::Pipeline(ref list), &Statement::Pipeline(ref remove)) => {
+                assert_eq!(vec!["let"], list.jobs[0].args);
+                assert_eq!(vec!["let", "x"], remove.jobs[0].args);
+            },
+            statements => panic!("expected pipelines, found {:?}", statements),
+        }
     }
 
     #[test]
-    fn double_quoting() {
-        let jobs = parse("echo \"Hello World\"").remove(0).jobs;
-        assert_eq!(2, jobs[0].args.len());
-        assert_eq!("Hello World", jobs[0].args[1]);
+    fn commands_that_are_not_assignments() {
+        let statements = parse_statements("a == b; 1x=2; echo x=1; x = a b").unwrap();
+        assert_eq!(4, statements.len());
+        assert!(statements.iter().all(|statement| match *statement {
+            Statement::Pipeline(_) => true,
+            _ => false,
+        }));
     }
 
     #[test]
-    fn all_whitespace() {
-        let pipelines = parse("  \t ");
-        assert_eq!(0, pipelines.len());
+    fn array_assignment() {
+        let statements = parse_statements("let dirs = [ /tmp \"a b\" $HOME ]\nempty=[]\nglob=[*.rs]").unwrap();
+        assert_eq!(vec![Statement::Assignment {
+                            name: "dirs".to_string(),
+                            value: Value::Array(vec![Word::literal("/tmp"),
+                                                     Word::literal("a b"),
+                                                     Word::new(vec![WordSegment::Variable("HOME".to_string())])]),
+                        },
+                        Statement::Assignment { name: "empty".to_string(), value: Value::Array(vec![]) },
+                        Statement::Assignment {
+                            name: "glob".to_string(),
+                            value: Value::Word(Word::new(vec![WordSegment::Pattern("[*.rs]".to_string())])),
+                        }],
+                   statements);
+        assert_eq!(Statement::Assignment {
+                       name: "x".to_string(),
+                       value: Value::Array(vec![Word::literal("a"), Word::literal("b")]),
+                   },
+                   parse_statements("x = [ a b ] # two").unwrap()[0]);
     }
 
     #[test]
-    fn not_background_job() {
-        let jobs = parse("echo hello world").remove(0).jobs;
-        assert_eq!(false, jobs[0].background);
+    fn braced_variables() {
+        let jobs = parse("tar cf ${name}_backup.tar \"${HOME}\"/x ${ {a,b}").unwrap().remove(0).jobs;
+        assert_eq!(Word::new(vec![WordSegment::Variable("name".to_string()),
+                                  WordSegment::Literal("_backup.tar".to_string())]),
+                   jobs[0].words[2]);
+        assert_eq!("${name}_backup.tar", jobs[0].args[2]);
+        assert_eq!(Word::new(vec![WordSegment::Variable("HOME".to_string()),
+                                  WordSegment::Literal("/x".to_string())]),
+                   jobs[0].words[3]);
+        assert_eq!("$HOME/x", jobs[0].args[3]);
+        assert_eq!(Word::literal("${"), jobs[0].words[4]);
     }
 
     #[test]
-    fn background_job() {
-        let jobs = parse("echo hello world&").remove(0).jobs;
-        assert_eq!(true, jobs[0].background);
+    fn parameter_expansions() {
+        let words = parse("echo ${a:-default value} \"${b:=$HOME/x}\" ${c:+'}'} ${d:?} pre${e:-x}").unwrap()
+                        .remove(0)
+                        .jobs
+                        .remove(0)
+                        .words;
+        assert_eq!(Word::new(vec![WordSegment::Parameter("a".to_string(),
+                                                         ParameterOperator::Default,
+                                                         Word::literal("default value"))]),
+                   words[1]);
+        assert_eq!(Word::new(vec![WordSegment::Parameter("b".to_string(),
+                                                         ParameterOperator::Assign,
+                                                         Word::new(vec![WordSegment::Variable("HOME".to_string()),
+                                                                        WordSegment::Literal("/x".to_string())]))]),
+                   words[2]);
+        assert_eq!(Word::new(vec![WordSegment::Parameter("c".to_string(),
+                                                         ParameterOperator::Alternative,
+                                                         Word::literal("}"))]),
+                   words[3]);
+        assert_eq!(Word::new(vec![WordSegment::Parameter("d".to_string(),
+                                                         ParameterOperator::Error,
+                                                         Word::new(vec![]))]),
+                   words[4]);
+        assert_eq!("pre${e:-x}", words[5].to_string());
     }
 
     #[test]
-    fn background_job_with_space() {
-        let jobs = parse("echo hello world &").remove(0).jobs;
-        assert_eq!(true, jobs[0].background);
+    fn array_expansions() {
+        let words = parse("echo @dirs @dirs[2] @dirs[1..-1] @dirs[..2] x@dirs \"@dirs\" @dirs[x]").unwrap()
+                        .remove(0)
+                        .jobs
+                        .remove(0)
+                        .words;
+        assert_eq!(Word::new(vec![WordSegment::Array("dirs".to_string(), None)]), words[1]);
+        assert_eq!(Word::new(vec![WordSegment::Array("dirs".to_string(), Some(Index::Single(2)))]),
+                   words[2]);
+        assert_eq!(Word::new(vec![WordSegment::Array("dirs".to_string(), Some(Index::Range(Some(1), Some(-1))))]),
+                   words[3]);
+        assert_eq!("@dirs[..2]", words[4].to_string());
+        assert_eq!(Word::literal("x@dirs"), words[5]);
+        assert_eq!(Word::literal("@dirs"), words[6]);
+        assert_eq!(Word::new(vec![WordSegment::Pattern("@dirs[x]".to_string())]), words[7]);
     }
 
     #[test]
-    fn lone_comment() {
-        let pipelines = parse("# ; \t as!!+dfa");
-        assert_eq!(0, pipelines.len());
+    fn select_array_elements() {
+        let elements: Vec<String> = vec!["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(&["c".to_string()], Index::Single(2).select(&elements));
+        assert_eq!(&["d".to_string()], Index::Single(-1).select(&elements));
+        assert!(Index::Single(4).select(&elements).is_empty());
+        assert_eq!(&elements[1..3], Index::Range(Some(1), Some(-1)).select(&elements));
+        assert_eq!(&elements[..2], Index::Range(None, Some(2)).select(&elements));
+        assert_eq!(&elements[2..], Index::Range(Some(2), None).select(&elements));
+        assert!(Index::Range(Some(3), Some(1)).select(&elements).is_empty());
     }
 
     #[test]
-    fn command_followed_by_comment() {
-        let pipelines = parse("cat # ; \t as!!+dfa");
-        assert_eq!(1, pipelines.len());
-        assert_eq!(1, pipelines[0].jobs[0].args.len());
+    fn method_calls() {
+        let words = parse("echo $len(name) \"$join( dirs , \", \" )\" $join(dirs,$sep)x $len").unwrap()
+                        .remove(0)
+                        .jobs
+                        .remove(0)
+                        .words;
+        assert_eq!(Word::new(vec![WordSegment::Method("len".to_string(), "name".to_string(), vec![])]),
+                   words[1]);
+        assert_eq!(Word::new(vec![WordSegment::Method("join".to_string(),
+                                                      "dirs".to_string(),
+                                                      vec![Word::literal(", ")])]),
+                   words[2]);
+        assert_eq!(Word::new(vec![WordSegment::Method("join".to_string(),
+                                                      "dirs".to_string(),
+                                                      vec![Word::new(vec![WordSegment::Variable("sep".to_string())])]),
+                                  WordSegment::Literal("x".to_string())]),
+                   words[3]);
+        assert_eq!("$join(dirs, $sep)x", words[3].to_string());
+        assert_eq!(Word::new(vec![WordSegment::Variable("len".to_string())]), words[4]);
     }
 
     #[test]
-    fn comments_in_multiline_script() {
-        let pipelines = parse("echo\n# a comment;\necho#asfasdf");
-        assert_eq!(2, pipelines.len());
+    fn operators_continue_onto_the_next_line() {
+        let pipelines = parse("ls |\n  grep foo &&\n\n echo found ||   # fallback\n echo missing\necho next").unwrap();
+        assert_eq!(4, pipelines.len());
+        assert_eq!(2, pipelines[0].jobs.len());
+        assert_eq!(vec!["grep", "foo"], pipelines[0].jobs[1].args);
+        assert_eq!(Condition::OnSuccess, pipelines[1].condition);
+        assert_eq!(Condition::OnFailure, pipelines[2].condition);
+        assert_eq!(Condition::Always, pipelines[3].condition);
+        let statements = parse_statements("make &&\n  (make test)\necho done").unwrap();
+        assert_eq!(3, statements.len());
+        match statements[1] {
+            Statement::Subshell { condition, .. } => assert_eq!(Condition::OnSuccess, condition),
+            ref statement => panic!("expected a subshell, found {:?}", statement),
+        }
     }
 
     #[test]
-    fn multiple_newlines() {
-        let pipelines = parse("echo\n\ncat");
-        assert_eq!(2, pipelines.len());
+    fn condition_keywords() {
+        let statements = parse_statements("make\nand make test; or echo failed\nnot grep -q x y\necho and or not").unwrap();
+        let pipelines: Vec<&Pipeline> = statements.iter()
+                                                  .map(|statement| match *statement {
+                                                      Statement::Pipeline(ref pipeline) => pipeline,
+                                                      ref statement => panic!("expected a pipeline, found {:?}", statement),
+                                                  })
+                                                  .collect();
+        assert_eq!(Condition::OnSuccess, pipelines[1].condition);
+        assert_eq!(vec!["make", "test"], pipelines[1].jobs[0].args);
+        assert_eq!(Condition::OnFailure, pipelines[2].condition);
+        assert!(pipelines[3].negated);
+        assert_eq!("grep", pipelines[3].jobs[0].command);
+        assert_eq!(Condition::Always, pipelines[4].condition);
+        assert_eq!(vec!["echo", "and", "or", "not"], pipelines[4].jobs[0].args);
+        assert_eq!(Statement::Assignment { name: "and".to_string(), value: Value::Word(Word::literal("1")) },
+                   parse_statements("and = 1").unwrap()[0]);
     }
 
     #[test]
-    fn leading_whitespace() {
-        let jobs = parse("    \techo").remove(0).jobs;
-        assert_eq!(1, jobs.len());
-        assert_eq!("echo", jobs[0].command);
+    fn recover_from_syntax_errors() {
+        let (statements, errors) = parse_statements_recovering("echo a\necho )\necho b; echo |;\n\necho c\nls >\n");
+        let commands: Vec<String> = statements.iter()
+                                              .map(|statement| match *statement {
+                                                  Statement::Pipeline(ref pipeline) => pipeline.jobs[0].args.join(" "),
+                                                  ref statement => panic!("expected a pipeline, found {:?}", statement),
+                                              })
+                                              .collect();
+        assert_eq!(vec!["echo a", "echo c"], commands);
+        assert_eq!(3, errors.len());
+        match (&errors[0], &errors[1]) {
+            (&ParseError::Syntax { line: 2, column: 6, offset: 12, .. },
+             &ParseError::Syntax { line: 3, column: 15, offset: 28, ref text, .. }) => {
+                assert_eq!("echo b; echo |;", text);
+            },
+            errors => panic!("unexpected errors: {:?}", errors),
+        }
+        assert!(errors[2].is_incomplete());
+        assert_eq!((vec![], vec![]), parse_statements_recovering(""));
     }
 
     #[test]
-    fn indentation_on_multiple_lines() {
-        let pipelines = parse("echo\n  cat");
-        assert_eq!(2, pipelines.len());
-        assert_eq!("echo", pipelines[0].jobs[0].command);
-        assert_eq!("cat", pipelines[1].jobs[0].command);
+    fn display_job_as_code() {
+        let words = vec![Word::literal("grep"),
+                         Word::literal("it's a \"test\""),
+                         Word::literal(""),
+                         Word::new(vec![WordSegment::Literal("src/".to_string()),
+                                        WordSegment::Pattern("*.rs".to_string())]),
+                         Word::new(vec![WordSegment::Variable("name".to_string()),
+                                        WordSegment::Literal("_backup".to_string())]),
+                         Word::literal("$HOME;#~"),
+                         Word::literal("@dirs"),
+                         Word::literal("a\nb")];
+        let redirections = vec![Redirection::new(0, Target::Input(Word::literal("in file"))),
+                                Redirection::new(2, Target::Duplicate(1)),
+                                Redirection::new(3, Target::Append(Word::literal("log"))),
+                                Redirection::new(0, Target::Close)];
+        let job = Job::new(words, redirections, true)
+                      .with_env(vec![("X".to_string(), Word::literal("1 2")), ("EMPTY".to_string(), Word::new(vec![]))]);
+        assert_eq!("X='1 2' EMPTY='' grep 'it'\\''s a \"test\"' '' 'src/'*.rs ${name}_backup '$HOME;#~' '@dirs' \
+                    'a\nb' < 'in file' 2>&1 3>> log <&- &",
+                   job.to_string());
+        assert_eq!(vec![job.clone()], parse(&job.to_string()).unwrap().remove(0).jobs);
+        let substituted = parse("echo \"$(printf '%s' \"a b\" > f)\" $(a && b 2>&1 || X=1 c; d &)").unwrap()
+                              .remove(0)
+                              .jobs
+                              .remove(0);
+        assert_eq!("echo \"$(printf %s 'a b' > f)\" $(a && b 2>&1 || X=1 c; d &)", substituted.to_string());
+        assert_eq!(vec![substituted.clone()], parse(&substituted.to_string()).unwrap().remove(0).jobs);
+        let processes = parse("diff <(sort < 'in file') >(grep -v \"a b\" >> log)").unwrap().remove(0).jobs.remove(0);
+        assert_eq!("diff <(sort < 'in file') >(grep -v 'a b' >> log)", processes.to_string());
+        assert_eq!(vec![processes.clone()], parse(&processes.to_string()).unwrap().remove(0).jobs);
+        let keyword = Job::new(vec![Word::literal("if"), Word::literal("end")], vec![], false);
+        assert_eq!("'if' end", keyword.to_string());
+        assert_eq!(Statement::Pipeline(Pipeline::new(vec![keyword.clone()], Condition::Always)),
+                   parse_statements(&keyword.to_string()).unwrap()[0]);
     }
 
     #[test]
-    fn single_quoting() {
-        let jobs = parse("echo '#!!;\"\\'").remove(0).jobs;
-        assert_eq!("#!!;\"\\", jobs[0].args[1]);
+    fn display_patterns_with_escapes() {
+        let job = Job::new(vec![Word::literal("ls"), Word::new(vec![WordSegment::Pattern("#a b*".to_string())])],
+                           vec![],
+                           false);
+        assert_eq!("ls \\#a\\ b*", job.to_string());
+        assert_eq!(Word::new(vec![WordSegment::Literal("#a ".to_string()), WordSegment::Pattern("b*".to_string())]),
+                   parse(&job.to_string()).unwrap().remove(0).jobs.remove(0).words.remove(1));
     }
 
     #[test]
-    fn mixed_quoted_and_unquoted() {
-        let jobs = parse("echo '#!!;\"\\' and \t some \"more' 'stuff\"").remove(0).jobs;
-        assert_eq!("#!!;\"\\", jobs[0].args[1]);
-        assert_eq!("and", jobs[0].args[2]);
-        assert_eq!("some", jobs[0].args[3]);
-        assert_eq!("more' 'stuff", jobs[0].args[4]);
+    fn display_pipeline_as_code() {
+        let pipeline = parse("time ! ls  -l | wc -l &").unwrap().remove(0);
+        assert!(pipeline.is_background());
+        assert_eq!("time ! ls -l | wc -l &", pipeline.to_string());
+        assert!(!parse("sleep 1 && ls").unwrap()[1].is_background());
     }
 
     #[test]
-    fn several_blank_lines() {
-        let pipelines = parse("\n\n\n");
-        assert_eq!(0, pipelines.len());
+    fn build_jobs_and_pipelines() {
+        let job = Job::builder("cargo")
+                      .arg("build")
+                      .args(&["--release", "a b"])
+                      .env("RUST_LOG", "debug")
+                      .stdout(Target::append("log"))
+                      .stderr(Target::Duplicate(1))
+                      .background()
+                      .build();
+        assert_eq!(parse("RUST_LOG=debug cargo build --release 'a b' >> log 2>&1 &").unwrap().remove(0).jobs[0],
+                   job);
+        let pipeline = Pipeline::builder()
+                           .job(Job::builder("ls").stdin(Target::input("dirs")).build())
+                           .job(Job::builder("wc").word(Word::new(vec![WordSegment::Variable("FLAGS".to_string())])).build())
+                           .condition(Condition::OnSuccess)
+                           .negated()
+                           .build();
+        assert_eq!(parse("true && ! ls < dirs | wc $FLAGS").unwrap().remove(1), pipeline);
+        assert!(Pipeline::builder().timed().build().timed);
     }
 
     #[test]
-    fn pipelines_with_redirection() {
-        let pipelines = parse("cat | echo hello | cat < stuff > other");
-        assert_eq!(3, pipelines[0].jobs.len());
-        assert_eq!(Some("stuff".to_string()), pipelines[0].stdin_file);
-        assert_eq!(Some("other".to_string()), pipelines[0].stdout_file);
+    fn timed_pipeline() {
+        let pipelines = parse("time cargo build | tee log; time ! false; timeout 5 ls").unwrap();
+        assert!(pipelines[0].timed);
+        assert_eq!(vec!["cargo", "build"], pipelines[0].jobs[0].args);
+        assert_eq!(2, pipelines[0].jobs.len());
+        assert!(pipelines[1].timed && pipelines[1].negated);
+        assert!(!pipelines[2].timed);
+        assert_eq!("timeout", pipelines[2].jobs[0].command);
     }
 
     #[test]
-    fn pipelines_with_redirection_reverse_order() {
-        let pipelines = parse("cat | echo hello | cat > stuff < other");
-        assert_eq!(3, pipelines[0].jobs.len());
-        assert_eq!(Some("other".to_string()), pipelines[0].stdin_file);
-        assert_eq!(Some("stuff".to_string()), pipelines[0].stdout_file);
+    fn syntax_error() {
+        match parse("echo hi\nls | )") {
+            Err(super::ParseError::Syntax { line, column, offset, ref text, .. }) => {
+                assert_eq!((2, 6, 13), (line, column, offset));
+                assert_eq!("ls | )", text);
+            },
+            result => panic!("expected a syntax error, found {:?}", result),
+        }
+    }
+
+    #[test]
+    fn incomplete_input() {
+        for code in &["echo \"abc", "echo 'abc\n", "ls |", "ls | \n", "make &&", "echo $(ls",
+                      "echo a \\", "cat <<EOF\nline"] {
+            assert!(parse(code).unwrap_err().is_incomplete(), "{} should be incomplete", code);
+        }
+        for code in &["echo )", "ls | )\n", "echo |;"] {
+            assert!(!parse(code).unwrap_err().is_incomplete(), "{} should be invalid", code);
+        }
+    }
+
+    #[test]
+    fn syntax_error_display() {
+        let error = parse("echo ok\n\tcat )").unwrap_err().to_string();
+        let mut lines = error.lines();
+        assert!(lines.next().unwrap().starts_with("syntax error at line 2, column 6"));
+        assert_eq!(Some("\tcat )"), lines.next());
+        assert_eq!(Some("\t    ^"), lines.next());
+    }
+
+    #[test]
+    fn numbered_file_descriptors() {
+        let jobs = parse("cmd 3> three.log 4>> four.log 5< five.txt 6>&3 7<&5 2>&- <&- >&-").unwrap()
+                       .remove(0)
+                       .jobs;
+        assert_eq!(vec!["cmd"], jobs[0].args);
+        assert_eq!(vec![Redirection::new(3, Target::Output(Word::literal("three.log"))),
+                        Redirection::new(4, Target::Append(Word::literal("four.log"))),
+                        Redirection::new(5, Target::Input(Word::literal("five.txt"))),
+                        Redirection::new(6, Target::Duplicate(3)),
+                        Redirection::new(7, Target::Duplicate(5)),
+                        Redirection::new(2, Target::Close),
+                        Redirection::new(0, Target::Close),
+                        Redirection::new(1, Target::Close)],
+                   jobs[0].redirections);
+    }
+
+    #[test]
+    fn stderr_redirection() {
+        let jobs = parse("make 2> errors.log").unwrap().remove(0).jobs;
+        assert_eq!(1, jobs[0].args.len());
+        assert_eq!(vec![Redirection::new(2, Target::Output(Word::literal("errors.log")))],
+                   jobs[0].redirections);
+    }
+
+    #[test]
+    fn stderr_duplicated_onto_stdout() {
+        let jobs = parse("make > build.log 2>&1 | less").unwrap().remove(0).jobs;
+        assert_eq!(2, jobs.len());
+        assert_eq!(vec![Redirection::new(1, Target::Output(Word::literal("build.log"))),
+                        Redirection::new(2, Target::Duplicate(1))],
+                   jobs[0].redirections);
+        assert!(jobs[1].redirections.is_empty());
+    }
+
+    #[test]
+    fn stdout_and_stderr_redirection() {
+        let jobs = parse("make &> build.log").unwrap().remove(0).jobs;
+        assert_eq!(1, jobs[0].args.len());
+        assert_eq!(false, jobs[0].background);
+        assert_eq!(vec![Redirection::new(1, Target::Output(Word::literal("build.log"))),
+                        Redirection::new(2, Target::Duplicate(1))],
+                   jobs[0].redirections);
+    }
+
+    #[test]
+    fn digit_arguments_are_not_redirections() {
+        let jobs = parse("echo 2 > out.txt").unwrap().remove(0).jobs;
+        assert_eq!("2", jobs[0].args[1]);
+        assert_eq!(vec![Redirection::new(1, Target::Output(Word::literal("out.txt")))],
+                   jobs[0].redirections);
     }
 
     #[test]
     fn full_script() {
-        pipelines(r#"if a == a
+        let statements = statements(r#"if a == a
   echo true a == a
 
   if b != b
@@ -337,12 +2951,56 @@ else
   echo false a == a
 fi
 "#)
-            .unwrap();  // Make sure it parses
+            .unwrap();
+        assert_eq!(1, statements.len());
+        match statements[0] {
+            Statement::If { ref test, ref success, ref failure } => {
+                assert_eq!(Test::Comparison(Word::literal("a"), "==".to_string(), Word::literal("a")),
+                           *test);
+                assert_eq!(2, success.len());
+                assert_eq!(1, failure.len());
+                match success[1] {
+                    Statement::If { ref success, ref failure, .. } => {
+                        assert_eq!(1, success.len());
+                        assert_eq!(2, failure.len());
+                    },
+                    ref statement => panic!("expected an if statement, found {:?}", statement),
+                }
+            },
+            ref statement => panic!("expected an if statement, found {:?}", statement),
+        }
+    }
+
+    #[test]
+    fn blocks_end_with_end() {
+        let statements = statements("if a == b\n  echo 1\nelse if a == c\n  for x in y\n    echo $x\n  end\nelse\n  echo 3\nend\n")
+                             .unwrap();
+        assert_eq!(1, statements.len());
+        match statements[0] {
+            Statement::If { ref success, ref failure, .. } => {
+                assert_eq!(1, success.len());
+                assert_eq!(1, failure.len());
+                match failure[0] {
+                    Statement::If { ref test, ref success, ref failure } => {
+                        assert_eq!(Test::Comparison(Word::literal("a"), "==".to_string(), Word::literal("c")),
+                                   *test);
+                        match success[0] {
+                            Statement::For { .. } => (),
+                            ref statement => panic!("expected a for loop, found {:?}", statement),
+                        }
+                        assert_eq!(1, failure.len());
+                    },
+                    ref statement => panic!("expected an if statement, found {:?}", statement),
+                }
+            },
+            ref statement => panic!("expected an if statement, found {:?}", statement),
+        }
+        assert!(statements("if a == b\n  echo 1\nelse if a == c\n  echo 2\nend\nend").is_err());
     }
 
     #[test]
     fn leading_and_trailing_junk() {
-        pipelines(r#"
+        statements(r#"
 
 # comment
    # comment
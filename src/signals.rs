@@ -0,0 +1,274 @@
+//! Handles the signals that the shell receives. The handlers only record that a signal arrived,
+//! and the shell acts on it between commands, such as by running the code that was given to
+//! `trap` for it.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, Ordering};
+
+use libc::{self, c_int};
+
+use super::status::{SUCCESS, FAILURE};
+
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The signals that have arrived but have not been handled yet, with one bit for each number
+static PENDING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The number that `trap` uses for the code that runs when the shell exits
+pub const EXIT: c_int = 0;
+
+/// The signals that can be sent with `kill`, by name. All but `KILL` and `STOP` can be trapped.
+const SIGNALS: &'static [(&'static str, c_int)] = &[("HUP", libc::SIGHUP),
+                                                    ("INT", libc::SIGINT),
+                                                    ("QUIT", libc::SIGQUIT),
+                                                    ("ILL", libc::SIGILL),
+                                                    ("TRAP", libc::SIGTRAP),
+                                                    ("ABRT", libc::SIGABRT),
+                                                    ("BUS", libc::SIGBUS),
+                                                    ("FPE", libc::SIGFPE),
+                                                    ("KILL", libc::SIGKILL),
+                                                    ("USR1", libc::SIGUSR1),
+                                                    ("SEGV", libc::SIGSEGV),
+                                                    ("USR2", libc::SIGUSR2),
+                                                    ("PIPE", libc::SIGPIPE),
+                                                    ("ALRM", libc::SIGALRM),
+                                                    ("TERM", libc::SIGTERM),
+                                                    ("CHLD", libc::SIGCHLD),
+                                                    ("CONT", libc::SIGCONT),
+                                                    ("STOP", libc::SIGSTOP),
+                                                    ("TSTP", libc::SIGTSTP),
+                                                    ("TTIN", libc::SIGTTIN),
+                                                    ("TTOU", libc::SIGTTOU),
+                                                    ("URG", libc::SIGURG),
+                                                    ("XCPU", libc::SIGXCPU),
+                                                    ("XFSZ", libc::SIGXFSZ),
+                                                    ("VTALRM", libc::SIGVTALRM),
+                                                    ("PROF", libc::SIGPROF),
+                                                    ("WINCH", libc::SIGWINCH),
+                                                    ("IO", libc::SIGIO),
+                                                    ("SYS", libc::SIGSYS)];
+
+extern "C" fn handle_signal(signal: c_int) {
+    record(signal, &INTERRUPTED, &PENDING);
+}
+
+/// Records that the signal arrived in the flags, which are taken as arguments so that the
+/// recording can be tested without touching those of the shell
+fn record(signal: c_int, interrupted: &AtomicBool, pending: &AtomicUsize) {
+    if signal == libc::SIGINT {
+        interrupted.store(true, Ordering::SeqCst);
+    }
+    pending.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Installs the handlers, so that Ctrl-C stops the commands that are running instead of the
+/// shell. Programs that the shell starts get the default handlers back when they are executed.
+pub fn init() {
+    install(libc::SIGINT, handle_signal as libc::sighandler_t);
+}
+
+/// Sets how the signal is handled. Unlike with `signal`, system calls that the signal interrupts
+/// are not restarted, so that `wait` can give up on the jobs it is blocked on.
+fn install(signal: c_int, handler: libc::sighandler_t) {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler;
+        libc::sigemptyset(&mut action.sa_mask);
+        action.sa_flags = 0;
+        libc::sigaction(signal, &action, ptr::null_mut());
+    }
+}
+
+/// Whether Ctrl-C was pressed since the shell started running the current command, in which case
+/// the rest of it should not run
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// A signal that arrived while the current command was running and that the shell handles,
+/// which is Ctrl-C if it was pressed
+pub fn arrived() -> Option<c_int> {
+    if interrupted() {
+        return Some(libc::SIGINT);
+    }
+    let pending = PENDING.load(Ordering::SeqCst);
+    let bits = 8 * mem::size_of::<usize>() as c_int;
+    (1..bits).find(|&signal| pending & (1 << signal) != 0)
+}
+
+/// Records an interrupt that the shell did not receive itself, such as when the pipeline in the
+/// foreground has its own process group and was ended by Ctrl-C
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Forgets any interrupt, before the next command is run
+pub fn clear() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+/// Takes the signals that have arrived since the last time, in order of their numbers
+pub fn take_pending() -> Vec<c_int> {
+    take(&PENDING)
+}
+
+fn take(pending: &AtomicUsize) -> Vec<c_int> {
+    let pending = pending.swap(0, Ordering::SeqCst);
+    let bits = 8 * mem::size_of::<usize>() as c_int;
+    (1..bits).filter(|&signal| pending & (1 << signal) != 0).collect()
+}
+
+/// The number of a signal from its name, such as `INT` or `SIGINT`, or from the number itself.
+/// `EXIT` is 0.
+pub fn number(name: &str) -> Option<c_int> {
+    if let Ok(number) = name.parse::<c_int>() {
+        return if number == EXIT || SIGNALS.iter().any(|&(_, signal)| signal == number) {
+            Some(number)
+        } else {
+            None
+        };
+    }
+    let name = name.to_uppercase();
+    let name = if name.starts_with("SIG") { &name[3..] } else { &name[..] };
+    if name == "EXIT" {
+        return Some(EXIT);
+    }
+    SIGNALS.iter().find(|&&(signal_name, _)| signal_name == name).map(|&(_, signal)| signal)
+}
+
+/// The names of the signals, in the order of their numbers
+pub fn names() -> Vec<&'static str> {
+    SIGNALS.iter().map(|&(name, _)| name).collect()
+}
+
+/// The name of a signal number, without the `SIG` at the start
+pub fn name(number: c_int) -> &'static str {
+    if number == EXIT {
+        return "EXIT";
+    }
+    SIGNALS.iter().find(|&&(_, signal)| signal == number).map_or("UNKNOWN", |&(name, _)| name)
+}
+
+/// The code that the user wants to run when the shell receives signals, which is set with `trap`
+pub struct Traps {
+    handlers: BTreeMap<c_int, String>,
+}
+
+impl Traps {
+    pub fn new() -> Self {
+        Traps { handlers: BTreeMap::new() }
+    }
+
+    /// The code to run for the signal, if there is any
+    pub fn get(&self, signal: c_int) -> Option<&String> {
+        self.handlers.get(&signal).and_then(|code| if code.is_empty() { None } else { Some(code) })
+    }
+
+    /// Takes the code to run when the shell exits, so that it only runs once
+    pub fn take_exit(&mut self) -> Option<String> {
+        self.handlers.remove(&EXIT).and_then(|code| if code.is_empty() { None } else { Some(code) })
+    }
+
+    /// Runs the `trap` builtin. `trap code INT TERM` will run the code when the shell receives
+    /// the signals, `trap '' INT` will ignore them, `trap - INT` will restore the default
+    /// handling and `trap` on its own will list the traps.
+    pub fn trap(&mut self, args: &[String]) -> i32 {
+        if args.len() < 2 {
+            for (signal, code) in self.handlers.iter() {
+                println!("trap -- '{}' {}", code.replace("'", "'\\''"), name(*signal));
+            }
+            return SUCCESS;
+        }
+        if args.len() < 3 {
+            println!("ion: trap: expected a signal after the code");
+            return FAILURE;
+        }
+        let mut status = SUCCESS;
+        for signal_name in &args[2..] {
+            match number(signal_name) {
+                Some(signal) if signal == libc::SIGKILL || signal == libc::SIGSTOP => {
+                    println!("ion: trap: {}: cannot be trapped", signal_name);
+                    status = FAILURE;
+                },
+                Some(signal) => self.set(signal, &args[1]),
+                None => {
+                    println!("ion: trap: {}: invalid signal specification", signal_name);
+                    status = FAILURE;
+                }
+            }
+        }
+        status
+    }
+
+    fn set(&mut self, signal: c_int, code: &str) {
+        let handler = if code == "-" {
+            self.handlers.remove(&signal);
+            // The shell keeps handling Ctrl-C itself, so that it is not ended by it
+            if signal == libc::SIGINT {
+                handle_signal as libc::sighandler_t
+            } else {
+                libc::SIG_DFL
+            }
+        } else {
+            self.handlers.insert(signal, code.to_string());
+            if code.is_empty() {
+                libc::SIG_IGN
+            } else {
+                handle_signal as libc::sighandler_t
+            }
+        };
+        if signal != EXIT {
+            install(signal, handler);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{record, take};
+    use libc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[test]
+    fn record_interrupts() {
+        // The shell's own flags are left alone, since other tests check them as they run
+        let interrupted = AtomicBool::new(false);
+        let pending = AtomicUsize::new(0);
+        record(libc::SIGUSR1, &interrupted, &pending);
+        assert!(!interrupted.load(Ordering::SeqCst));
+        record(libc::SIGINT, &interrupted, &pending);
+        assert!(interrupted.load(Ordering::SeqCst));
+        assert_eq!(vec![libc::SIGINT, libc::SIGUSR1], take(&pending));
+        assert!(take(&pending).is_empty());
+    }
+
+    #[test]
+    fn signal_names_and_numbers() {
+        assert_eq!(Some(libc::SIGINT), number("INT"));
+        assert_eq!(Some(libc::SIGTERM), number("sigterm"));
+        assert_eq!(Some(libc::SIGHUP), number("1"));
+        assert_eq!(Some(EXIT), number("EXIT"));
+        assert_eq!(Some(libc::SIGKILL), number("KILL"));
+        assert_eq!(None, number("NOTASIGNAL"));
+        assert_eq!(None, number("99"));
+        assert_eq!("USR1", name(libc::SIGUSR1));
+    }
+
+    #[test]
+    fn trap_signals() {
+        let mut traps = Traps::new();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        assert_eq!(SUCCESS, traps.trap(&args(&["trap", "echo usr2", "USR2"])));
+        assert_eq!(Some(&"echo usr2".to_string()), traps.get(libc::SIGUSR2));
+        unsafe { libc::raise(libc::SIGUSR2) };
+        assert!(take_pending().contains(&libc::SIGUSR2));
+        assert!(!take_pending().contains(&libc::SIGUSR2));
+        assert_eq!(FAILURE, traps.trap(&args(&["trap", "echo", "NOTASIGNAL"])));
+        assert_eq!(FAILURE, traps.trap(&args(&["trap", "echo", "KILL"])));
+        assert_eq!(SUCCESS, traps.trap(&args(&["trap", "-", "USR2"])));
+        assert_eq!(None, traps.get(libc::SIGUSR2));
+    }
+}
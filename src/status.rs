@@ -2,3 +2,4 @@ pub const SUCCESS: i32 = 0;
 pub const FAILURE: i32 = -1;
 pub const NO_SUCH_COMMAND: i32 = 127;
 pub const TERMINATED: i32 = 143;
+pub const STOPPED: i32 = 148;
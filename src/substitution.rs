@@ -0,0 +1,268 @@
+//! Replaces the substitutions in the words of a pipeline with what they stand for, before the
+//! rest of the words are expanded. Running the pipelines inside the substitutions is left to the
+//! shell, which is given each substitution and returns the text that replaces it.
+
+use super::peg::{Pipeline, Job, Redirection, Target, Word, WordSegment};
+
+/// The characters that the output of a command substitution is split on when `$IFS` is unset
+pub const DEFAULT_IFS: &'static str = " \t\n";
+
+/// A substitution that the shell runs, and what it is replaced with
+#[derive(Clone, Copy)]
+pub enum Substitution<'a> {
+    /// The output of the pipelines (`$(ls)`)
+    Command(&'a [Pipeline]),
+    /// A path that the output of the pipelines can be read from (`<(ls)`)
+    Input(&'a [Pipeline]),
+    /// A path that can be written to, with the pipelines reading it as input (`>(wc -l)`)
+    Output(&'a [Pipeline]),
+}
+
+/// Whether the word contains a substitution that the shell needs to run
+fn has_substitution(word: &Word) -> bool {
+    word.segments.iter().any(|segment| {
+        match *segment {
+            WordSegment::CommandSubstitution(..) |
+            WordSegment::InputSubstitution(_) |
+            WordSegment::OutputSubstitution(_) => true,
+            WordSegment::Parameter(_, _, ref word) => has_substitution(word),
+            WordSegment::Method(_, _, ref args) => args.iter().any(has_substitution),
+            WordSegment::Brace(ref alternatives) => alternatives.iter().any(has_substitution),
+            _ => false,
+        }
+    })
+}
+
+/// Substitutes the words of each job in the pipeline. The output of unquoted command
+/// substitutions is split into arguments on the characters in `ifs`.
+pub fn substitute_pipeline(pipeline: &Pipeline, ifs: &str, run: &mut FnMut(Substitution) -> String) -> Pipeline {
+    let mut substituted = Pipeline::new(pipeline.jobs.iter().map(|job| substitute_job(job, ifs, run)).collect(),
+                                        pipeline.condition);
+    substituted.negated = pipeline.negated;
+    substituted.timed = pipeline.timed;
+    substituted
+}
+
+/// Substitutes the words, variables and redirections of the job. A job whose words all turn
+/// out to be empty, such as `$(true)`, is left with a single empty word.
+pub fn substitute_job(job: &Job, ifs: &str, run: &mut FnMut(Substitution) -> String) -> Job {
+    let redirection_word = |redirection: &Redirection| {
+        match redirection.target {
+            Target::Input(ref word) | Target::Output(ref word) | Target::Clobber(ref word) |
+            Target::Append(ref word) | Target::HereDocument(ref word) | Target::HereString(ref word) => {
+                has_substitution(word)
+            },
+            _ => false,
+        }
+    };
+    if !job.words.iter().any(has_substitution) && !job.env.iter().any(|&(_, ref value)| has_substitution(value)) &&
+       !job.redirections.iter().any(redirection_word) {
+        return job.clone();
+    }
+    let mut words: Vec<Word> = job.words.iter().flat_map(|word| substitute_arguments(word, ifs, run)).collect();
+    if words.is_empty() {
+        words.push(Word::literal("").with_span(job.span));
+    }
+    let env = job.env
+                 .iter()
+                 .map(|&(ref name, ref value)| (name.clone(), substitute_word(value, run)))
+                 .collect();
+    let redirections = job.redirections
+                          .iter()
+                          .map(|redirection| {
+                              let target = match redirection.target {
+                                  Target::Input(ref word) => Target::Input(substitute_word(word, run)),
+                                  Target::Output(ref word) => Target::Output(substitute_word(word, run)),
+                                  Target::Clobber(ref word) => Target::Clobber(substitute_word(word, run)),
+                                  Target::Append(ref word) => Target::Append(substitute_word(word, run)),
+                                  Target::HereDocument(ref word) => Target::HereDocument(substitute_word(word, run)),
+                                  Target::HereString(ref word) => Target::HereString(substitute_word(word, run)),
+                                  ref target => target.clone(),
+                              };
+                              Redirection::new(redirection.fd, target)
+                          })
+                          .collect();
+    Job::new(words, redirections, job.background).with_env(env).with_span(job.span)
+}
+
+/// Replaces the substitutions in a word that is not split into arguments, such as the value of
+/// a variable, including those nested in other segments
+pub fn substitute_word(word: &Word, run: &mut FnMut(Substitution) -> String) -> Word {
+    if !has_substitution(word) {
+        return word.clone();
+    }
+    let mut words = substitute(word, None, run);
+    if words.is_empty() { Word::literal("").with_span(word.span) } else { words.remove(0) }
+}
+
+/// Replaces the substitutions in a word, which becomes an argument for each field of the output
+/// of its unquoted command substitutions, or none if they are all empty
+pub fn substitute_arguments(word: &Word, ifs: &str, run: &mut FnMut(Substitution) -> String) -> Vec<Word> {
+    if !has_substitution(word) {
+        return vec![word.clone()];
+    }
+    substitute(word, Some(ifs), run)
+}
+
+fn substitute(word: &Word, ifs: Option<&str>, run: &mut FnMut(Substitution) -> String) -> Vec<Word> {
+    let mut words = vec![];
+    // The segments of the word that is being put together, if anything has been put in it
+    let mut current: Option<Vec<WordSegment>> = None;
+    for segment in word.segments.iter() {
+        let substituted = match *segment {
+            WordSegment::CommandSubstitution(ref pipelines, split) => {
+                let output = run(Substitution::Command(pipelines));
+                let output = output.trim_right_matches('\n');
+                match ifs {
+                    Some(ifs) if split => {
+                        let mut fields = split_fields(output, ifs).into_iter();
+                        if let Some(first) = fields.next() {
+                            append(&mut current, WordSegment::unquoted(&first));
+                        }
+                        for field in fields {
+                            if let Some(segments) = current.take() {
+                                words.push(Word::new(segments).with_span(word.span));
+                            }
+                            current = Some(vec![WordSegment::unquoted(&field)]);
+                        }
+                        continue;
+                    },
+                    _ => WordSegment::Literal(output.to_string()),
+                }
+            },
+            WordSegment::InputSubstitution(ref pipelines) => {
+                WordSegment::Literal(run(Substitution::Input(pipelines)))
+            },
+            WordSegment::OutputSubstitution(ref pipelines) => {
+                WordSegment::Literal(run(Substitution::Output(pipelines)))
+            },
+            WordSegment::Parameter(ref name, operator, ref word) => {
+                WordSegment::Parameter(name.clone(), operator, substitute_word(word, run))
+            },
+            WordSegment::Method(ref method, ref variable, ref args) => {
+                WordSegment::Method(method.clone(),
+                                    variable.clone(),
+                                    args.iter().map(|arg| substitute_word(arg, run)).collect())
+            },
+            WordSegment::Brace(ref alternatives) => {
+                WordSegment::Brace(alternatives.iter().map(|alternative| substitute_word(alternative, run)).collect())
+            },
+            ref segment => segment.clone(),
+        };
+        append(&mut current, substituted);
+    }
+    if let Some(segments) = current {
+        words.push(Word::new(segments).with_span(word.span));
+    }
+    words
+}
+
+fn append(segments: &mut Option<Vec<WordSegment>>, segment: WordSegment) {
+    if segments.is_none() {
+        *segments = Some(vec![]);
+    }
+    segments.as_mut().unwrap().push(segment);
+}
+
+/// Splits the text into fields that are separated by the characters in `ifs`. Whitespace around
+/// the fields is left out, while other separators each end a field, even an empty one.
+pub fn split_fields(text: &str, ifs: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field: Option<String> = None;
+    // A separator right after whitespace that ended a field belongs with it
+    let mut after_whitespace = false;
+    for character in text.chars() {
+        if !ifs.contains(character) {
+            if field.is_none() {
+                field = Some(String::new());
+            }
+            field.as_mut().unwrap().push(character);
+            after_whitespace = false;
+        } else if character.is_whitespace() {
+            if let Some(field) = field.take() {
+                fields.push(field);
+                after_whitespace = true;
+            }
+        } else {
+            match field.take() {
+                Some(field) => fields.push(field),
+                None if after_whitespace => (),
+                None => fields.push(String::new()),
+            }
+            after_whitespace = false;
+        }
+    }
+    if let Some(field) = field {
+        fields.push(field);
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peg::{parse, Word, WordSegment};
+
+    #[test]
+    fn replace_process_substitutions_with_paths() {
+        let pipeline = parse("diff <(ls a) --new=>(wc -l) plain").unwrap().remove(0);
+        let substituted = substitute_pipeline(&pipeline, DEFAULT_IFS, &mut |substitution| {
+            match substitution {
+                Substitution::Input(pipelines) => format!("/dev/fd/{}", pipelines[0].jobs[0].args[1]),
+                Substitution::Output(pipelines) => format!("/dev/fd/{}", pipelines[0].jobs[0].command),
+                Substitution::Command(_) => panic!("there are no command substitutions"),
+            }
+        });
+        assert_eq!(vec!["diff", "/dev/fd/a", "--new=/dev/fd/wc", "plain"], substituted.jobs[0].args);
+    }
+
+    #[test]
+    fn leave_words_without_substitutions() {
+        let word = Word::new(vec![WordSegment::Variable("x".to_string())]);
+        assert_eq!(word, substitute_word(&word, &mut |_| panic!("there is nothing to substitute")));
+    }
+
+    #[test]
+    fn split_unquoted_command_substitutions() {
+        let pipeline = parse("touch a$(echo)b $(echo) \"$(echo)\" x").unwrap().remove(0);
+        let substituted = substitute_pipeline(&pipeline, DEFAULT_IFS, &mut |_| " one  two\tthree\n\n".to_string());
+        assert_eq!(vec!["touch", "aone", "two", "threeb", "one", "two", "three", " one  two\tthree", "x"],
+                   substituted.jobs[0].args);
+        let substituted = substitute_pipeline(&pipeline, "", &mut |_| "one two\n".to_string());
+        assert_eq!(vec!["touch", "aone twob", "one two", "one two", "x"], substituted.jobs[0].args);
+    }
+
+    #[test]
+    fn drop_empty_substitutions() {
+        let pipeline = parse("$(true)").unwrap().remove(0);
+        let substituted = substitute_pipeline(&pipeline, DEFAULT_IFS, &mut |_| String::new());
+        assert_eq!(vec![""], substituted.jobs[0].args);
+        let word = parse("echo \"$(true)\"").unwrap().remove(0).jobs.remove(0).words.remove(1);
+        assert_eq!(vec![Word::literal("")], substitute_arguments(&word, DEFAULT_IFS, &mut |_| String::new()));
+    }
+
+    #[test]
+    fn leave_nested_substitutions_to_the_pipelines_they_are_in() {
+        let pipeline = parse("echo $(dirname $(which cargo))").unwrap().remove(0);
+        let substituted = substitute_pipeline(&pipeline, DEFAULT_IFS, &mut |substitution| {
+            match substitution {
+                Substitution::Command(pipelines) => {
+                    assert_eq!("dirname", pipelines[0].jobs[0].command);
+                    assert_eq!("$(which cargo)", pipelines[0].jobs[0].args[1]);
+                    "/usr/bin\n".to_string()
+                },
+                _ => panic!("there are only command substitutions"),
+            }
+        });
+        assert_eq!(vec!["echo", "/usr/bin"], substituted.jobs[0].args);
+    }
+
+    #[test]
+    fn split_fields_on_separators() {
+        assert_eq!(vec!["a", "b", "c"], split_fields("  a b\n\tc ", DEFAULT_IFS));
+        assert_eq!(vec!["a", "", "b"], split_fields("a::b", ":"));
+        assert_eq!(vec!["a", "b"], split_fields("a : b", " :"));
+        assert_eq!(vec!["", "a"], split_fields(":a:", ":"));
+        assert!(split_fields("", DEFAULT_IFS).is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt;
+
+use super::peg::{parse_spanned_statements, ParseError, Span, Statement};
+
+/// Code that is not part of any statement, but is kept so that the code can be reproduced
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Trivia {
+    /// Spaces, tabs, and backslashes at the end of a line
+    Whitespace(String),
+    /// `\n` or `\r\n`
+    Newline(String),
+    /// A comment, from the `#` up to the end of the line
+    Comment(String),
+    /// The `;` or `&` that ends a statement
+    Separator(String),
+    /// A line of the body of a here document, or the line with its delimiter
+    HereDocument(String),
+}
+
+impl Trivia {
+    pub fn text(&self) -> &str {
+        match *self {
+            Trivia::Whitespace(ref text) |
+            Trivia::Newline(ref text) |
+            Trivia::Comment(ref text) |
+            Trivia::Separator(ref text) |
+            Trivia::HereDocument(ref text) => text,
+        }
+    }
+}
+
+/// One of the `;` or newline separated items of the code. An item is a single statement, or
+/// several when they are chained with `&&` and `||`.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Node {
+    pub statements: Vec<Statement>,
+    /// The code the statements were parsed from, as it was written. A comment at the end of a
+    /// statement's line is part of its text.
+    pub text: String,
+    pub span: Span,
+    /// The trivia between the previous node and this one
+    pub leading: Vec<Trivia>,
+}
+
+/// Code parsed without losing anything, so that its exact text can be reproduced from the
+/// nodes and trivia, such as for a formatter or a highlighter.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SyntaxTree {
+    pub nodes: Vec<Node>,
+    /// The trivia after the last node
+    pub trailing: Vec<Trivia>,
+}
+
+impl fmt::Display for SyntaxTree {
+    /// Displays the code exactly as it was parsed
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for node in self.nodes.iter() {
+            for trivia in node.leading.iter() {
+                try!(write!(f, "{}", trivia.text()));
+            }
+            try!(write!(f, "{}", node.text));
+        }
+        for trivia in self.trailing.iter() {
+            try!(write!(f, "{}", trivia.text()));
+        }
+        Ok(())
+    }
+}
+
+/// Parses the code into a syntax tree that keeps its whitespace, comments and quoting
+pub fn parse_lossless(code: &str) -> Result<SyntaxTree, ParseError> {
+    let (parsed, items) = try!(parse_spanned_statements(code));
+    let mut nodes = vec![];
+    let mut position = 0;
+    for (statements, span) in items {
+        let span = trim_span(&parsed, span);
+        nodes.push(Node {
+            statements: statements,
+            text: code[span.start..span.end].to_string(),
+            span: span,
+            leading: trivia(code, &parsed, position, span.start),
+        });
+        position = span.end;
+    }
+    Ok(SyntaxTree {
+        nodes: nodes,
+        trailing: trivia(code, &parsed, position, code.len()),
+    })
+}
+
+/// Items are parsed with the whitespace before and after them, which is trivia instead
+fn trim_span(parsed: &str, span: Span) -> Span {
+    let mut start = span.start;
+    let mut end = span.end;
+    loop {
+        let rest = &parsed[start..end];
+        if rest.starts_with(' ') || rest.starts_with('\t') {
+            start += 1;
+        } else if rest.starts_with("\\\n") {
+            start += 2;
+        } else if rest.starts_with("\\\r\n") {
+            start += 3;
+        } else {
+            break;
+        }
+    }
+    while end > start && parsed[start..end].ends_with(|c: char| c == ' ' || c == '\t') {
+        end -= 1;
+    }
+    Span::new(start, end)
+}
+
+/// Splits the code between two nodes into trivia. The parsed code is used to tell where the
+/// bodies of here documents are, since they were replaced by spaces in it.
+fn trivia(code: &str, parsed: &str, start: usize, end: usize) -> Vec<Trivia> {
+    let mut trivia = vec![];
+    let mut position = start;
+    while position < end {
+        let rest = &parsed[position..end];
+        let length = if rest.starts_with('#') {
+            rest.find(|c: char| c == '\r' || c == '\n').unwrap_or(rest.len())
+        } else if rest.starts_with("\r\n") {
+            2
+        } else if rest.starts_with(|c: char| c == '\n' || c == '\r' || c == ';' || c == '&') {
+            1
+        } else {
+            let mut length = 0;
+            loop {
+                let whitespace = &rest[length..];
+                if whitespace.starts_with(' ') || whitespace.starts_with('\t') {
+                    length += 1;
+                } else if whitespace.starts_with("\\\n") {
+                    length += 2;
+                } else if whitespace.starts_with("\\\r\n") {
+                    length += 3;
+                } else {
+                    break;
+                }
+            }
+            // Anything else would have been parsed as part of a node
+            if length == 0 { rest.len() } else { length }
+        };
+        let text = code[position..position + length].to_string();
+        let piece = if rest.starts_with('#') {
+            Trivia::Comment(text)
+        } else if rest.starts_with('\n') || rest.starts_with('\r') {
+            Trivia::Newline(text)
+        } else if rest.starts_with(';') || rest.starts_with('&') {
+            Trivia::Separator(text)
+        } else if text != &rest[..length] {
+            Trivia::HereDocument(text)
+        } else {
+            Trivia::Whitespace(text)
+        };
+        trivia.push(piece);
+        position += length;
+    }
+    trivia
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peg::{parse_statements, Statement};
+
+    const SCRIPT: &'static str = "#!/usr/bin/env ion\n\n  echo 'single'  \"double $x\" # greet\r\n\
+                                  cat <<EOF ; make && make test &\n  body $x\nEOF\n\
+                                  if a == b\n  # inside\n  echo yes\nend\n\t # done\n";
+
+    #[test]
+    fn reproduces_the_code() {
+        let tree = parse_lossless(SCRIPT).unwrap();
+        assert_eq!(SCRIPT, tree.to_string());
+    }
+
+    #[test]
+    fn nodes_have_the_statements_and_their_text() {
+        let tree = parse_lossless(SCRIPT).unwrap();
+        let texts: Vec<&str> = tree.nodes.iter().map(|node| node.text.as_str()).collect();
+        assert_eq!(vec!["echo 'single'  \"double $x\" # greet",
+                        "cat <<EOF",
+                        "make && make test",
+                        "if a == b\n  # inside\n  echo yes\nend"],
+                   texts);
+        let statements: Vec<Statement> = tree.nodes.iter().flat_map(|node| node.statements.clone()).collect();
+        assert_eq!(parse_statements(SCRIPT).unwrap(), statements);
+    }
+
+    #[test]
+    fn trivia_between_nodes() {
+        let tree = parse_lossless(SCRIPT).unwrap();
+        assert_eq!(vec![Trivia::Comment("#!/usr/bin/env ion".to_string()),
+                        Trivia::Newline("\n".to_string()),
+                        Trivia::Newline("\n".to_string()),
+                        Trivia::Whitespace("  ".to_string())],
+                   tree.nodes[0].leading);
+        assert_eq!(vec![Trivia::Whitespace(" ".to_string()),
+                        Trivia::Separator(";".to_string()),
+                        Trivia::Whitespace(" ".to_string())],
+                   tree.nodes[2].leading);
+        assert_eq!(vec![Trivia::Whitespace(" ".to_string()),
+                        Trivia::Separator("&".to_string()),
+                        Trivia::Newline("\n".to_string()),
+                        Trivia::HereDocument("  body $x".to_string()),
+                        Trivia::Newline("\n".to_string()),
+                        Trivia::HereDocument("EOF".to_string()),
+                        Trivia::Newline("\n".to_string())],
+                   tree.nodes[3].leading);
+        assert_eq!(vec![Trivia::Newline("\n".to_string()),
+                        Trivia::Whitespace("\t ".to_string()),
+                        Trivia::Comment("# done".to_string()),
+                        Trivia::Newline("\n".to_string())],
+                   tree.trailing);
+    }
+
+    #[test]
+    fn empty_code() {
+        let tree = parse_lossless("  # nothing\n").unwrap();
+        assert!(tree.nodes.is_empty());
+        assert_eq!("  # nothing\n", tree.to_string());
+    }
+}
@@ -0,0 +1,308 @@
+use super::peg::Span;
+
+/// What a piece of code is, as far as can be told without parsing it
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TokenKind {
+    /// Unquoted text of a word, including any escapes, such as `ls` or `\*.rs`
+    Word,
+    /// Quoted text of a word, with its quotes, such as `'a b'`, `"a $b"` or `$'\n'`
+    Quoted,
+    /// A variable, such as `$HOME`, `${name:-default}` or `@dirs[1]`
+    Variable,
+    /// Code that is replaced by its output or result, such as `$(ls)`, `` `ls` ``, `$((1 + 2))`
+    /// or `<(ls)`
+    Substitution,
+    /// Pipes, conditions, separators, parentheses and redirections, such as `|`, `&&`, `;` or
+    /// `2>&1`
+    Operator,
+    /// A comment, from the `#` up to the end of the line
+    Comment,
+    /// Spaces, tabs, and backslashes at the end of a line
+    Whitespace,
+    /// `\n` or `\r\n`
+    Newline,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Span,
+}
+
+/// Splits the code into tokens that cover all of it, even if it does not parse. Quotes and
+/// substitutions that are not closed continue to the end of the code. Adjacent word, quoted,
+/// variable and substitution tokens are parts of the same word.
+pub fn tokenize(code: &str) -> Vec<Token> {
+    let chars: Vec<(usize, char)> = code.char_indices().collect();
+    let mut tokens = vec![];
+    let mut index = 0;
+    while index < chars.len() {
+        let (kind, end) = next_token(&chars, index, tokens.last().map(|token: &Token| token.kind));
+        let start = chars[index].0;
+        let end_offset = chars.get(end).map_or(code.len(), |&(offset, _)| offset);
+        tokens.push(Token {
+            kind: kind,
+            text: code[start..end_offset].to_string(),
+            span: Span::new(start, end_offset),
+        });
+        index = end;
+    }
+    tokens
+}
+
+/// Returns the kind of the token at the index, and the index of the character after it
+fn next_token(chars: &[(usize, char)], index: usize, previous: Option<TokenKind>) -> (TokenKind, usize) {
+    let at = |offset: usize| chars.get(index + offset).map(|&(_, c)| c);
+    let starts_word = match previous {
+        None | Some(TokenKind::Whitespace) | Some(TokenKind::Newline) | Some(TokenKind::Operator) => true,
+        _ => false,
+    };
+    match (at(0), at(1)) {
+        (Some('\r'), Some('\n')) => (TokenKind::Newline, index + 2),
+        (Some('\n'), _) | (Some('\r'), _) => (TokenKind::Newline, index + 1),
+        (Some(' '), _) | (Some('\t'), _) | (Some('\\'), Some('\n')) | (Some('\\'), Some('\r')) => {
+            (TokenKind::Whitespace, whitespace_end(chars, index))
+        },
+        (Some('#'), _) if starts_word => {
+            let mut end = index;
+            while end < chars.len() && chars[end].1 != '\n' && chars[end].1 != '\r' {
+                end += 1;
+            }
+            (TokenKind::Comment, end)
+        },
+        (Some('\''), _) => (TokenKind::Quoted, quote_end(chars, index + 1, '\'', false)),
+        (Some('"'), _) => (TokenKind::Quoted, quote_end(chars, index + 1, '"', true)),
+        (Some('$'), Some('\'')) => (TokenKind::Quoted, quote_end(chars, index + 2, '\'', true)),
+        (Some('$'), Some('(')) | (Some('<'), Some('(')) | (Some('>'), Some('(')) => {
+            (TokenKind::Substitution, parenthesis_end(chars, index + 2))
+        },
+        (Some('`'), _) => (TokenKind::Substitution, quote_end(chars, index + 1, '`', true)),
+        (Some('$'), Some('{')) => (TokenKind::Variable, brace_end(chars, index + 2)),
+        (Some('$'), Some(c)) if c.is_alphanumeric() || c == '_' || c == '?' || c == '!' => {
+            if c == '?' || c == '!' {
+                return (TokenKind::Variable, index + 2);
+            }
+            (TokenKind::Variable, name_end(chars, index + 1))
+        },
+        (Some('@'), Some(c)) if starts_word && (c.is_alphabetic() || c == '_') => {
+            let end = name_end(chars, index + 1);
+            if chars.get(end).map(|&(_, c)| c) == Some('[') {
+                let mut close = end;
+                while close < chars.len() && chars[close].1 != ']' {
+                    close += 1;
+                }
+                if close < chars.len() {
+                    return (TokenKind::Variable, close + 1);
+                }
+            }
+            (TokenKind::Variable, end)
+        },
+        (Some(c), _) if starts_word && c.is_digit(10) => {
+            let mut end = index;
+            while chars.get(end).map_or(false, |&(_, c)| c.is_digit(10)) {
+                end += 1;
+            }
+            match chars.get(end).map(|&(_, c)| c) {
+                Some('<') | Some('>') => (TokenKind::Operator, operator_end(chars, end)),
+                _ => (TokenKind::Word, word_end(chars, end)),
+            }
+        },
+        (Some(c), _) if "|&;<>()".contains(c) => (TokenKind::Operator, operator_end(chars, index)),
+        (Some('\\'), _) => (TokenKind::Word, word_end(chars, index)),
+        // The character can not start any other token, so it is part of the word
+        _ => (TokenKind::Word, word_end(chars, index + 1)),
+    }
+}
+
+fn whitespace_end(chars: &[(usize, char)], mut index: usize) -> usize {
+    loop {
+        match (chars.get(index).map(|&(_, c)| c), chars.get(index + 1).map(|&(_, c)| c)) {
+            (Some(' '), _) | (Some('\t'), _) => index += 1,
+            (Some('\\'), Some('\n')) => index += 2,
+            (Some('\\'), Some('\r')) => {
+                index += 2;
+                if chars.get(index).map(|&(_, c)| c) == Some('\n') {
+                    index += 1;
+                }
+            },
+            _ => return index,
+        }
+    }
+}
+
+/// Finds the end of text quoted with the quote, where the quote can be escaped if `escapes` is
+/// true
+fn quote_end(chars: &[(usize, char)], mut index: usize, quote: char, escapes: bool) -> usize {
+    while index < chars.len() {
+        match chars[index].1 {
+            '\\' if escapes => index += 2,
+            c if c == quote => return index + 1,
+            _ => index += 1,
+        }
+    }
+    chars.len()
+}
+
+/// Finds the end of a substitution, skipping over quotes and nested parentheses
+fn parenthesis_end(chars: &[(usize, char)], mut index: usize) -> usize {
+    let mut depth = 1;
+    while index < chars.len() {
+        match chars[index].1 {
+            '\\' => index += 2,
+            '\'' => index = quote_end(chars, index + 1, '\'', false),
+            '"' => index = quote_end(chars, index + 1, '"', true),
+            '(' => {
+                depth += 1;
+                index += 1;
+            },
+            ')' => {
+                depth -= 1;
+                index += 1;
+                if depth == 0 {
+                    return index;
+                }
+            },
+            _ => index += 1,
+        }
+    }
+    chars.len()
+}
+
+fn brace_end(chars: &[(usize, char)], mut index: usize) -> usize {
+    while index < chars.len() {
+        match chars[index].1 {
+            '\\' => index += 2,
+            '\'' => index = quote_end(chars, index + 1, '\'', false),
+            '"' => index = quote_end(chars, index + 1, '"', true),
+            '}' => return index + 1,
+            _ => index += 1,
+        }
+    }
+    chars.len()
+}
+
+fn name_end(chars: &[(usize, char)], mut index: usize) -> usize {
+    while chars.get(index).map_or(false, |&(_, c)| c.is_alphanumeric() || c == '_') {
+        index += 1;
+    }
+    index
+}
+
+fn operator_end(chars: &[(usize, char)], index: usize) -> usize {
+    let text: String = chars[index..].iter().take(3).map(|&(_, c)| c).collect();
+    for operator in &["<<<", "&&", "||", "&>", ">>", ">&", "<&", "<<"] {
+        if text.starts_with(operator) {
+            let mut end = index + operator.len();
+            // The descriptor that is duplicated or closed is part of the redirection
+            if *operator == ">&" || *operator == "<&" {
+                if chars.get(end).map(|&(_, c)| c) == Some('-') {
+                    return end + 1;
+                }
+                while chars.get(end).map_or(false, |&(_, c)| c.is_digit(10)) {
+                    end += 1;
+                }
+            }
+            return end;
+        }
+    }
+    index + 1
+}
+
+fn word_end(chars: &[(usize, char)], mut index: usize) -> usize {
+    while index < chars.len() {
+        match chars[index].1 {
+            '\\' => {
+                match chars.get(index + 1).map(|&(_, c)| c) {
+                    Some('\n') | Some('\r') => return index,
+                    _ => index += 2,
+                }
+            },
+            c if " \t\r\n|&;<>()'\"`$".contains(c) => return index,
+            _ => index += 1,
+        }
+    }
+    chars.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peg::Span;
+
+    fn kinds(code: &str) -> Vec<(TokenKind, &str)> {
+        let tokens = tokenize(code);
+        tokens.iter()
+              .map(|token| (token.kind, &code[token.span.start..token.span.end]))
+              .collect()
+    }
+
+    #[test]
+    fn words_and_operators() {
+        assert_eq!(vec![(TokenKind::Word, "ls"),
+                        (TokenKind::Whitespace, " "),
+                        (TokenKind::Word, "-l"),
+                        (TokenKind::Operator, "|"),
+                        (TokenKind::Word, "wc"),
+                        (TokenKind::Operator, "&&"),
+                        (TokenKind::Word, "x"),
+                        (TokenKind::Whitespace, " "),
+                        (TokenKind::Operator, "2>&1"),
+                        (TokenKind::Operator, ">>"),
+                        (TokenKind::Word, "log"),
+                        (TokenKind::Operator, ";"),
+                        (TokenKind::Newline, "\n")],
+                   kinds("ls -l|wc&&x 2>&1>>log;\n"));
+    }
+
+    #[test]
+    fn quotes_variables_and_substitutions() {
+        assert_eq!(vec![(TokenKind::Word, "echo"),
+                        (TokenKind::Whitespace, " "),
+                        (TokenKind::Word, "a"),
+                        (TokenKind::Quoted, "\"b \\\" $c\""),
+                        (TokenKind::Quoted, "'d'"),
+                        (TokenKind::Whitespace, " "),
+                        (TokenKind::Variable, "${e:-f}"),
+                        (TokenKind::Variable, "$g"),
+                        (TokenKind::Word, "/h"),
+                        (TokenKind::Whitespace, " "),
+                        (TokenKind::Substitution, "$(ls \")\" (x))"),
+                        (TokenKind::Whitespace, " "),
+                        (TokenKind::Variable, "@dirs[1..]"),
+                        (TokenKind::Whitespace, " "),
+                        (TokenKind::Quoted, "$'\\n'")],
+                   kinds("echo a\"b \\\" $c\"'d' ${e:-f}$g/h $(ls \")\" (x)) @dirs[1..] $'\\n'"));
+    }
+
+    #[test]
+    fn comments_only_start_words() {
+        assert_eq!(vec![(TokenKind::Word, "echo"),
+                        (TokenKind::Whitespace, " "),
+                        (TokenKind::Word, "a#b"),
+                        (TokenKind::Whitespace, " \\\n "),
+                        (TokenKind::Comment, "# c d"),
+                        (TokenKind::Newline, "\r\n")],
+                   kinds("echo a#b \\\n # c d\r\n"));
+    }
+
+    #[test]
+    fn unterminated_code_is_tokenized() {
+        assert_eq!(vec![(TokenKind::Word, "echo"),
+                        (TokenKind::Whitespace, " "),
+                        (TokenKind::Quoted, "\"abc $(ls")],
+                   kinds("echo \"abc $(ls"));
+        assert_eq!(vec![(TokenKind::Substitution, "$(echo 'x)")], kinds("$(echo 'x)"));
+    }
+
+    #[test]
+    fn spans() {
+        let tokens = tokenize("é | b");
+        assert_eq!(Span::new(0, 2), tokens[0].span);
+        assert_eq!("é", tokens[0].text);
+        assert_eq!(Span::new(3, 4), tokens[2].span);
+        assert_eq!(Span::new(5, 6), tokens[4].span);
+    }
+}
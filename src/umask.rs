@@ -0,0 +1,160 @@
+//! The `umask` builtin, which shows and sets the permissions that files the shell and its
+//! programs create are made without.
+
+use libc::{self, mode_t};
+
+use super::status::{SUCCESS, FAILURE};
+
+/// The permission bits of the owner, group and others, with the letters `umask -S` uses for them
+const CLASSES: &'static [(char, mode_t)] = &[('u', 0o700), ('g', 0o070), ('o', 0o007)];
+
+/// The read, write and execute bits for every class
+const PERMISSIONS: &'static [(char, mode_t)] = &[('r', 0o444), ('w', 0o222), ('x', 0o111)];
+
+/// Runs the `umask` builtin. `umask` prints the mask in octal, `umask -S` prints the
+/// permissions that it allows, and `umask 022` or `umask u=rwx,g=rx,o=rx` sets it.
+pub fn umask(args: &[String]) -> i32 {
+    let symbolic = args.get(1).map_or(false, |arg| arg == "-S");
+    let args = if symbolic { &args[2..] } else { &args[1..] };
+    let mask = current();
+    let text = match args.first() {
+        Some(text) => text,
+        None => {
+            if symbolic {
+                println!("{}", display_symbolic(mask));
+            } else {
+                println!("{:04o}", mask);
+            }
+            return SUCCESS;
+        },
+    };
+    match parse(mask, text) {
+        Some(mask) => {
+            unsafe { libc::umask(mask) };
+            SUCCESS
+        },
+        None => {
+            println!("ion: umask: {}: invalid mode", text);
+            FAILURE
+        },
+    }
+}
+
+/// The mask of the shell, which can only be read by setting it
+fn current() -> mode_t {
+    unsafe {
+        let mask = libc::umask(0);
+        libc::umask(mask);
+        mask
+    }
+}
+
+/// Parses a mask in octal, or the permissions it allows in the symbolic form of `chmod`, which
+/// changes the current mask
+fn parse(mask: mode_t, text: &str) -> Option<mode_t> {
+    if text.chars().all(|character| character.is_digit(8)) {
+        return match u32::from_str_radix(text, 8) {
+            Ok(mask) if mask <= 0o777 => Some(mask as mode_t),
+            _ => None,
+        };
+    }
+    let mut allowed = !mask & 0o777;
+    for clause in text.split(',') {
+        let operator = match clause.find(|character: char| "+-=".contains(character)) {
+            Some(operator) => operator,
+            None => return None,
+        };
+        let mut classes = 0;
+        for character in clause[..operator].chars() {
+            classes |= match character {
+                'a' => 0o777,
+                _ => {
+                    match CLASSES.iter().find(|&&(letter, _)| letter == character) {
+                        Some(&(_, bits)) => bits,
+                        None => return None,
+                    }
+                },
+            };
+        }
+        // Leaving out who the permissions are for means all of them
+        if classes == 0 {
+            classes = 0o777;
+        }
+        let mut permissions = 0;
+        for character in clause[operator + 1..].chars() {
+            match PERMISSIONS.iter().find(|&&(letter, _)| letter == character) {
+                Some(&(_, bits)) => permissions |= bits & classes,
+                None => return None,
+            }
+        }
+        match &clause[operator..operator + 1] {
+            "+" => allowed |= permissions,
+            "-" => allowed &= !permissions,
+            _ => allowed = (allowed & !classes) | permissions,
+        }
+    }
+    Some(!allowed & 0o777)
+}
+
+/// The permissions that the mask allows, such as `u=rwx,g=rx,o=rx` for `022`
+fn display_symbolic(mask: mode_t) -> String {
+    let allowed = !mask & 0o777;
+    let classes: Vec<String> = CLASSES.iter()
+                                      .map(|&(class, class_bits)| {
+                                          let permissions: String = PERMISSIONS.iter()
+                                                                               .filter(|&&(_, bits)| allowed & bits & class_bits != 0)
+                                                                               .map(|&(letter, _)| letter)
+                                                                               .collect();
+                                          format!("{}={}", class, permissions)
+                                      })
+                                      .collect();
+    classes.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{current, parse, display_symbolic};
+    use status::{SUCCESS, FAILURE};
+    use libc;
+
+    #[test]
+    fn parse_octal_masks() {
+        assert_eq!(Some(0o022), parse(0o077, "022"));
+        assert_eq!(Some(0o7), parse(0o077, "7"));
+        assert_eq!(None, parse(0o077, "1000"));
+        assert_eq!(None, parse(0o077, "08"));
+    }
+
+    #[test]
+    fn parse_symbolic_masks() {
+        assert_eq!(Some(0o022), parse(0o077, "u=rwx,g=rx,o=rx"));
+        assert_eq!(Some(0o027), parse(0o022, "g-w,o-rx"));
+        assert_eq!(Some(0o002), parse(0o022, "g+w"));
+        assert_eq!(Some(0o000), parse(0o777, "a=rwx"));
+        assert_eq!(Some(0o333), parse(0o022, "=r"));
+        assert_eq!(Some(0o077), parse(0o022, "go="));
+        assert_eq!(None, parse(0o022, "u=rwz"));
+        assert_eq!(None, parse(0o022, "z+r"));
+        assert_eq!(None, parse(0o022, "rwx"));
+    }
+
+    #[test]
+    fn display_symbolic_masks() {
+        assert_eq!("u=rwx,g=rx,o=rx", display_symbolic(0o022));
+        assert_eq!("u=rw,g=,o=", display_symbolic(0o177));
+    }
+
+    #[test]
+    fn set_the_mask() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        let mask = current();
+        assert_eq!(SUCCESS, umask(&args(&["umask", "027"])));
+        assert_eq!(0o027, current());
+        assert_eq!(SUCCESS, umask(&args(&["umask", "-S", "o+r"])));
+        assert_eq!(0o023, current());
+        assert_eq!(FAILURE, umask(&args(&["umask", "abc"])));
+        assert_eq!(0o023, current());
+        unsafe { libc::umask(mask) };
+    }
+}
@@ -0,0 +1,97 @@
+//! Measures the processor time and memory that the shell and the programs it runs use, for the
+//! `times` builtin, `time` pipelines and the `rusage` option.
+
+use std::mem;
+use std::time::Duration;
+
+use libc::{self, c_int};
+
+use super::status::SUCCESS;
+
+/// The resources that processes used, from what `wait4` or `getrusage` reported for them
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Usage {
+    /// The time spent running the code of the processes
+    pub user: Duration,
+    /// The time the system spent working for the processes
+    pub system: Duration,
+    /// The most memory that any one of the processes had at once, in kilobytes
+    pub max_rss: i64,
+}
+
+impl Usage {
+    pub fn new() -> Self {
+        Usage {
+            user: Duration::new(0, 0),
+            system: Duration::new(0, 0),
+            max_rss: 0,
+        }
+    }
+
+    /// What the shell itself has used, or with `RUSAGE_CHILDREN` the programs it has waited for
+    fn of(who: c_int) -> Self {
+        let mut usage = Usage::new();
+        let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+        if unsafe { libc::getrusage(who, &mut rusage) } == 0 {
+            usage.add(&rusage);
+        }
+        usage
+    }
+
+    /// Adds what another process used
+    pub fn add(&mut self, rusage: &libc::rusage) {
+        self.user = self.user + duration(rusage.ru_utime);
+        self.system = self.system + duration(rusage.ru_stime);
+        if rusage.ru_maxrss as i64 > self.max_rss {
+            self.max_rss = rusage.ru_maxrss as i64;
+        }
+    }
+}
+
+fn duration(time: libc::timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
+
+/// Shows a duration in minutes and seconds, such as `1m2.345s`
+pub fn format_duration(duration: Duration) -> String {
+    format!("{}m{}.{:03}s",
+            duration.as_secs() / 60,
+            duration.as_secs() % 60,
+            duration.subsec_nanos() / 1000000)
+}
+
+/// Runs the `times` builtin, which prints the user and system time of the shell on one line, and
+/// of the programs it has run on the next
+pub fn times() -> i32 {
+    for usage in &[Usage::of(libc::RUSAGE_SELF), Usage::of(libc::RUSAGE_CHILDREN)] {
+        println!("{} {}", format_duration(usage.user), format_duration(usage.system));
+    }
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn format_durations() {
+        assert_eq!("0m0.000s", format_duration(Duration::new(0, 0)));
+        assert_eq!("1m2.345s", format_duration(Duration::new(62, 345000000)));
+    }
+
+    #[test]
+    fn add_usage() {
+        let mut usage = Usage::new();
+        let mut rusage: ::libc::rusage = unsafe { ::std::mem::zeroed() };
+        rusage.ru_utime.tv_sec = 1;
+        rusage.ru_stime.tv_usec = 500000;
+        rusage.ru_maxrss = 2048;
+        usage.add(&rusage);
+        rusage.ru_maxrss = 1024;
+        usage.add(&rusage);
+        assert_eq!(Duration::new(2, 0), usage.user);
+        assert_eq!(Duration::new(1, 0), usage.system);
+        assert_eq!(2048, usage.max_rss);
+    }
+}
@@ -2,19 +2,137 @@ use std::collections::BTreeMap;
 use std::io::{stdout, Write};
 use std::env;
 
-use super::peg::{Pipeline, Job};
+use super::environment::Environment;
+use super::peg::{Pipeline, Job, Redirection, Target, Word, WordSegment, ParameterOperator, Index};
 use super::input_editor::readln;
 use super::status::{SUCCESS, FAILURE};
+use super::expansion::{escape_pattern, expand_braces, expand_glob};
 
 use regex::Regex;
 
+/// The options that `set -o` turns on, with the variables that hold them
+const OPTIONS: &'static [(&'static str, &'static str)] = &[("errexit", "ERREXIT_ENABLED"),
+                                                           ("noclobber", "NOCLOBBER_ENABLED"),
+                                                           ("pipefail", "PIPEFAIL_ENABLED"),
+                                                           ("rusage", "RUSAGE_ENABLED")];
+
+/// The letters that turn options on after `-` and off after `+`, such as `set -e`
+const FLAGS: &'static [(char, &'static str)] = &[('C', "noclobber"), ('e', "errexit")];
+
+#[derive(Clone)]
 pub struct Variables {
     variables: BTreeMap<String, String>,
+    arrays: BTreeMap<String, Vec<String>>,
+    /// The variables that are exported to the programs the shell runs, which are not also kept
+    /// with the other variables
+    environment: Environment,
 }
 
 impl Variables {
     pub fn new() -> Variables {
-        Variables { variables: BTreeMap::new() }
+        Variables {
+            variables: BTreeMap::new(),
+            arrays: BTreeMap::new(),
+            environment: Environment::new(),
+        }
+    }
+
+    /// Exports the variables that the shell was started with
+    pub fn inherit_environment(&mut self) {
+        self.environment = Environment::inherit();
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// Exports the variables given as `name=value`, or the variables that are already set given
+    /// by their names. Lists the exported variables if there are none.
+    pub fn export<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let args: Vec<String> = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect();
+        if args.is_empty() {
+            for (name, value) in self.environment.iter() {
+                println!("{}={}", name, value);
+            }
+            return SUCCESS;
+        }
+        let mut status = SUCCESS;
+        for arg in args {
+            let (name, value) = match arg.find('=') {
+                Some(index) => (&arg[..index], Some(arg[index + 1..].to_string())),
+                None => (arg.as_str(), None),
+            };
+            if name.is_empty() || !Variables::is_valid_variable_name(name) {
+                println!("ion: export: {}: not a valid variable name", name);
+                status = FAILURE;
+                continue;
+            }
+            let value = match value.or_else(|| self.variables.remove(name)) {
+                Some(value) => value,
+                None => self.environment.get(name).cloned().unwrap_or(String::new()),
+            };
+            self.arrays.remove(name);
+            self.environment.set(name, &value);
+        }
+        status
+    }
+
+    /// Turns the options given after `-o` on and those after `+o` off, which sets the variable
+    /// for each to `1` or `0`. Some options also have a letter, so that `set -e` is the same as
+    /// `set -o errexit`. Lists the options if none are given.
+    pub fn set_options(&mut self, args: &[String]) -> i32 {
+        if args.len() < 2 || (args.len() == 2 && (args[1] == "-o" || args[1] == "+o")) {
+            for &(option, variable) in OPTIONS {
+                let state = if self.expand_string(&format!("${}", variable)) == "1" { "on" } else { "off" };
+                println!("{}\t{}", option, state);
+            }
+            return SUCCESS;
+        }
+        let mut status = SUCCESS;
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.chars().next() {
+                Some('-') => "1",
+                Some('+') => "0",
+                _ => {
+                    println!("ion: set: {}: invalid option", arg);
+                    return FAILURE;
+                }
+            };
+            let names: Vec<&str> = if &arg[1..] == "o" {
+                match args.next() {
+                    Some(name) => vec![name.as_str()],
+                    None => {
+                        println!("ion: set: {}: option requires an argument", arg);
+                        return FAILURE;
+                    }
+                }
+            } else {
+                let mut names = vec![];
+                for flag in arg[1..].chars() {
+                    match FLAGS.iter().find(|&&(letter, _)| letter == flag) {
+                        Some(&(_, name)) => names.push(name),
+                        None => {
+                            println!("ion: set: {}{}: invalid option", &arg[..1], flag);
+                            return FAILURE;
+                        }
+                    }
+                }
+                names
+            };
+            for name in names {
+                match OPTIONS.iter().find(|&&(option, _)| option == name) {
+                    Some(&(_, variable)) => self.set_var(variable, value),
+                    None => {
+                        println!("ion: set: {}: invalid option name", name);
+                        status = FAILURE;
+                    }
+                }
+            }
+        }
+        status
     }
 
     pub fn read<I: IntoIterator>(&mut self, args: I) -> i32
@@ -45,24 +163,38 @@ impl Variables {
                 if !Variables::is_valid_variable_name(&key) {
                     return FAILURE;
                 }
-                self.variables.insert(key.to_string(), value.to_string());
+                if self.environment.contains(key) {
+                    self.environment.set(key, value);
+                } else {
+                    self.variables.insert(key.to_string(), value.to_string());
+                }
             },
             (Some(key), None) => {
                 self.variables.remove(key);
+                self.arrays.remove(key);
+                self.environment.unset(key);
             },
             _ => {
                 for (key, value) in self.variables.iter() {
                     println!("{}={}", key, value);
                 }
+                for (key, values) in self.arrays.iter() {
+                    println!("{}=[ {} ]", key, values.join(" "));
+                }
             }
         }
         SUCCESS
     }
 
+    /// Sets a variable, which stays exported if it was. Setting it to nothing removes it.
     pub fn set_var(&mut self, name: &str, value: &str) {
         if !name.is_empty() {
+            self.arrays.remove(name);
             if value.is_empty() {
                 self.variables.remove(&name.to_string());
+                self.environment.unset(name);
+            } else if self.environment.contains(name) {
+                self.environment.set(name, value);
             } else {
                 self.variables.insert(name.to_string(), value.to_string());
             }
@@ -70,30 +202,265 @@ impl Variables {
     }
 
     pub fn get_var(&self, name: &str) -> Option<&String> {
-        self.variables.get(name)
+        self.variables.get(name).or_else(|| self.environment.get(name))
     }
 
     pub fn unset_var(&mut self, name: &str) -> Option<String> {
-        self.variables.remove(name)
+        let environment = self.environment.unset(name);
+        self.variables.remove(name).or(environment)
     }
 
+    /// Sets the positional parameters of a script, so that `$0` is its name, `$1` its first
+    /// argument and so on, and `@args` has all of them
+    pub fn set_arguments(&mut self, name: &str, args: &[String]) {
+        self.set_var("0", name);
+        for (index, arg) in args.iter().enumerate() {
+            self.set_var(&(index + 1).to_string(), arg);
+        }
+        let mut all = vec![name.to_string()];
+        all.extend(args.iter().cloned());
+        self.set_array("args", all);
+    }
+
+    /// Sets an array variable, replacing any variable with the same name
+    pub fn set_array(&mut self, name: &str, values: Vec<String>) {
+        if !name.is_empty() {
+            self.variables.remove(name);
+            self.arrays.insert(name.to_string(), values);
+        }
+    }
+
+    pub fn get_array(&self, name: &str) -> Option<&Vec<String>> {
+        self.arrays.get(name)
+    }
+
+
+    /// Sets the variables of the `${name:=word}` expansions in the pipeline that are unset or
+    /// empty, so that the rest of the pipeline expands them to their new values. Fails with the
+    /// message of the first `${name:?message}` expansion whose variable is unset or empty.
+    pub fn assign_parameters(&mut self, pipeline: &Pipeline) -> Result<(), String> {
+        for job in pipeline.jobs.iter() {
+            let words = job.words.iter().chain(job.env.iter().map(|&(_, ref value)| value));
+            for word in words {
+                try!(self.assign_word_parameters(word));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn assign_word_parameters(&mut self, word: &Word) -> Result<(), String> {
+        for segment in word.segments.iter() {
+            if let WordSegment::Parameter(ref name, operator, ref word) = *segment {
+                if self.get_var(name).is_some() {
+                    continue;
+                }
+                match operator {
+                    ParameterOperator::Assign => {
+                        let value = self.expand_word(word);
+                        self.set_var(name, &value);
+                    },
+                    ParameterOperator::Error => {
+                        let message = self.expand_word(word);
+                        if message.is_empty() {
+                            return Err(format!("{}: parameter not set", name));
+                        }
+                        return Err(format!("{}: {}", name, message));
+                    },
+                    ParameterOperator::Default | ParameterOperator::Alternative => (),
+                }
+            }
+        }
+        Ok(())
+    }
 
     pub fn expand_pipeline(&self, pipeline: &Pipeline) -> Pipeline {
         // TODO don't copy everything
         // TODO ugh, I made it worse
-        Pipeline::new(
-            pipeline.jobs.iter().map(|job| {self.expand_job(job)}).collect(),
-            pipeline.stdin_file.clone(),
-            pipeline.stdout_file.clone())
+        let mut expanded = Pipeline::new(pipeline.jobs.iter().map(|job| {self.expand_job(job)}).collect(),
+                                         pipeline.condition);
+        expanded.negated = pipeline.negated;
+        expanded.timed = pipeline.timed;
+        expanded
     }
 
     pub fn expand_job(&self, job: &Job) -> Job {
         // TODO don't copy everything
-        Job::new(job.args
-                    .iter()
-                    .map(|original: &String| self.expand_string(&original))
-                    .collect(),
-                 job.background)
+        let mut words: Vec<Word> = vec![];
+        for word in job.words.iter() {
+            words.extend(self.expand_arguments(word)
+                             .iter()
+                             .map(|arg| Word::literal(arg).with_span(word.span)));
+        }
+        let env = job.env
+                     .iter()
+                     .map(|&(ref name, ref value)| (name.clone(), Word::literal(&self.expand_word(value))))
+                     .collect();
+        Job::new(words, self.expand_redirections(&job.redirections), job.background).with_env(env).with_span(job.span)
+    }
+
+    /// Expands the paths of files, such as `/dev/fd/$COPROC_OUTPUT`, and the bodies of the here
+    /// documents and here strings in the redirections into their text
+    pub fn expand_redirections(&self, redirections: &[Redirection]) -> Vec<Redirection> {
+        redirections.iter()
+                    .map(|redirection| {
+                        let literal = |word: &Word| Word::literal(&self.expand_word(word));
+                        let target = match redirection.target {
+                            Target::Input(ref path) => Target::Input(literal(path)),
+                            Target::Output(ref path) => Target::Output(literal(path)),
+                            Target::Append(ref path) => Target::Append(literal(path)),
+                            Target::Clobber(ref path) => Target::Clobber(literal(path)),
+                            Target::HereDocument(ref body) => Target::HereDocument(literal(body)),
+                            Target::HereString(ref word) => Target::HereString(literal(word)),
+                            ref target => target.clone(),
+                        };
+                        Redirection::new(redirection.fd, target)
+                    })
+                    .collect()
+    }
+
+    /// Expands the word into the arguments that it produces. Brace expansions produce an argument
+    /// for each alternative, and an unquoted glob pattern produces an argument for each path it
+    /// matches, or the pattern itself if nothing matches.
+    pub fn expand_arguments(&self, word: &Word) -> Vec<String> {
+        if word.segments.len() == 1 {
+            if let WordSegment::Array(ref name, index) = word.segments[0] {
+                return self.expand_array(name, index);
+            }
+        }
+        let mut arguments: Vec<String> = vec![];
+        for word in expand_braces(word) {
+            let (expanded, pattern) = self.expand_segments(&word);
+            if let Some(pattern) = pattern {
+                let recursive = self.expand_string("$RECURSIVE_GLOB_ENABLED") == "1";
+                let paths = expand_glob(&pattern, recursive);
+                if !paths.is_empty() {
+                    arguments.extend(paths);
+                    continue;
+                }
+            }
+            arguments.push(expanded);
+        }
+        arguments
+    }
+
+    /// Joins the segments of the word into a single argument, replacing variables with their
+    /// values. Tilde expansion only applies to unquoted text at the start of the word.
+    pub fn expand_word(&self, word: &Word) -> String {
+        self.expand_segments(word).0
+    }
+
+    /// Expands the word into its text, and into a glob pattern if the word contains unquoted
+    /// glob characters.
+    fn expand_segments(&self, word: &Word) -> (String, Option<String>) {
+        let mut expanded = String::new();
+        let mut pattern = String::new();
+        let mut is_pattern = false;
+        for (index, segment) in word.segments.iter().enumerate() {
+            match *segment {
+                WordSegment::Literal(ref text) => {
+                    let text = if index == 0 { self.tilde_expansion(text.clone()) } else { text.clone() };
+                    pattern.push_str(&escape_pattern(&text));
+                    expanded.push_str(&text);
+                },
+                WordSegment::Pattern(ref text) => {
+                    let text = if index == 0 { self.tilde_expansion(text.clone()) } else { text.clone() };
+                    is_pattern = true;
+                    pattern.push_str(&text);
+                    expanded.push_str(&text);
+                },
+                WordSegment::Variable(ref name) => {
+                    if let Some(value) = self.get_var(name) {
+                        pattern.push_str(&escape_pattern(value));
+                        expanded.push_str(value);
+                    }
+                },
+                WordSegment::Parameter(ref name, operator, ref word) => {
+                    let value = match (self.get_var(name), operator) {
+                        (Some(_), ParameterOperator::Alternative) => self.expand_word(word),
+                        (None, ParameterOperator::Alternative) | (None, ParameterOperator::Error) => String::new(),
+                        (Some(value), _) => value.clone(),
+                        (None, _) => self.expand_word(word),
+                    };
+                    pattern.push_str(&escape_pattern(&value));
+                    expanded.push_str(&value);
+                },
+                WordSegment::Method(ref method, ref variable, ref args) => {
+                    let value = self.expand_method(method, variable, args);
+                    pattern.push_str(&escape_pattern(&value));
+                    expanded.push_str(&value);
+                },
+                WordSegment::Array(ref name, index) => {
+                    let value = self.expand_array(name, index).join(" ");
+                    pattern.push_str(&escape_pattern(&value));
+                    expanded.push_str(&value);
+                },
+                WordSegment::Arithmetic(ref expression) => {
+                    match expression.evaluate(&|name: &str| self.get_var(name).cloned()) {
+                        Ok(value) => {
+                            pattern.push_str(&value.to_string());
+                            expanded.push_str(&value.to_string());
+                        },
+                        Err(message) => println!("ion: {}", message),
+                    }
+                },
+                // The shell replaces these with their output or the paths of their pipes before
+                // expanding words
+                WordSegment::CommandSubstitution(..) |
+                WordSegment::InputSubstitution(_) |
+                WordSegment::OutputSubstitution(_) => (),
+                WordSegment::Brace(_) | WordSegment::Range(..) => {
+                    let alternatives: Vec<String> = expand_braces(&Word::new(vec![segment.clone()]))
+                                                        .iter()
+                                                        .map(|word| self.expand_word(word))
+                                                        .collect();
+                    pattern.push_str(&escape_pattern(&alternatives.join(" ")));
+                    expanded.push_str(&alternatives.join(" "));
+                },
+            }
+        }
+        (expanded, if is_pattern { Some(pattern) } else { None })
+    }
+
+    /// Calls the method on the variable, which is an array if one with the name is set, and a
+    /// string otherwise. Unknown methods print an error and produce nothing.
+    fn expand_method(&self, method: &str, variable: &str, args: &[Word]) -> String {
+        let args: Vec<String> = args.iter().map(|arg| self.expand_word(arg)).collect();
+        let value = || {
+            match self.get_array(variable) {
+                Some(elements) => elements.join(" "),
+                None => self.get_var(variable).cloned().unwrap_or(String::new()),
+            }
+        };
+        match method {
+            "len" => {
+                match self.get_array(variable) {
+                    Some(elements) => elements.len().to_string(),
+                    None => value().chars().count().to_string(),
+                }
+            },
+            "join" => {
+                let separator = args.get(0).map_or(" ", |separator| separator.as_str());
+                match self.get_array(variable) {
+                    Some(elements) => elements.join(separator),
+                    None => value(),
+                }
+            },
+            "upper" => value().to_uppercase(),
+            "lower" => value().to_lowercase(),
+            _ => {
+                println!("ion: unknown method: {}", method);
+                String::new()
+            },
+        }
+    }
+
+    /// Returns the elements of the array that the index selects, or all of them without an index
+    fn expand_array(&self, name: &str, index: Option<Index>) -> Vec<String> {
+        match (self.get_array(name), index) {
+            (Some(elements), Some(index)) => index.select(elements).to_vec(),
+            (Some(elements), None) => elements.clone(),
+            (None, _) => vec![],
+        }
     }
 
     fn replace_substring(string: &mut String, start: usize, end: usize, replacement: &str) {
@@ -116,7 +483,9 @@ impl Variables {
             if let (Some(tilde_prefix), Some(remainder)) = (cap.at(1), cap.at(2)) {
                 match tilde_prefix {
                     "" => {
-                        if let Some(home) = env::home_dir() {
+                        if let Some(home) = self.get_var("HOME") {
+                            return home.to_string() + remainder;
+                        } else if let Some(home) = env::home_dir() {
                             return home.to_string_lossy().to_string() + remainder;
                         }
                     },
@@ -167,7 +536,7 @@ impl Variables {
         }
 
         for &(start, end, ref var_name) in replacements.iter().rev() {
-            let value: &str = match self.variables.get(var_name) {
+            let value: &str = match self.get_var(var_name) {
                 Some(v) => &v,
                 None => ""
             };
@@ -180,7 +549,8 @@ impl Variables {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use status::FAILURE;
+    use status::{SUCCESS, FAILURE};
+    use peg::{Target, Word, WordSegment};
 
     #[test]
     fn undefined_variable_expands_to_empty_string() {
@@ -223,6 +593,181 @@ mod tests {
         assert_eq!("variables: BAR Y", &expanded);
     }
 
+    #[test]
+    fn expand_word_with_variables_and_literals() {
+        let mut variables = Variables::new();
+        variables.set_var("FOO", "BAR");
+        let word = Word::new(vec![WordSegment::Literal("a ".to_string()),
+                                  WordSegment::Variable("FOO".to_string()),
+                                  WordSegment::Variable("UNDEFINED".to_string()),
+                                  WordSegment::Literal(" b".to_string())]);
+        assert_eq!("a BAR b", &variables.expand_word(&word));
+    }
+
+    #[test]
+    fn literal_dollar_sign_is_not_expanded() {
+        let mut variables = Variables::new();
+        variables.set_var("FOO", "BAR");
+        assert_eq!("$FOO", &variables.expand_word(&Word::literal("$FOO")));
+    }
+
+    #[test]
+    fn expand_parameters() {
+        let mut variables = Variables::new();
+        variables.set_var("SET", "value");
+        let words = ::peg::parse("echo ${SET:-x} ${UNSET:-x} ${SET:+y} ${UNSET:+y} ${NEW:=${SET}z} $NEW").unwrap()
+                        .remove(0)
+                        .jobs
+                        .remove(0)
+                        .words;
+        assert_eq!(Ok(()), variables.assign_word_parameters(&words[5]));
+        let expanded: Vec<String> = words.iter().map(|word| variables.expand_word(word)).collect();
+        assert_eq!(vec!["echo", "value", "x", "y", "", "valuez", "valuez"], expanded);
+    }
+
+    #[test]
+    fn unset_parameter_error() {
+        let mut variables = Variables::new();
+        let pipeline = ::peg::parse("echo ${SET:?} ${UNSET:?is required}").unwrap().remove(0);
+        variables.set_var("SET", "value");
+        assert_eq!(Err("UNSET: is required".to_string()), variables.assign_parameters(&pipeline));
+        variables.unset_var("SET");
+        assert_eq!(Err("SET: parameter not set".to_string()), variables.assign_parameters(&pipeline));
+    }
+
+    #[test]
+    fn expand_array_into_arguments() {
+        let mut variables = Variables::new();
+        variables.set_array("A", vec!["x".to_string(), "y z".to_string(), "w".to_string()]);
+        let words = ::peg::parse("echo @A @A[1] @A[-2..] @B").unwrap().remove(0).jobs.remove(0).words;
+        let arguments: Vec<String> = words.iter().flat_map(|word| variables.expand_arguments(word)).collect();
+        assert_eq!(vec!["echo", "x", "y z", "w", "y z", "y z", "w"], arguments);
+        assert_eq!("x y z w", &variables.expand_word(&words[1]));
+    }
+
+    #[test]
+    fn expand_methods() {
+        let mut variables = Variables::new();
+        variables.set_var("NAME", "Ion é");
+        variables.set_var("SEP", "-");
+        variables.set_array("DIRS", vec!["/tmp".to_string(), "/var".to_string()]);
+        let words = ::peg::parse("echo $len(NAME) $len(DIRS) $len(UNSET) $join(DIRS, \", \") $join(DIRS) \
+                                  $join(DIRS, $SEP) $upper(NAME) $lower(DIRS)")
+                        .unwrap()
+                        .remove(0)
+                        .jobs
+                        .remove(0)
+                        .words;
+        let expanded: Vec<String> = words.iter().map(|word| variables.expand_word(word)).collect();
+        assert_eq!(vec!["echo", "5", "2", "0", "/tmp, /var", "/tmp /var", "/tmp-/var", "ION É", "/tmp /var"],
+                   expanded);
+    }
+
+    #[test]
+    fn expand_arithmetic() {
+        let mut variables = Variables::new();
+        variables.set_var("X", "4");
+        let word = ::peg::parse("echo $((X * (2 + 1)))").unwrap().remove(0).jobs.remove(0).words.remove(1);
+        assert_eq!("12", &variables.expand_word(&word));
+    }
+
+    #[test]
+    fn arrays_and_variables_share_names() {
+        let mut variables = Variables::new();
+        variables.set_array("X", vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(Some(&vec!["a".to_string(), "b".to_string()]), variables.get_array("X"));
+        variables.set_var("X", "c");
+        assert_eq!(None, variables.get_array("X"));
+        variables.set_array("X", vec![]);
+        assert_eq!(None, variables.get_var("X"));
+    }
+
+    #[test]
+    fn expand_here_documents() {
+        let mut variables = Variables::new();
+        variables.set_var("X", "world");
+        let job = ::peg::parse("cat <<EOF\nhello $X\nEOF").unwrap().remove(0).jobs.remove(0);
+        assert_eq!(Target::HereDocument(Word::literal("hello world\n")),
+                   variables.expand_job(&job).redirections[0].target);
+    }
+
+    #[test]
+    fn expand_redirection_paths() {
+        let mut variables = Variables::new();
+        variables.set_var("FD", "12");
+        let job = ::peg::parse("echo 1+1 > /dev/fd/$FD").unwrap().remove(0).jobs.remove(0);
+        assert_eq!(Target::Output(Word::literal("/dev/fd/12")), variables.expand_job(&job).redirections[0].target);
+        let target = |variables: &Variables, code: &str| {
+            variables.expand_job(&::peg::parse(code).unwrap()[0].jobs[0]).redirections[0].target.clone()
+        };
+        assert_eq!(Target::Output(Word::literal("$FD")), target(&variables, "echo > '$FD'"));
+        assert_eq!(Target::Append(Word::literal("12_log")), target(&variables, "echo >> ${FD}_log"));
+        assert_eq!(Target::Input(Word::literal("default")), target(&variables, "cat < ${MISSING:-default}"));
+        assert_eq!(SUCCESS, variables.export(&["export", "LOG_DIR=/tmp/logs"]));
+        assert_eq!(Target::Clobber(Word::literal("/tmp/logs/out")), target(&variables, "echo >| $LOG_DIR/out"));
+    }
+
+    #[test]
+    fn set_positional_parameters() {
+        let mut variables = Variables::new();
+        variables.set_arguments("script.ion", &["a".to_string(), "b c".to_string()]);
+        assert_eq!(Some(&"script.ion".to_string()), variables.get_var("0"));
+        assert_eq!(Some(&"b c".to_string()), variables.get_var("2"));
+        assert_eq!(None, variables.get_var("3"));
+        assert_eq!(3, variables.get_array("args").unwrap().len());
+    }
+
+    #[test]
+    fn expand_exit_status_in_prompt() {
+        let mut variables = Variables::new();
+        variables.set_var("?", "1");
+        variables.set_var("PROMPT", "[$?] ion# ");
+        assert_eq!("[1] ion# ", variables.expand_string(&variables.expand_string("$PROMPT")));
+    }
+
+    #[test]
+    fn set_options() {
+        let mut variables = Variables::new();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        assert_eq!(SUCCESS, variables.set_options(&args(&["set", "-o", "pipefail"])));
+        assert_eq!("1", variables.expand_string("$PIPEFAIL_ENABLED"));
+        assert_eq!(SUCCESS, variables.set_options(&args(&["set", "+o", "pipefail"])));
+        assert_eq!("0", variables.expand_string("$PIPEFAIL_ENABLED"));
+        assert_eq!(FAILURE, variables.set_options(&args(&["set", "-o", "nonsense"])));
+        assert_eq!(FAILURE, variables.set_options(&args(&["set", "-x", "pipefail"])));
+        assert_eq!(SUCCESS, variables.set_options(&args(&["set", "-eC", "-o", "pipefail"])));
+        assert_eq!("1", variables.expand_string("$ERREXIT_ENABLED"));
+        assert_eq!("1", variables.expand_string("$NOCLOBBER_ENABLED"));
+        assert_eq!("1", variables.expand_string("$PIPEFAIL_ENABLED"));
+        assert_eq!(SUCCESS, variables.set_options(&args(&["set", "+e"])));
+        assert_eq!("0", variables.expand_string("$ERREXIT_ENABLED"));
+        assert_eq!(FAILURE, variables.set_options(&args(&["set", "-e", "-o"])));
+    }
+
+    #[test]
+    fn expand_unquoted_glob_pattern() {
+        let variables = Variables::new();
+        let word = Word::new(vec![WordSegment::Pattern("src/ma*.rs".to_string())]);
+        assert_eq!(vec!["src/main.rs".to_string()], variables.expand_arguments(&word));
+    }
+
+    #[test]
+    fn expand_recursive_glob_pattern() {
+        let mut variables = Variables::new();
+        let word = Word::new(vec![WordSegment::Pattern("**/main.rs".to_string())]);
+        variables.set_var("RECURSIVE_GLOB_ENABLED", "1");
+        assert!(variables.expand_arguments(&word).contains(&"src/main.rs".to_string()));
+    }
+
+    #[test]
+    fn quoted_glob_pattern_is_not_expanded() {
+        let variables = Variables::new();
+        let word = Word::new(vec![WordSegment::Literal("src/".to_string()),
+                                  WordSegment::Pattern("*".to_string()),
+                                  WordSegment::Literal("*.rs".to_string())]);
+        assert_eq!(vec!["src/**.rs".to_string()], variables.expand_arguments(&word));
+    }
+
     #[test]
     fn replace_substring() {
         let mut string = "variable: $FOO".to_owned();
@@ -237,6 +782,30 @@ mod tests {
         assert_eq!("\\$FOO", &expanded);
     }
 
+    #[test]
+    fn export_variables() {
+        let mut variables = Variables::new();
+        variables.set_var("EDITOR", "vi");
+        assert_eq!(SUCCESS, variables.export(&["export", "EDITOR", "PAGER=less"]));
+        assert_eq!(Some(&"vi".to_string()), variables.environment().get("EDITOR"));
+        assert_eq!(Some(&"less".to_string()), variables.get_var("PAGER"));
+        variables.set_var("EDITOR", "vim");
+        assert_eq!(Some(&"vim".to_string()), variables.environment().get("EDITOR"));
+        variables.let_(&["let", "EDITOR"]);
+        assert_eq!(None, variables.get_var("EDITOR"));
+        assert_eq!(None, variables.environment().get("EDITOR"));
+        assert_eq!(FAILURE, variables.export(&["export", "A-B=1"]));
+    }
+
+    #[test]
+    fn expand_exported_variables() {
+        let mut variables = Variables::new();
+        assert_eq!(SUCCESS, variables.export(&["export", "CDPATH=/tmp:/var"]));
+        assert_eq!("cd in /tmp:/var", variables.expand_string("cd in $CDPATH"));
+        variables.set_var("CDPATH", "/usr");
+        assert_eq!("/usr", variables.expand_string("$CDPATH"));
+    }
+
     #[test]
     fn let_checks_variable_name() {
         let mut variables = Variables::new();